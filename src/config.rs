@@ -2,6 +2,9 @@ use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use anyhow::Result;
 
+use crate::engine::config::CompressionLevel;
+use crate::recompress::RecompressJob;
+
 #[allow(dead_code)] // Protocol constants for future use
 pub const SYMVEA_PORT: u16 = 24096;
 pub const MAX_FRAME_SIZE: usize = 1024 * 1024 * 1024; // 1GB
@@ -14,6 +17,15 @@ pub struct ServerConfig {
     pub readonly_mounts: Vec<PathBuf>,
     pub auto_create_directories: bool,
     pub max_file_size: usize,
+    /// Level for uploads that do not ask for one
+    #[serde(default)]
+    pub compression_level: CompressionLevel,
+    /// Store uploads of 64 KiB and more as chunks shared between objects
+    #[serde(default)]
+    pub dedup_chunks: bool,
+    /// Migration to run in the background while serving
+    #[serde(default)]
+    pub recompress: Option<RecompressJob>,
 }
 
 impl Default for ServerConfig {
@@ -24,6 +36,9 @@ impl Default for ServerConfig {
             readonly_mounts: Vec::new(),
             auto_create_directories: true,
             max_file_size: MAX_FRAME_SIZE,
+            compression_level: CompressionLevel::Default,
+            dedup_chunks: false,
+            recompress: None,
         }
     }
 }
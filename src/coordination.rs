@@ -1,4 +1,6 @@
+use std::collections::hash_map::DefaultHasher;
 use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::io::Write;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use anyhow::Result;
@@ -53,6 +55,40 @@ impl Drop for FileLock {
     }
 }
 
+/// Locks keys spread over
+const OBJECT_LOCK_STRIPES: usize = 64;
+
+/// Serializes writers of the same object within the server: uploads
+/// replacing it and background recompression swapping its files. Keys
+/// share a fixed set of locks, so memory does not grow with the keys seen.
+pub struct ObjectLocks {
+    stripes: Vec<tokio::sync::Mutex<()>>,
+}
+
+impl ObjectLocks {
+    pub fn new() -> Self {
+        Self {
+            stripes: (0..OBJECT_LOCK_STRIPES).map(|_| tokio::sync::Mutex::new(())).collect(),
+        }
+    }
+
+    pub async fn lock(&self, key: &str) -> tokio::sync::MutexGuard<'_, ()> {
+        self.stripe(key).lock().await
+    }
+
+    fn stripe(&self, key: &str) -> &tokio::sync::Mutex<()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.stripes[hasher.finish() as usize % self.stripes.len()]
+    }
+}
+
+impl Default for ObjectLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct CoordinationManager {
     data_dir: String,
 }
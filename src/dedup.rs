@@ -0,0 +1,365 @@
+//! Uploads stored as content-defined chunks. Each chunk is compressed and
+//! kept once in the chunk store however many objects contain it, so
+//! near-identical uploads only add the chunks that differ.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use sha2::{Digest, Sha256};
+
+use crate::engine::{
+    chunker::{ContentChunker, AVG_CHUNK},
+    codec::{choose_codec, encode_or_store, Codec, SymbolCodec, CHUNKED_CODEC, SYMBOL_CODEC},
+    config::EngineConfig,
+    decompressor::dictionary_id,
+    hash::sha256,
+};
+use crate::storage::{
+    chunks::ChunkStore,
+    dictionary::Dictionary,
+    metadata::{ChunkRef, ObjectMetadata, SymbolInfo, TokenBreakdown},
+    registry::DictionaryRegistry,
+    symbols::SymbolStore,
+};
+use crate::utils::parallel::{parallel_map, workers};
+
+/// Whether an upload of `size` bytes is stored as chunks. Smaller ones
+/// would be a single chunk and gain nothing from the indirection.
+pub fn stores_as_chunks(size: u64, config: &EngineConfig) -> bool {
+    config.dedup_chunks && size >= AVG_CHUNK as u64
+}
+
+/// Chunks an upload as it arrives and stores the chunks not already in
+/// the chunk store
+pub struct DedupWriter {
+    key: String,
+    user_id: Option<String>,
+    config: EngineConfig,
+    chunker: ContentChunker,
+    hasher: Sha256,
+    // Generation new chunks are coded against, learned from the first
+    // chunks that were not already stored
+    dict: Option<Arc<Dictionary>>,
+    learned: Vec<SymbolInfo>,
+    chunks: Vec<ChunkRef>,
+    // Chunks this upload coded and has not referenced yet
+    coded: HashSet<String>,
+    original_size: u64,
+    stored_size: u64,
+    symbols: Vec<SymbolInfo>,
+    symbol_hashes: HashSet<String>,
+    breakdown: TokenBreakdown,
+}
+
+impl DedupWriter {
+    pub fn new(key: String, user_id: Option<String>, config: EngineConfig) -> Self {
+        Self {
+            key,
+            user_id,
+            config,
+            chunker: ContentChunker::new(),
+            hasher: Sha256::new(),
+            dict: None,
+            learned: Vec::new(),
+            chunks: Vec::new(),
+            coded: HashSet::new(),
+            original_size: 0,
+            stored_size: 0,
+            symbols: Vec::new(),
+            symbol_hashes: HashSet::new(),
+            breakdown: TokenBreakdown {
+                symbol_bytes: 0,
+                literal_bytes: 0,
+                literal_reason: "Below promotion threshold".to_string(),
+                stored_bytes: 0,
+                stored_reason: None,
+                matched_bytes: 0,
+                deduplicated_bytes: 0,
+            },
+        }
+    }
+
+    /// Chunks and stores `data`. An upload that fails here must be
+    /// `abort`ed to give up the chunks it already references.
+    pub fn push(
+        &mut self,
+        data: &[u8],
+        dictionaries: &DictionaryRegistry,
+        symbol_store: &SymbolStore,
+        chunk_store: &ChunkStore,
+    ) -> anyhow::Result<()> {
+        self.hasher.update(data);
+        self.original_size += data.len() as u64;
+        let chunks = self.chunker.push(data);
+        self.store_chunks(chunks, dictionaries, symbol_store, chunk_store)
+    }
+
+    /// Stores the last chunks and returns the object's metadata. Its
+    /// `compressed_size` counts only the bytes this upload added to the
+    /// chunk store. On failure the upload's references are dropped.
+    pub fn finish(
+        mut self,
+        dictionaries: &DictionaryRegistry,
+        symbol_store: &SymbolStore,
+        chunk_store: &ChunkStore,
+    ) -> anyhow::Result<ObjectMetadata> {
+        let chunks = std::mem::take(&mut self.chunker).finish();
+        let dict_id = self.store_chunks(chunks, dictionaries, symbol_store, chunk_store)
+            .and_then(|()| match &self.dict {
+                Some(dict) => Ok(dictionary_id(dict)),
+                None => Ok(dictionary_id(&*dictionaries.current(self.user_id.as_deref(), &self.config)?)),
+            });
+        let dict_id = match dict_id {
+            Ok(dict_id) => dict_id,
+            Err(e) => {
+                self.abort(chunk_store)?;
+                return Err(e);
+            }
+        };
+        let content_hash: [u8; 32] = self.hasher.finalize().into();
+        let mut meta = ObjectMetadata::new(
+            self.key,
+            content_hash,
+            content_hash,
+            dict_id,
+            self.original_size,
+            self.stored_size,
+            self.user_id,
+        );
+
+        let mut symbols = self.learned;
+        symbols.extend(self.symbols);
+        meta.symbols = symbols;
+        meta.explained_ratio = if self.original_size > 0 {
+            self.breakdown.symbol_bytes as f64 / self.original_size as f64
+        } else {
+            0.0
+        };
+        meta.token_breakdown = self.breakdown;
+        meta.entropy_coder = self.config.entropy_coder;
+        meta.level = self.config.level;
+        meta.codec = CHUNKED_CODEC.to_string();
+        meta.chunks = self.chunks;
+        Ok(meta)
+    }
+
+    /// Drops the references taken so far, for an upload that will not be
+    /// stored
+    pub fn abort(self, chunk_store: &ChunkStore) -> anyhow::Result<()> {
+        chunk_store.release(&self.key, self.chunks.iter().map(|chunk| chunk.hash.as_str()))
+    }
+
+    /// Codes the chunks the store does not have, then references every
+    /// chunk in order
+    fn store_chunks(
+        &mut self,
+        chunks: Vec<Vec<u8>>,
+        dictionaries: &DictionaryRegistry,
+        symbol_store: &SymbolStore,
+        chunk_store: &ChunkStore,
+    ) -> anyhow::Result<()> {
+        let hashes: Vec<String> = chunks.iter().map(|chunk| hex::encode(sha256(chunk))).collect();
+
+        let mut seen = HashSet::new();
+        let missing: Vec<usize> = (0..chunks.len())
+            .filter(|&i| seen.insert(&hashes[i]) && !chunk_store.contains(&hashes[i]))
+            .collect();
+        self.code_chunks(&missing, &chunks, &hashes, dictionaries, symbol_store, chunk_store)?;
+
+        for (i, (chunk, hash)) in chunks.iter().zip(&hashes).enumerate() {
+            let meta = match chunk_store.retain(hash, &self.key)? {
+                Some(meta) => meta,
+                None => {
+                    // Released by its last other object since it was looked up
+                    self.code_chunks(&[i], &chunks, &hashes, dictionaries, symbol_store, chunk_store)?;
+                    chunk_store.retain(hash, &self.key)?
+                        .ok_or_else(|| anyhow::anyhow!("Chunk {} disappeared while it was stored", hash))?
+                }
+            };
+            self.add_chunk(hash, chunk.len() as u64, &meta);
+        }
+        Ok(())
+    }
+
+    fn code_chunks(
+        &mut self,
+        indices: &[usize],
+        chunks: &[Vec<u8>],
+        hashes: &[String],
+        dictionaries: &DictionaryRegistry,
+        symbol_store: &SymbolStore,
+        chunk_store: &ChunkStore,
+    ) -> anyhow::Result<()> {
+        if indices.is_empty() {
+            return Ok(());
+        }
+        let work: Vec<(usize, &dyn Codec)> = indices.iter().map(|&i| (i, choose_codec(&chunks[i]))).collect();
+
+        if self.dict.is_none() && work.iter().any(|(_, codec)| codec.id() == SYMBOL_CODEC) {
+            // The dictionary learns once per upload, from the first new
+            // content it brings
+            let sample: Vec<u8> = work.iter()
+                .filter(|(_, codec)| codec.id() == SYMBOL_CODEC)
+                .flat_map(|&(i, _)| chunks[i].iter().copied())
+                .collect();
+            let config = &self.config;
+            let (dict, learned) = dictionaries.learn(self.user_id.as_deref(), config, |dict| {
+                SymbolCodec.learn(&sample, dict, symbol_store, config)
+            })?;
+            self.dict = Some(dict);
+            self.learned = learned;
+        }
+        let dict = match &self.dict {
+            Some(dict) => Arc::clone(dict),
+            None => dictionaries.current(self.user_id.as_deref(), &self.config)?,
+        };
+        let dict_id = dictionary_id(&dict);
+
+        for batch in work.chunks(workers()) {
+            let coded = parallel_map(batch, |&(i, codec)| {
+                let hash = &hashes[i];
+                let (codec, (blob, symbols, explained_ratio, token_breakdown)) =
+                    encode_or_store(codec, &chunks[i], &dict, symbol_store, hash, &self.config, Vec::new());
+                let content_hash = sha256(&chunks[i]);
+                let mut meta = ObjectMetadata::new(
+                    hash.clone(),
+                    content_hash,
+                    content_hash,
+                    dict_id.clone(),
+                    chunks[i].len() as u64,
+                    blob.len() as u64,
+                    None,
+                );
+                meta.symbols = symbols;
+                meta.explained_ratio = explained_ratio;
+                meta.token_breakdown = token_breakdown;
+                meta.entropy_coder = self.config.entropy_coder;
+                meta.level = self.config.level;
+                meta.codec = codec.id().to_string();
+                (blob, meta)
+            });
+            for (&(i, _), (blob, meta)) in batch.iter().zip(coded) {
+                chunk_store.insert(&hashes[i], &blob, &meta)?;
+                self.coded.insert(hashes[i].clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a referenced chunk towards the object. Only the first
+    /// reference to a chunk this upload coded adds its coding; every other
+    /// reference is a duplicate.
+    fn add_chunk(&mut self, hash: &str, size: u64, meta: &ObjectMetadata) {
+        self.chunks.push(ChunkRef { hash: hash.to_string(), size });
+        if !self.coded.remove(hash) {
+            self.breakdown.deduplicated_bytes += size;
+            return;
+        }
+
+        self.stored_size += meta.compressed_size;
+        let chunk = &meta.token_breakdown;
+        self.breakdown.symbol_bytes += chunk.symbol_bytes;
+        self.breakdown.literal_bytes += chunk.literal_bytes;
+        self.breakdown.stored_bytes += chunk.stored_bytes;
+        self.breakdown.matched_bytes += chunk.matched_bytes;
+        if self.breakdown.stored_reason.is_none() {
+            self.breakdown.stored_reason = chunk.stored_reason.clone();
+        }
+        for symbol in &meta.symbols {
+            if self.symbol_hashes.insert(symbol.hash.clone()) {
+                self.symbols.push(symbol.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::config::CompressionLevel;
+
+    struct Stores {
+        dir: std::path::PathBuf,
+        dictionaries: DictionaryRegistry,
+        symbol_store: SymbolStore,
+        chunk_store: ChunkStore,
+    }
+
+    impl Stores {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("symvea-dedup-{}-{}", name, std::process::id()));
+            std::fs::remove_dir_all(&dir).ok();
+            let data_dir = dir.to_string_lossy().to_string();
+            Self {
+                dictionaries: DictionaryRegistry::new(data_dir.clone(), Dictionary::new("global")),
+                symbol_store: SymbolStore::new(data_dir.clone()),
+                chunk_store: ChunkStore::new(data_dir),
+                dir,
+            }
+        }
+
+        fn upload(&self, key: &str, data: &[u8]) -> ObjectMetadata {
+            let config = EngineConfig { dedup_chunks: true, ..EngineConfig::default().with_level(CompressionLevel::Fast) };
+            let mut writer = DedupWriter::new(key.to_string(), None, config);
+            writer.push(data, &self.dictionaries, &self.symbol_store, &self.chunk_store).unwrap();
+            writer.finish(&self.dictionaries, &self.symbol_store, &self.chunk_store).unwrap()
+        }
+    }
+
+    impl Drop for Stores {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state = 0x6a09_e667_f3bc_c909u64;
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            out.extend_from_slice(format!("record {} value {}\n", state % 1000, state >> 40).as_bytes());
+        }
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn repeated_upload_shares_its_chunks() {
+        let stores = Stores::new("shared");
+        let data = sample(192 * 1024);
+        let first = stores.upload("a", &data);
+        assert!(first.chunks.len() > 1);
+        assert_eq!(first.token_breakdown.deduplicated_bytes, 0);
+        assert_eq!(stores.chunk_store.assemble(&first, &stores.dictionaries).unwrap(), data);
+
+        let second = stores.upload("b", &data);
+        assert_eq!(second.token_breakdown.deduplicated_bytes, data.len() as u64);
+        assert_eq!(second.compressed_size, 0);
+        for chunk in &second.chunks {
+            let references = stores.chunk_store.references(&chunk.hash).unwrap();
+            assert_eq!(references.objects["a"], references.objects["b"]);
+        }
+    }
+
+    #[test]
+    fn abort_gives_up_references() {
+        let stores = Stores::new("abort");
+        let data = sample(192 * 1024);
+        let kept = stores.upload("a", &data[..96 * 1024]);
+
+        let config = EngineConfig { dedup_chunks: true, ..EngineConfig::default().with_level(CompressionLevel::Fast) };
+        let mut writer = DedupWriter::new("b".to_string(), None, config);
+        writer.push(&data, &stores.dictionaries, &stores.symbol_store, &stores.chunk_store).unwrap();
+        writer.abort(&stores.chunk_store).unwrap();
+
+        // Only the chunks of the finished upload are left
+        for entry in std::fs::read_dir(stores.dir.join("chunk_refs")).unwrap() {
+            let hash = entry.unwrap().file_name().to_string_lossy().into_owned();
+            let references = stores.chunk_store.references(&hash).unwrap();
+            assert!(!references.objects.contains_key("b"));
+            assert!(kept.chunks.iter().any(|chunk| chunk.hash == hash));
+        }
+        assert_eq!(stores.chunk_store.assemble(&kept, &stores.dictionaries).unwrap(), &data[..96 * 1024]);
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use sha2::{Sha256, Digest};
+
+use crate::engine::{
+    container::{ContainerHeader, INDEX_ENTRY_LEN, TRAILER_LEN, has_coder_tags},
+    decompressor::{detokenize, dictionary_id},
+    entropy::{EntropyCoder, EntropyCoderKind, HuffmanCoder},
+    error::DecompressError,
+    lz77::{find_back_references, MATCH_TOKEN, MIN_MATCH},
+    tokenizer::{tokenize, SymbolMatcher},
+};
+use crate::utils::{parallel::{parallel_map, workers}, varint::encode_varint};
+use crate::storage::dictionary::Dictionary;
+
+/// Uncompressed bytes per block. Each block carries its own Huffman table,
+/// so this trades table overhead against memory held per stream.
+pub const BLOCK_SIZE: usize = 256 * 1024;
+
+/// Raw length (u32) and payload length (u32) in front of every block. A
+/// zero raw length ends the block section.
+pub const BLOCK_HEADER_LEN: usize = 8;
+
+/// Block tag for raw bytes kept as they are, when coding would not shrink them
+pub const STORED_TAG: u8 = 0xff;
+
+/// Largest payload a block record may claim. Blocks that coding would not
+/// shrink are stored behind a tag byte; the slack covers containers from
+/// before coder tags, whose Huffman codes and table could outgrow a block.
+pub const MAX_BLOCK_PAYLOAD: usize = 4 * BLOCK_SIZE;
+
+/// Set on a coder tag when the block carries back-references. The tag is
+/// then followed by the match stream length (u32) and the match stream: a
+/// varint length minus `MIN_MATCH` and a varint distance per `MATCH_TOKEN`.
+pub const BACK_REFERENCE_FLAG: u8 = 0x40;
+
+/// What the encoder saw, for the object's metadata
+#[derive(Debug, Clone, Default)]
+pub struct StreamSummary {
+    pub dict_id: String,
+    pub original_size: u64,
+    pub compressed_size: u64,
+    pub content_hash: [u8; 32],
+    /// Times each dictionary symbol was emitted, in token order so the
+    /// metadata built from it is the same on every run
+    pub symbol_counts: BTreeMap<u32, u64>,
+    /// Original bytes in blocks that were stored uncoded
+    pub stored_bytes: u64,
+    /// Original bytes reproduced by back-references
+    pub matched_bytes: u64,
+}
+
+/// Where a block starts, in the original content and in the container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub raw_offset: u64,
+    pub offset: u64,
+}
+
+/// Incremental encoder for the block container. It does no I/O: input is
+/// pushed in pieces of any size and encoded bytes are drained as blocks
+/// fill. Full blocks are coded in parallel, one per worker, so memory stays
+/// bounded by a block per worker.
+pub struct BlockEncoder {
+    matcher: Arc<SymbolMatcher>,
+    coder: EntropyCoderKind,
+    store_raw: bool,
+    back_references: bool,
+    block: Vec<u8>,
+    // Full blocks waiting for a batch to code together
+    full_blocks: Vec<Vec<u8>>,
+    workers: usize,
+    out: Vec<u8>,
+    hasher: Sha256,
+    summary: StreamSummary,
+    index: Vec<IndexEntry>,
+    raw_offset: u64,
+}
+
+impl BlockEncoder {
+    pub fn new(dict: &Dictionary, coder: EntropyCoderKind) -> Self {
+        let dict_id = dictionary_id(dict);
+        let mut out = Vec::new();
+        ContainerHeader::write_prefix(&dict_id, &mut out);
+
+        Self {
+            matcher: dict.matcher(),
+            coder,
+            store_raw: false,
+            back_references: false,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            full_blocks: Vec::new(),
+            workers: workers(),
+            out,
+            hasher: Sha256::new(),
+            summary: StreamSummary {
+                dict_id,
+                ..Default::default()
+            },
+            index: Vec::new(),
+            raw_offset: 0,
+        }
+    }
+
+    pub fn push(&mut self, mut data: &[u8]) {
+        self.hasher.update(data);
+        self.summary.original_size += data.len() as u64;
+
+        while !data.is_empty() {
+            let take = (BLOCK_SIZE - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            if self.block.len() == BLOCK_SIZE {
+                let block = std::mem::replace(&mut self.block, Vec::with_capacity(BLOCK_SIZE));
+                self.full_blocks.push(block);
+                if self.full_blocks.len() == self.workers {
+                    self.flush_blocks();
+                }
+            }
+        }
+    }
+
+    /// Stores every following block uncoded, for input known not to compress
+    pub fn store_raw(&mut self) {
+        self.store_raw = true;
+    }
+
+    /// Replaces repeats of at least `MIN_MATCH` bytes within a block with
+    /// back-references before tokenizing
+    pub fn enable_back_references(&mut self) {
+        self.back_references = true;
+    }
+
+    /// Encoded bytes produced so far that have not been taken yet
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.summary.compressed_size += self.out.len() as u64;
+        std::mem::take(&mut self.out)
+    }
+
+    /// Encodes the last partial block and writes the end marker, the block
+    /// index and the trailer
+    pub fn finish(mut self) -> (Vec<u8>, StreamSummary) {
+        if !self.block.is_empty() {
+            let block = std::mem::take(&mut self.block);
+            self.full_blocks.push(block);
+        }
+        self.flush_blocks();
+
+        self.out.extend_from_slice(&0u32.to_be_bytes());
+        for entry in &self.index {
+            self.out.extend_from_slice(&entry.raw_offset.to_be_bytes());
+            self.out.extend_from_slice(&entry.offset.to_be_bytes());
+        }
+        self.out.extend_from_slice(&(self.index.len() as u32).to_be_bytes());
+        self.summary.content_hash = std::mem::take(&mut self.hasher).finalize().into();
+        ContainerHeader::write_trailer(self.summary.original_size, &self.summary.content_hash, &mut self.out);
+
+        let out = self.take_output();
+        (out, self.summary)
+    }
+
+    /// Tokenizes and entropy codes `block` into a tagged payload, with the
+    /// tokens it used and the bytes covered by back-references, or returns
+    /// `None` if that would not make it smaller
+    fn code_block(&self, block: &[u8]) -> Option<(Vec<u8>, Vec<u32>, u64)> {
+        if self.store_raw {
+            return None;
+        }
+        let tokens = tokenize(block, &self.matcher);
+        let mut payload = self.code_tokens(&tokens, &[]);
+        let mut tokens = tokens;
+        let mut matched_bytes = 0;
+
+        // Back-references displace symbols that often code the same bytes
+        // more cheaply, so they are only kept when the block comes out smaller
+        if self.back_references {
+            let (ref_tokens, match_stream, ref_bytes) = self.tokens_with_back_references(block);
+            if !match_stream.is_empty() {
+                let ref_payload = self.code_tokens(&ref_tokens, &match_stream);
+                if ref_payload.len() < payload.len() {
+                    payload = ref_payload;
+                    tokens = ref_tokens;
+                    matched_bytes = ref_bytes;
+                }
+            }
+        }
+        if payload.len() > block.len() {
+            return None;
+        }
+        Some((payload, tokens, matched_bytes))
+    }
+
+    /// Entropy codes `tokens` behind their tag byte and match stream
+    fn code_tokens(&self, tokens: &[u32], match_stream: &[u8]) -> Vec<u8> {
+        // Fall back to Huffman, which codes any alphabet, if the chosen
+        // coder cannot handle this block
+        let mut coder = self.coder;
+        let mut coded = Vec::new();
+        if !coder.coder().encode(tokens, &mut coded) {
+            coder = EntropyCoderKind::Huffman;
+            coded.clear();
+            HuffmanCoder.encode(tokens, &mut coded);
+        }
+
+        let mut payload = Vec::with_capacity(coded.len() + match_stream.len() + 5);
+        if match_stream.is_empty() {
+            payload.push(coder.tag());
+        } else {
+            payload.push(coder.tag() | BACK_REFERENCE_FLAG);
+            payload.extend_from_slice(&(match_stream.len() as u32).to_be_bytes());
+            payload.extend_from_slice(match_stream);
+        }
+        payload.extend(coded);
+        payload
+    }
+
+    /// Tokens for `block` with repeats replaced by `MATCH_TOKEN`s, the match
+    /// stream that goes with them and the bytes they cover
+    fn tokens_with_back_references(&self, block: &[u8]) -> (Vec<u32>, Vec<u8>, u64) {
+        let mut tokens = Vec::new();
+        let mut match_stream = Vec::new();
+        let mut matched_bytes = 0;
+        let mut pos = 0;
+        for reference in find_back_references(block) {
+            tokens.extend(tokenize(&block[pos..reference.pos], &self.matcher));
+            tokens.push(MATCH_TOKEN);
+            encode_varint((reference.len - MIN_MATCH) as u64, &mut match_stream);
+            encode_varint(reference.distance as u64, &mut match_stream);
+            matched_bytes += reference.len as u64;
+            pos = reference.pos + reference.len;
+        }
+        tokens.extend(tokenize(&block[pos..], &self.matcher));
+
+        (tokens, match_stream, matched_bytes)
+    }
+
+    /// Codes the waiting full blocks side by side and appends them in order
+    fn flush_blocks(&mut self) {
+        let blocks = std::mem::take(&mut self.full_blocks);
+        let coded = parallel_map(&blocks, |block| self.code_block(block));
+        for (block, coded) in blocks.iter().zip(coded) {
+            self.append_block(block, coded);
+        }
+    }
+
+    fn append_block(&mut self, block: &[u8], coded: Option<(Vec<u8>, Vec<u32>, u64)>) {
+        let payload = match coded {
+            Some((payload, tokens, matched_bytes)) => {
+                for token in tokens {
+                    if token > 255 && token != MATCH_TOKEN {
+                        *self.summary.symbol_counts.entry(token).or_insert(0) += 1;
+                    }
+                }
+                self.summary.matched_bytes += matched_bytes;
+                payload
+            }
+            None => {
+                self.summary.stored_bytes += block.len() as u64;
+                let mut payload = Vec::with_capacity(block.len() + 1);
+                payload.push(STORED_TAG);
+                payload.extend_from_slice(block);
+                payload
+            }
+        };
+
+        self.index.push(IndexEntry {
+            raw_offset: self.raw_offset,
+            offset: self.summary.compressed_size + self.out.len() as u64,
+        });
+        self.raw_offset += block.len() as u64;
+
+        self.out.extend_from_slice(&(block.len() as u32).to_be_bytes());
+        self.out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        self.out.extend(payload);
+    }
+}
+
+/// Exact size of `len` bytes stored uncoded against the dictionary
+/// `dict_id`. Coded blobs never exceed it, since blocks that coding would
+/// not shrink are stored.
+pub fn stored_size(dict_id: &str, len: usize) -> usize {
+    let blocks = len.div_ceil(BLOCK_SIZE);
+    ContainerHeader::prefix_len(dict_id)
+        + blocks * (BLOCK_HEADER_LEN + 1 + INDEX_ENTRY_LEN)
+        + len
+        + 4
+        + 4
+        + TRAILER_LEN
+}
+
+/// Splits a block record header into raw and payload lengths. Lengths no
+/// encoder writes are rejected here, before a reader allocates for them.
+pub fn read_block_header(header: &[u8; BLOCK_HEADER_LEN]) -> Result<(usize, usize), DecompressError> {
+    let raw_len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    let payload_len = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+    if raw_len > BLOCK_SIZE || payload_len > MAX_BLOCK_PAYLOAD {
+        return Err(DecompressError::TruncatedBlock);
+    }
+    Ok((raw_len, payload_len))
+}
+
+pub fn decode_block(
+    payload: &[u8],
+    raw_len: usize,
+    dict: &Dictionary,
+    codec_version: u16,
+) -> Result<Vec<u8>, DecompressError> {
+    // Each token covers at least one byte, which bounds a corrupt count
+    let (tokens, match_stream) = if has_coder_tags(codec_version) {
+        let (&tag, mut data) = payload.split_first().ok_or(DecompressError::TruncatedBlock)?;
+        if tag == STORED_TAG {
+            return if data.len() == raw_len {
+                Ok(data.to_vec())
+            } else {
+                Err(DecompressError::TruncatedBlock)
+            };
+        }
+
+        let mut match_stream: &[u8] = &[];
+        if tag & BACK_REFERENCE_FLAG != 0 {
+            let (len, rest) = data.split_first_chunk::<4>().ok_or(DecompressError::TruncatedBlock)?;
+            let len = u32::from_be_bytes(*len) as usize;
+            if len > rest.len() {
+                return Err(DecompressError::TruncatedBlock);
+            }
+            (match_stream, data) = rest.split_at(len);
+        }
+
+        let coder_tag = tag & !BACK_REFERENCE_FLAG;
+        let kind = EntropyCoderKind::from_tag(coder_tag).ok_or(DecompressError::UnknownCoder(tag))?;
+        (kind.coder().decode(data, raw_len)?, match_stream)
+    } else {
+        (HuffmanCoder.decode(payload, raw_len)?, &[][..])
+    };
+    let out = detokenize(&tokens, match_stream, dict, raw_len)?;
+    if out.len() != raw_len {
+        return Err(DecompressError::LengthMismatch {
+            expected: raw_len as u64,
+            actual: out.len() as u64,
+        });
+    }
+    Ok(out)
+}
+
+/// Decodes the block section of a complete version 3 blob. Records are
+/// located first, then decoded a batch per worker at a time.
+pub fn decode_blocks(mut data: &[u8], dict: &Dictionary, codec_version: u16) -> Result<Vec<u8>, DecompressError> {
+    let mut records = Vec::new();
+    loop {
+        let Some((&end, _)) = data.split_first_chunk::<4>() else {
+            return Err(DecompressError::TruncatedBlock);
+        };
+        if u32::from_be_bytes(end) == 0 {
+            if data.len() != 4 {
+                return Err(DecompressError::TruncatedBlock);
+            }
+            break;
+        }
+
+        let (payload, raw_len, used) = split_block_record(data)?;
+        records.push((payload, raw_len));
+        data = &data[used..];
+    }
+
+    let mut out = Vec::new();
+    for batch in records.chunks(workers()) {
+        let blocks = parallel_map(batch, |&(payload, raw_len)| decode_block(payload, raw_len, dict, codec_version));
+        for block in blocks {
+            out.extend(block?);
+        }
+    }
+    Ok(out)
+}
+
+/// Parses the index entries split off by `container::split_index`
+pub fn read_index(entries: &[u8]) -> Vec<IndexEntry> {
+    entries.chunks_exact(INDEX_ENTRY_LEN)
+        .map(|entry| IndexEntry {
+            raw_offset: u64::from_be_bytes(entry[..8].try_into().unwrap()),
+            offset: u64::from_be_bytes(entry[8..].try_into().unwrap()),
+        })
+        .collect()
+}
+
+/// Decodes the single block record at the start of `data`, returning its
+/// bytes and the size of the record
+pub fn decode_block_at(
+    data: &[u8],
+    dict: &Dictionary,
+    codec_version: u16,
+) -> Result<(Vec<u8>, usize), DecompressError> {
+    let (payload, raw_len, used) = split_block_record(data)?;
+    let block = decode_block(payload, raw_len, dict, codec_version)?;
+    Ok((block, used))
+}
+
+/// Payload and raw length of the block record at the start of `data`, and
+/// the size of the record
+fn split_block_record(data: &[u8]) -> Result<(&[u8], usize, usize), DecompressError> {
+    let Some((header, rest)) = data.split_first_chunk::<BLOCK_HEADER_LEN>() else {
+        return Err(DecompressError::TruncatedBlock);
+    };
+    let (raw_len, payload_len) = read_block_header(header)?;
+    if raw_len == 0 || payload_len > rest.len() {
+        return Err(DecompressError::TruncatedBlock);
+    }
+    Ok((&rest[..payload_len], raw_len, BLOCK_HEADER_LEN + payload_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::container::CODEC_VERSION;
+    use crate::engine::decompressor::{decompress, decompress_range};
+
+    fn encode(data: &[u8], dict: &Dictionary, setup: impl FnOnce(&mut BlockEncoder)) -> (Vec<u8>, StreamSummary) {
+        let mut encoder = BlockEncoder::new(dict, EntropyCoderKind::Huffman);
+        setup(&mut encoder);
+        // Pushed in uneven pieces, as uploads arrive
+        for piece in data.chunks(100_003) {
+            encoder.push(piece);
+        }
+        encoder.finish()
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state = 7u32;
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            out.extend_from_slice(format!("row {} value {} ", state % 5000, state % 7).as_bytes());
+            if state.is_multiple_of(11) {
+                let back = out.len().min(400);
+                out.extend_from_within(out.len() - back..);
+            }
+        }
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn back_references_roundtrip_across_blocks() {
+        let mut dict = Dictionary::new("test");
+        dict.insert_symbol(b"value ".to_vec(), 256);
+        let data = sample(BLOCK_SIZE * 2 + 1234);
+
+        let (blob, summary) = encode(&data, &dict, |e| e.enable_back_references());
+        assert!(summary.matched_bytes > 0);
+        assert!(summary.symbol_counts[&256] > 0);
+        assert_eq!(decompress(&blob, &dict).unwrap(), data);
+
+        let range = decompress_range(&blob, &dict, BLOCK_SIZE as u64 - 10, 20).unwrap();
+        assert_eq!(range, &data[BLOCK_SIZE - 10..BLOCK_SIZE + 10]);
+    }
+
+    #[test]
+    fn stored_blocks_roundtrip() {
+        let dict = Dictionary::new("test");
+        let data = sample(BLOCK_SIZE + 10);
+        let (blob, summary) = encode(&data, &dict, |e| e.store_raw());
+        assert_eq!(summary.stored_bytes, data.len() as u64);
+        assert_eq!(blob.len(), stored_size(&summary.dict_id, data.len()));
+        assert_eq!(decompress(&blob, &dict).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_bad_back_reference() {
+        let dict = Dictionary::new("test");
+        // Literal 'a', then a match reaching past the start of the block
+        let mut match_stream = Vec::new();
+        encode_varint(0, &mut match_stream);
+        encode_varint(2, &mut match_stream);
+        let mut payload = vec![EntropyCoderKind::Huffman.tag() | BACK_REFERENCE_FLAG];
+        payload.extend_from_slice(&(match_stream.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&match_stream);
+        HuffmanCoder.encode(&[b'a' as u32, MATCH_TOKEN], &mut payload);
+
+        assert!(matches!(
+            decode_block(&payload, 1 + MIN_MATCH, &dict, CODEC_VERSION),
+            Err(DecompressError::BadBackReference)
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_block_section() {
+        let dict = Dictionary::new("test");
+        let (blob, _) = encode(b"some content to frame in a block", &dict, |_| {});
+        let (_, payload) = ContainerHeader::read(&blob).unwrap().unwrap();
+        assert!(decode_blocks(payload, &dict, CODEC_VERSION).is_ok());
+        for len in 0..payload.len() {
+            assert!(decode_blocks(&payload[..len], &dict, CODEC_VERSION).is_err(), "accepted {} bytes", len);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Content-defined chunking. Cut points depend only on the bytes before
+//! them, so an edit moves at most the chunks around it and the rest of an
+//! object splits exactly as it did before.
+
+use fastcdc::v2020::FastCDC;
+
+pub const MIN_CHUNK: u32 = 16 * 1024;
+pub const AVG_CHUNK: u32 = 64 * 1024;
+/// No larger than a block, so every chunk codes as a single block
+pub const MAX_CHUNK: u32 = 256 * 1024;
+
+/// Splits a stream into content-defined chunks as it arrives
+#[derive(Default)]
+pub struct ContentChunker {
+    // Bytes after the last cut, always starting on a chunk boundary
+    pending: Vec<u8>,
+}
+
+impl ContentChunker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `data` and returns every chunk whose end is now known. The
+    /// last chunk is held back, since it may only end where the input ran
+    /// out so far.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+        let mut cuts = cut_points(&self.pending);
+        cuts.pop();
+
+        let consumed = cuts.last().map_or(0, |&(offset, len)| offset + len);
+        let chunks = cuts.iter()
+            .map(|&(offset, len)| self.pending[offset..offset + len].to_vec())
+            .collect();
+        self.pending.drain(..consumed);
+        chunks
+    }
+
+    /// Returns the chunks left once the input has ended
+    pub fn finish(self) -> Vec<Vec<u8>> {
+        cut_points(&self.pending).into_iter()
+            .map(|(offset, len)| self.pending[offset..offset + len].to_vec())
+            .collect()
+    }
+}
+
+fn cut_points(data: &[u8]) -> Vec<(usize, usize)> {
+    FastCDC::new(data, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK)
+        .map(|chunk| (chunk.offset, chunk.length))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    fn chunk_all(data: &[u8], piece: usize) -> Vec<Vec<u8>> {
+        let mut chunker = ContentChunker::new();
+        let mut chunks = Vec::new();
+        for part in data.chunks(piece) {
+            chunks.extend(chunker.push(part));
+        }
+        chunks.extend(chunker.finish());
+        chunks
+    }
+
+    #[test]
+    fn cuts_do_not_depend_on_how_input_arrives() {
+        let data = sample(1024 * 1024, 0x9e37_79b9_7f4a_7c15);
+        let whole = chunk_all(&data, data.len());
+        assert!(whole.len() > 1);
+        assert_eq!(whole.concat(), data);
+        for piece in [1000, 64 * 1024, 300 * 1024] {
+            assert_eq!(chunk_all(&data, piece), whole, "pieces of {}", piece);
+        }
+
+        let (last, rest) = whole.split_last().unwrap();
+        assert!(rest.iter().all(|c| (MIN_CHUNK as usize..=MAX_CHUNK as usize).contains(&c.len())));
+        assert!(last.len() <= MAX_CHUNK as usize);
+    }
+
+    #[test]
+    fn edit_only_moves_nearby_chunks() {
+        let data = sample(1024 * 1024, 0x2545_f491_4f6c_dd1d);
+        let mut edited = data.clone();
+        edited.splice(500_000..500_010, b"an inserted edit".iter().copied());
+
+        let before = chunk_all(&data, data.len());
+        let after = chunk_all(&edited, edited.len());
+        let changed = after.iter().filter(|c| !before.contains(c)).count();
+        assert!(changed <= 2, "{} chunks changed", changed);
+    }
+
+    #[test]
+    fn empty_input_has_no_chunks() {
+        assert!(chunk_all(&[], 1).is_empty());
+    }
+}
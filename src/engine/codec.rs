@@ -0,0 +1,217 @@
+use crate::engine::{
+    block::{stored_size, BlockEncoder},
+    compressor::{encode, learn_symbols, summarize},
+    config::EngineConfig,
+    decompressor::{decompress, dictionary_id},
+    error::DecompressError,
+    estimate::incompressible_reason,
+};
+use crate::storage::{
+    dictionary::Dictionary,
+    symbols::SymbolStore,
+    metadata::{SymbolInfo, TokenBreakdown},
+};
+
+/// Symbol planning and tokenizing followed by entropy coding
+pub const SYMBOL_CODEC: &str = "symbol";
+
+/// Original bytes kept as they are, inside the same container
+pub const STORED_CODEC: &str = "stored";
+
+/// Content-defined chunks each stored once in the chunk store and shared
+/// between objects. The object's own blob is empty; its metadata lists
+/// the chunks, which are decoded with the codecs that wrote them.
+pub const CHUNKED_CODEC: &str = "chunked";
+
+/// Blob, symbols used, explained ratio and token breakdown of an encoded object
+pub type Encoded = (Vec<u8>, Vec<SymbolInfo>, f64, TokenBreakdown);
+
+/// A way of turning an object into a stored blob and back. The id is
+/// persisted in each object's metadata so downloads decode with the codec
+/// that wrote it.
+pub trait Codec: Send + Sync {
+    fn id(&self) -> &'static str;
+
+    /// Teaches a mutable dictionary from `input` before it is encoded. This
+    /// is the only step that changes the dictionary.
+    fn learn(
+        &self,
+        _input: &[u8],
+        _dict: &mut Dictionary,
+        _symbol_store: &SymbolStore,
+        _config: &EngineConfig,
+    ) -> Vec<SymbolInfo> {
+        Vec::new()
+    }
+
+    fn encode(
+        &self,
+        input: &[u8],
+        dict: &Dictionary,
+        symbol_store: &SymbolStore,
+        object_key: &str,
+        config: &EngineConfig,
+        symbol_infos: Vec<SymbolInfo>,
+    ) -> (Vec<u8>, Vec<SymbolInfo>, f64, TokenBreakdown);
+
+    fn decompress(&self, data: &[u8], dict: &Dictionary) -> Result<Vec<u8>, DecompressError>;
+}
+
+pub struct SymbolCodec;
+
+impl Codec for SymbolCodec {
+    fn id(&self) -> &'static str {
+        SYMBOL_CODEC
+    }
+
+    fn learn(
+        &self,
+        input: &[u8],
+        dict: &mut Dictionary,
+        symbol_store: &SymbolStore,
+        config: &EngineConfig,
+    ) -> Vec<SymbolInfo> {
+        if dict.frozen {
+            return Vec::new();
+        }
+        learn_symbols(input, dict, symbol_store, config)
+    }
+
+    fn encode(
+        &self,
+        input: &[u8],
+        dict: &Dictionary,
+        symbol_store: &SymbolStore,
+        object_key: &str,
+        config: &EngineConfig,
+        symbol_infos: Vec<SymbolInfo>,
+    ) -> (Vec<u8>, Vec<SymbolInfo>, f64, TokenBreakdown) {
+        encode(input, dict, symbol_store, object_key, config, symbol_infos)
+    }
+
+    fn decompress(&self, data: &[u8], dict: &Dictionary) -> Result<Vec<u8>, DecompressError> {
+        decompress(data, dict)
+    }
+}
+
+pub struct StoredCodec;
+
+impl Codec for StoredCodec {
+    fn id(&self) -> &'static str {
+        STORED_CODEC
+    }
+
+    fn encode(
+        &self,
+        input: &[u8],
+        dict: &Dictionary,
+        symbol_store: &SymbolStore,
+        object_key: &str,
+        config: &EngineConfig,
+        _symbol_infos: Vec<SymbolInfo>,
+    ) -> (Vec<u8>, Vec<SymbolInfo>, f64, TokenBreakdown) {
+        let mut encoder = BlockEncoder::new(dict, config.entropy_coder);
+        encoder.store_raw();
+        encoder.push(input);
+        let (output, summary) = encoder.finish();
+
+        let reason = incompressible_reason(input).unwrap_or_else(|| "Stored codec selected".to_string());
+        let (symbol_infos, explained_ratio, token_breakdown) =
+            summarize(&summary, dict, symbol_store, object_key, Vec::new(), Some(reason));
+
+        (output, symbol_infos, explained_ratio, token_breakdown)
+    }
+
+    fn decompress(&self, data: &[u8], dict: &Dictionary) -> Result<Vec<u8>, DecompressError> {
+        decompress(data, dict)
+    }
+}
+
+/// Encodes `input` with `codec`, then stores it instead unless coding came
+/// out smaller than storing. `choose_codec` only guesses from the leading
+/// bytes, and passes small inputs whatever they hold. Returns the codec
+/// that wrote the blob.
+pub fn encode_or_store(
+    codec: &'static dyn Codec,
+    input: &[u8],
+    dict: &Dictionary,
+    symbol_store: &SymbolStore,
+    object_key: &str,
+    config: &EngineConfig,
+    symbol_infos: Vec<SymbolInfo>,
+) -> (&'static dyn Codec, Encoded) {
+    let encoded = codec.encode(input, dict, symbol_store, object_key, config, symbol_infos);
+    if codec.id() == STORED_CODEC || encoded.0.len() < stored_size(&dictionary_id(dict), input.len()) {
+        return (codec, encoded);
+    }
+
+    let mut stored = StoredCodec.encode(input, dict, symbol_store, object_key, config, Vec::new());
+    stored.3.stored_reason = Some("Coding did not shrink the object".to_string());
+    (&StoredCodec, stored)
+}
+
+pub fn codec_by_id(id: &str) -> Option<&'static dyn Codec> {
+    match id {
+        SYMBOL_CODEC => Some(&SymbolCodec),
+        STORED_CODEC => Some(&StoredCodec),
+        _ => None,
+    }
+}
+
+/// Picks the codec for a new object from a look at its leading bytes.
+/// Already-compressed or random input is stored and kept out of the planner.
+pub fn choose_codec(input: &[u8]) -> &'static dyn Codec {
+    if incompressible_reason(input).is_some() {
+        &StoredCodec
+    } else {
+        &SymbolCodec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(input: &[u8], name: &str) -> (&'static dyn Codec, Vec<u8>, Dictionary) {
+        let dir = std::env::temp_dir().join(format!("symvea-codec-{}-{}", name, std::process::id()));
+        let symbol_store = SymbolStore::new(dir.to_string_lossy().to_string());
+        let mut dict = Dictionary::new("test");
+        let config = EngineConfig::default();
+        let codec = choose_codec(input);
+        let learned = codec.learn(input, &mut dict, &symbol_store, &config);
+        let (codec, (blob, ..)) = encode_or_store(codec, input, &dict, &symbol_store, "key", &config, learned);
+        std::fs::remove_dir_all(&dir).ok();
+        (codec, blob, dict)
+    }
+
+    #[test]
+    fn small_inputs_never_grow_past_stored() {
+        let mut rng = 0x9e37_79b9u32;
+        let random: Vec<u8> = (0..2000).map(|_| {
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+            rng as u8
+        }).collect();
+        let short = b"golden vector: a short input that still repeats, still repeats";
+
+        for (name, input) in [("empty", &[][..]), ("byte", b"x"), ("short", short), ("random", &random)] {
+            let (codec, blob, dict) = encode(input, name);
+            assert!(blob.len() <= stored_size(&dictionary_id(&dict), input.len()), "{} grew", name);
+            assert_eq!(codec.decompress(&blob, &dict).unwrap(), input, "{}", name);
+        }
+
+        // Too small for the entropy check, but coding cannot shrink it
+        let (codec, ..) = encode(&random, "random");
+        assert_eq!(codec.id(), STORED_CODEC);
+    }
+
+    #[test]
+    fn compressible_input_keeps_its_codec() {
+        let input = b"the block symbol dictionary stream ".repeat(200);
+        let (codec, blob, dict) = encode(&input, "text");
+        assert_eq!(codec.id(), SYMBOL_CODEC);
+        assert!(blob.len() < input.len());
+        assert_eq!(codec.decompress(&blob, &dict).unwrap(), input);
+    }
+}
@@ -1,8 +1,8 @@
 use crate::engine::{
-    tokenizer::tokenize,
-    planner::plan_symbols,
-    huffman::HuffmanTable,
+    planner::plan_symbols_limited,
     symbols::Symbol,
+    config::EngineConfig,
+    block::{BlockEncoder, StreamSummary},
 };
 use crate::storage::{
     dictionary::Dictionary,
@@ -10,133 +10,121 @@ use crate::storage::{
     metadata::{SymbolInfo, TokenBreakdown},
 };
 
-pub fn compress(
+/// Encodes `input` against a dictionary that is no longer changing.
+/// Symbols already learned from `input` are passed in.
+pub fn encode(
     input: &[u8],
-    dict: &mut Dictionary,
+    dict: &Dictionary,
     symbol_store: &SymbolStore,
     object_key: &str,
+    config: &EngineConfig,
+    symbol_infos: Vec<SymbolInfo>,
 ) -> (Vec<u8>, Vec<SymbolInfo>, f64, TokenBreakdown) {
+    let mut encoder = BlockEncoder::new(dict, config.entropy_coder);
+    if config.back_references {
+        encoder.enable_back_references();
+    }
+    encoder.push(input);
+    let (output, summary) = encoder.finish();
+    
+    let (symbol_infos, explained_ratio, token_breakdown) =
+        summarize(&summary, dict, symbol_store, object_key, symbol_infos, None);
+    
+    (output, symbol_infos, explained_ratio, token_breakdown)
+}
+
+/// Plans symbols from `input` into a mutable dictionary and records them in
+/// the symbol store. Large inputs are planned from a prefix sample, which
+/// also makes this usable on the first chunk of a streamed upload.
+pub fn learn_symbols(
+    input: &[u8],
+    dict: &mut Dictionary,
+    symbol_store: &SymbolStore,
+    config: &EngineConfig,
+) -> Vec<SymbolInfo> {
     let mut symbol_infos = Vec::new();
     
-    if !dict.frozen {
-        let symbols = if input.len() > 1024 * 1024 {
-            let sample_size = (input.len() / 10).min(64 * 1024);
-            plan_symbols(&input[..sample_size], 16)
-        } else {
-            plan_symbols(input, 32)
-        };
-        
-        for s in symbols {
-            let symbol = Symbol::new(s.bytes.clone(), s.token, s.gain);
-            
-            // Store symbol globally
-            symbol_store.store_symbol(&symbol.hash, &symbol.bytes).ok();
-            symbol_store.add_usage(&symbol.hash, object_key, symbol.bytes.len() as u64, 1).ok();
-            
-            // Track for metadata
-            symbol_infos.push(SymbolInfo {
-                hash: symbol.hash.clone(),
-                bytes: symbol.bytes.len() as u64,
-            });
-            
-            dict.encode.insert(symbol.bytes.clone(), symbol.token);
-            dict.decode.insert(symbol.token, symbol.bytes);
-        }
+    let sample = if input.len() > config.sample_threshold {
+        let sample_size = (input.len() / 10).min(config.max_sample);
+        &input[..sample_size]
     } else {
-        // Dictionary is frozen, track existing symbols with actual usage counts
-        let tokens = tokenize(input, &dict.encode.iter().map(|(b, t)| Symbol::new(b.clone(), *t, 0)).collect::<Vec<_>>());
+        input
+    };
+    let symbols = plan_symbols_limited(sample, config.max_symbol_len, config.max_symbols, config.min_gain_bytes);
+    
+    for s in symbols {
+        // Planned tokens restart at 256 each time; the dictionary allocates
+        // the real ones so earlier generations stay valid. A dictionary with
+        // no tokens left learns nothing more.
+        let Some(token) = dict.append_symbol(s.bytes.clone()) else {
+            break;
+        };
+        let symbol = Symbol::new(s.bytes, token, s.gain);
         
-        // Count actual symbol usage in this file
-        let mut symbol_counts = std::collections::HashMap::new();
-        for &token in &tokens {
-            if let Some(bytes) = dict.decode.get(&token) {
-                let symbol = Symbol::new(bytes.clone(), token, 0);
-                *symbol_counts.entry(symbol.hash.clone()).or_insert(0) += 1;
-                
-                if !symbol_infos.iter().any(|s| s.hash == symbol.hash) {
-                    symbol_infos.push(SymbolInfo {
-                        hash: symbol.hash.clone(),
-                        bytes: symbol.bytes.len() as u64,
-                    });
-                }
-            }
-        }
+        // Store symbol globally; usage is recorded once the object is
+        // encoded and the symbol's real occurrences are known
+        symbol_store.store_symbol(&symbol.hash, &symbol.bytes).ok();
         
-        // Update usage with actual counts
-        for (hash, count) in symbol_counts {
-            if let Some(info) = symbol_infos.iter().find(|s| s.hash == hash) {
-                symbol_store.add_usage(&hash, object_key, info.bytes, count).ok();
-            }
-        }
+        // Track for metadata
+        symbol_infos.push(SymbolInfo {
+            hash: symbol.hash.clone(),
+            bytes: symbol.bytes.len() as u64,
+        });
     }
-
-    let symbols = dict.encode.iter().map(|(b, t)| {
-        Symbol::new(b.clone(), *t, 0)
-    }).collect::<Vec<_>>();
-
-    let tokens = tokenize(input, &symbols);
     
-    // Calculate explained bytes from actual token usage
-    let mut explained_bytes = 0u64;
-    let mut literal_bytes = 0u64;
-    
-    for &token in &tokens {
+    symbol_infos
+}
+
+/// Turns what the encoder saw into the object's symbol metadata, recording
+/// how often each symbol was used so pruning can measure its contribution.
+pub fn summarize(
+    summary: &StreamSummary,
+    dict: &Dictionary,
+    symbol_store: &SymbolStore,
+    object_key: &str,
+    mut symbol_infos: Vec<SymbolInfo>,
+    stored_reason: Option<String>,
+) -> (Vec<SymbolInfo>, f64, TokenBreakdown) {
+    // Track symbols with actual usage counts, hashing each distinct symbol
+    // once. Symbols learned from this object are already listed.
+    for (&token, &count) in &summary.symbol_counts {
         if let Some(bytes) = dict.decode.get(&token) {
-            explained_bytes += bytes.len() as u64;
-        } else {
-            literal_bytes += 1; // Raw byte
-        }
-    }
-    
-    let huffman_table = HuffmanTable::build(&tokens);
-    let huffman_data = huffman_table.encode(&tokens);
-    
-    let mut output = Vec::new();
-    output.extend_from_slice(&(huffman_table.encode_table.len() as u32).to_be_bytes());
-    
-    for (&token, code) in &huffman_table.encode_table {
-        output.extend_from_slice(&token.to_be_bytes());
-        output.push(code.len() as u8);
-        
-        let mut code_bytes = Vec::new();
-        let mut current_byte = 0u8;
-        let mut bit_count = 0;
-        
-        for &bit in code {
-            if bit {
-                current_byte |= 1 << (7 - bit_count);
-            }
-            bit_count += 1;
-            
-            if bit_count == 8 {
-                code_bytes.push(current_byte);
-                current_byte = 0;
-                bit_count = 0;
+            let symbol = Symbol::new(bytes.clone(), token, 0);
+            symbol_store.add_usage(&symbol.hash, object_key, symbol.bytes.len() as u64, count).ok();
+            if dict.frozen {
+                symbol_infos.push(SymbolInfo {
+                    hash: symbol.hash,
+                    bytes: symbol.bytes.len() as u64,
+                });
             }
         }
-        
-        if bit_count > 0 {
-            code_bytes.push(current_byte);
-        }
-        
-        output.push(code_bytes.len() as u8);
-        output.extend(code_bytes);
     }
+
+    // Calculate explained bytes from actual token usage
+    let explained_bytes: u64 = summary.symbol_counts.iter()
+        .filter_map(|(token, &count)| dict.decode.get(token).map(|bytes| bytes.len() as u64 * count))
+        .sum();
+    let literal_bytes = summary.original_size - explained_bytes - summary.stored_bytes - summary.matched_bytes;
     
-    output.extend_from_slice(&(huffman_data.len() as u32).to_be_bytes());
-    output.extend(huffman_data);
-    
-    let explained_ratio = if input.len() > 0 {
-        explained_bytes as f64 / input.len() as f64
+    let explained_ratio = if summary.original_size > 0 {
+        explained_bytes as f64 / summary.original_size as f64
     } else {
         0.0
     };
     
+    let stored_reason = stored_reason.or_else(|| {
+        (summary.stored_bytes > 0).then(|| "Coding did not shrink some blocks".to_string())
+    });
+    
     let token_breakdown = TokenBreakdown {
         symbol_bytes: explained_bytes,
         literal_bytes,
         literal_reason: "Below promotion threshold".to_string(),
+        stored_bytes: summary.stored_bytes,
+        stored_reason,
+        matched_bytes: summary.matched_bytes,
+        deduplicated_bytes: 0,
     };
     
-    (output, symbol_infos, explained_ratio, token_breakdown)
+    (symbol_infos, explained_ratio, token_breakdown)
 }
@@ -1,18 +1,128 @@
+use serde::{Deserialize, Serialize};
+
+use crate::engine::entropy::EntropyCoderKind;
+
+/// Speed/ratio trade-off for an upload: how much of it the planner looks
+/// at, how many and how long the learned symbols may be, and which
+/// entropy coder the blocks prefer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionLevel {
+    Fast,
+    #[default]
+    Default,
+    Max,
+}
+
+impl CompressionLevel {
+    /// Byte sent after `FRAME_FLAG_LEVEL`
+    pub fn tag(self) -> u8 {
+        match self {
+            CompressionLevel::Fast => 0,
+            CompressionLevel::Default => 1,
+            CompressionLevel::Max => 2,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(CompressionLevel::Fast),
+            1 => Some(CompressionLevel::Default),
+            2 => Some(CompressionLevel::Max),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CompressionLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompressionLevel::Fast => write!(f, "fast"),
+            CompressionLevel::Default => write!(f, "default"),
+            CompressionLevel::Max => write!(f, "max"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct EngineConfig {
+    /// Level the planner and coder settings below were taken from
+    pub level: CompressionLevel,
     pub max_symbol_len: usize,
+    /// Most symbols learned from a single upload
+    pub max_symbols: usize,
+    /// Inputs larger than this are planned from a 10% prefix sample
+    pub sample_threshold: usize,
+    /// Cap on that prefix sample
+    pub max_sample: usize,
+    /// Least a planned symbol must save over the sample to be learned
     pub min_gain_bytes: isize,
+    /// Give uploads that carry a user id that user's own dictionary
     pub allow_user_dict: bool,
+    /// Layer user dictionaries on the frozen global dictionary; when off
+    /// they stand alone
     pub allow_global_dict: bool,
+    /// Preferred coder for block payloads
+    pub entropy_coder: EntropyCoderKind,
+    /// Code long repeats within a block as back-references
+    pub back_references: bool,
+    /// Symbols that saved fewer bytes than this across the corpus are
+    /// dropped when the global dictionary is frozen
+    pub prune_min_contribution: u64,
+    /// Store larger uploads as content-defined chunks shared between
+    /// objects. Off unless asked for: only corpora of near-identical
+    /// uploads gain from it, and every other upload pays for coding each
+    /// chunk on its own.
+    pub dedup_chunks: bool,
+}
+
+impl EngineConfig {
+    /// This configuration with the planner and coder settings of `level`
+    pub fn with_level(&self, level: CompressionLevel) -> Self {
+        let mut config = self.clone();
+        config.level = level;
+        match level {
+            CompressionLevel::Fast => {
+                config.max_symbol_len = 32;
+                config.max_symbols = 256;
+                config.sample_threshold = 256 * 1024;
+                config.max_sample = 16 * 1024;
+                config.entropy_coder = EntropyCoderKind::Huffman;
+            }
+            CompressionLevel::Default => {
+                config.max_symbol_len = 64;
+                config.max_symbols = 1000;
+                config.sample_threshold = 1024 * 1024;
+                config.max_sample = 64 * 1024;
+                config.entropy_coder = EntropyCoderKind::Huffman;
+            }
+            CompressionLevel::Max => {
+                config.max_symbol_len = 128;
+                config.max_symbols = 4000;
+                config.sample_threshold = 8 * 1024 * 1024;
+                config.max_sample = 1024 * 1024;
+                config.entropy_coder = EntropyCoderKind::Rans;
+            }
+        }
+        config
+    }
 }
 
 impl Default for EngineConfig {
     fn default() -> Self {
         Self {
+            level: CompressionLevel::Default,
             max_symbol_len: 64,
+            max_symbols: 1000,
+            sample_threshold: 1024 * 1024,
+            max_sample: 64 * 1024,
             min_gain_bytes: 2,
             allow_user_dict: true,
             allow_global_dict: true,
+            entropy_coder: EntropyCoderKind::Huffman,
+            back_references: true,
+            prune_min_contribution: 1,
+            dedup_chunks: false,
         }
     }
 }
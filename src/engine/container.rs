@@ -0,0 +1,236 @@
+use crate::engine::error::DecompressError;
+
+/// Leading bytes of every framed blob. A headerless version 1 blob starts
+/// with its Huffman table entry count instead, which can never be this large.
+pub const MAGIC: [u8; 4] = *b"SYMV";
+
+/// Blobs written before the container: no header, just the legacy Huffman
+/// table with every code spelled out, followed by the stream. Never framed;
+/// a header claiming this version is rejected.
+pub const CODEC_V1: u16 = 1;
+
+/// Container header followed by a single payload coded with the canonical
+/// Huffman table, which stores code lengths only
+pub const CODEC_V2: u16 = 2;
+
+/// Independently coded blocks, with the original size and hash moved to a
+/// trailer so the container can be written in a single pass
+pub const CODEC_V3: u16 = 3;
+
+/// Version 3 with a block index in front of the trailer
+pub const CODEC_V4: u16 = 4;
+
+/// Version 4 with each block payload tagged by its entropy coder
+pub const CODEC_VERSION: u16 = 5;
+
+/// Original size (u64) followed by the SHA-256 of the original content
+pub const TRAILER_LEN: usize = 8 + 32;
+
+/// Raw offset (u64) and container offset (u64) of one block
+pub const INDEX_ENTRY_LEN: usize = 16;
+
+/// Everything needed to decode and check a blob without its `.meta` sidecar.
+///
+/// On disk (big endian) a header starts with the magic, the codec version
+/// (u16) and the dict id length (u8) and bytes. Version 2 follows that with
+/// the original size and content hash; later versions keep them in the
+/// trailer, which from version 4 on is preceded by the index entries and
+/// their count (u32).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerHeader {
+    pub codec_version: u16,
+    pub dict_id: String,
+    pub original_size: u64,
+    pub content_hash: [u8; 32],
+}
+
+impl ContainerHeader {
+    /// Length of the prefix `write_prefix` writes for `dict_id`
+    pub fn prefix_len(dict_id: &str) -> usize {
+        MAGIC.len() + 2 + 1 + dict_id.len()
+    }
+
+    /// Writes the part of the header that is known before any content
+    pub fn write_prefix(dict_id: &str, out: &mut Vec<u8>) {
+        let dict_id = dict_id.as_bytes();
+        assert!(dict_id.len() <= u8::MAX as usize, "dictionary id too long for container header");
+
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&CODEC_VERSION.to_be_bytes());
+        out.push(dict_id.len() as u8);
+        out.extend_from_slice(dict_id);
+    }
+
+    pub fn write_trailer(original_size: u64, content_hash: &[u8; 32], out: &mut Vec<u8>) {
+        out.extend_from_slice(&original_size.to_be_bytes());
+        out.extend_from_slice(content_hash);
+    }
+
+    /// Parses magic, version and dict id, returning them and the number of
+    /// bytes consumed. Returns `Ok(None)` for a headerless version 1 blob.
+    pub fn read_prefix(data: &[u8]) -> Result<Option<(u16, String, usize)>, DecompressError> {
+        if !data.starts_with(&MAGIC) {
+            // A short prefix of the magic may still be a framed blob
+            if data.len() < MAGIC.len() && MAGIC.starts_with(data) {
+                return Err(DecompressError::TruncatedHeader);
+            }
+            return Ok(None);
+        }
+
+        let mut rest = &data[MAGIC.len()..];
+
+        let codec_version = u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap());
+        if !is_supported(codec_version) || codec_version == CODEC_V1 {
+            return Err(DecompressError::UnsupportedVersion(codec_version));
+        }
+
+        let dict_id_len = take(&mut rest, 1)?[0] as usize;
+        let dict_id = String::from_utf8(take(&mut rest, dict_id_len)?.to_vec())
+            .map_err(|_| DecompressError::TruncatedHeader)?;
+
+        Ok(Some((codec_version, dict_id, data.len() - rest.len())))
+    }
+
+    pub fn read_trailer(trailer: &[u8; TRAILER_LEN]) -> (u64, [u8; 32]) {
+        let original_size = u64::from_be_bytes(trailer[..8].try_into().unwrap());
+        (original_size, trailer[8..].try_into().unwrap())
+    }
+
+    /// Parses the header of a complete blob, returning it and the payload
+    /// between header and trailer. Returns `Ok(None)` for a headerless
+    /// version 1 blob, whose payload is all of `data`.
+    pub fn read(data: &[u8]) -> Result<Option<(Self, &[u8])>, DecompressError> {
+        let Some((codec_version, dict_id, prefix_len)) = Self::read_prefix(data)? else {
+            return Ok(None);
+        };
+
+        let mut rest = &data[prefix_len..];
+        let (original_size, content_hash, payload) = if codec_version == CODEC_V2 {
+            let original_size = u64::from_be_bytes(take(&mut rest, 8)?.try_into().unwrap());
+            let content_hash = take(&mut rest, 32)?.try_into().unwrap();
+            (original_size, content_hash, rest)
+        } else {
+            let split = rest.len().checked_sub(TRAILER_LEN).ok_or(DecompressError::TruncatedHeader)?;
+            let (mut payload, trailer) = rest.split_at(split);
+            let (original_size, content_hash) = Self::read_trailer(trailer.try_into().unwrap());
+            if has_index(codec_version) {
+                payload = split_index(payload)?.0;
+            }
+            (original_size, content_hash, payload)
+        };
+
+        let header = Self {
+            codec_version,
+            dict_id,
+            original_size,
+            content_hash,
+        };
+        Ok(Some((header, payload)))
+    }
+}
+
+/// Splits the block index off the end of `data`, which must stop right
+/// before the trailer. Returns the bytes in front of the index and the raw
+/// index entries.
+pub fn split_index(data: &[u8]) -> Result<(&[u8], &[u8]), DecompressError> {
+    let Some((rest, count)) = data.split_last_chunk::<4>() else {
+        return Err(DecompressError::TruncatedHeader);
+    };
+    let index_len = (u32::from_be_bytes(*count) as usize)
+        .checked_mul(INDEX_ENTRY_LEN)
+        .filter(|&len| len <= rest.len())
+        .ok_or(DecompressError::TruncatedHeader)?;
+
+    Ok(rest.split_at(rest.len() - index_len))
+}
+
+fn take<'a>(rest: &mut &'a [u8], n: usize) -> Result<&'a [u8], DecompressError> {
+    if rest.len() < n {
+        return Err(DecompressError::TruncatedHeader);
+    }
+    let (head, tail) = rest.split_at(n);
+    *rest = tail;
+    Ok(head)
+}
+
+/// Whether this build can decode blobs written with `codec_version`
+pub fn is_supported(codec_version: u16) -> bool {
+    (CODEC_V1..=CODEC_VERSION).contains(&codec_version)
+}
+
+/// Whether blobs of `codec_version` carry a block index
+pub fn has_index(codec_version: u16) -> bool {
+    codec_version >= CODEC_V4
+}
+
+/// Whether block payloads of `codec_version` start with an entropy coder tag
+pub fn has_coder_tags(codec_version: u16) -> bool {
+    codec_version >= CODEC_VERSION
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(dict_id: &str, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        ContainerHeader::write_prefix(dict_id, &mut out);
+        out.extend_from_slice(payload);
+        out.extend_from_slice(&0u32.to_be_bytes()); // empty block index
+        ContainerHeader::write_trailer(payload.len() as u64, &[7; 32], &mut out);
+        out
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let blob = framed("dict-1", b"payload");
+        let (header, payload) = ContainerHeader::read(&blob).unwrap().unwrap();
+        assert_eq!(header, ContainerHeader {
+            codec_version: CODEC_VERSION,
+            dict_id: "dict-1".to_string(),
+            original_size: 7,
+            content_hash: [7; 32],
+        });
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn headerless_blob_is_not_framed() {
+        assert!(ContainerHeader::read(&[0, 0, 0, 3, 0, 0, 0, 97]).unwrap().is_none());
+        assert!(ContainerHeader::read(b"").is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        let blob = framed("dict-1", b"");
+        for len in 0..blob.len() {
+            assert!(ContainerHeader::read(&blob[..len]).is_err(), "accepted {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        for version in [0, CODEC_V1, CODEC_VERSION + 1] {
+            let mut blob = framed("dict-1", b"payload");
+            blob[4..6].copy_from_slice(&version.to_be_bytes());
+            assert!(matches!(
+                ContainerHeader::read(&blob),
+                Err(DecompressError::UnsupportedVersion(v)) if v == version
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_index_larger_than_payload() {
+        let mut data = b"payload".to_vec();
+        data.extend_from_slice(&1u32.to_be_bytes());
+        assert!(matches!(split_index(&data), Err(DecompressError::TruncatedHeader)));
+        assert!(matches!(split_index(&[0, 0, 0]), Err(DecompressError::TruncatedHeader)));
+
+        let mut data = vec![0xAA; INDEX_ENTRY_LEN];
+        data.extend_from_slice(&1u32.to_be_bytes());
+        let (rest, index) = split_index(&data).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(index.len(), INDEX_ENTRY_LEN);
+    }
+}
@@ -0,0 +1,230 @@
+//! Content detection and the candidate symbols each kind of content
+//! suggests beyond the repeats the suffix array finds. Repeats are
+//! maximal, so a token that always sits inside longer, varying runs (a
+//! JSON key between changing values) never surfaces as one on its own.
+
+use std::collections::HashMap;
+
+/// Share of control bytes above which valid UTF-8 is still treated as binary
+const MAX_CONTROL_BYTES_PER_MILLE: usize = 10;
+
+/// Field widths tried on binary input, at offsets aligned to the width
+const FIELD_WIDTHS: [usize; 3] = [4, 8, 16];
+
+/// Bytes that make up JSON structure around keys and values
+const JSON_PUNCTUATION: &[u8] = b"{}[]:,\" \t\r\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Text,
+    /// A JSON document or newline-delimited JSON records
+    Json,
+    Binary,
+}
+
+impl std::fmt::Display for ContentKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContentKind::Text => write!(f, "text"),
+            ContentKind::Json => write!(f, "json"),
+            ContentKind::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// Classifies `data` as JSON, other UTF-8 text or binary
+pub fn detect_content(data: &[u8]) -> ContentKind {
+    // A sample may end partway through a character
+    let utf8 = match std::str::from_utf8(data) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    let control = data.iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r'))
+        .count();
+    if !utf8 || control * 1000 > data.len() * MAX_CONTROL_BYTES_PER_MILLE {
+        return ContentKind::Binary;
+    }
+
+    match data.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') | Some(b'[') => ContentKind::Json,
+        _ => ContentKind::Text,
+    }
+}
+
+/// Tokens of `data` that repeat, as the content kind splits it, at most
+/// `limit` of them and none longer than `max_len`. The ones that would
+/// save the most come first; ties are ordered by their bytes so the
+/// result does not depend on hashing.
+pub fn token_candidates(kind: ContentKind, data: &[u8], max_len: usize, limit: usize) -> Vec<&[u8]> {
+    let mut counts = HashMap::new();
+    match kind {
+        ContentKind::Text => text_tokens(data, &mut counts),
+        ContentKind::Json => {
+            text_tokens(data, &mut counts);
+            json_tokens(data, &mut counts);
+        }
+        ContentKind::Binary => binary_fields(data, &mut counts),
+    }
+
+    let mut tokens: Vec<(&[u8], usize)> = counts.into_iter()
+        .filter(|&(token, count)| count >= 2 && token.len() >= 2 && token.len() <= max_len)
+        .collect();
+    tokens.sort_by(|a, b| {
+        let saved = |&(token, count): &(&[u8], usize)| count * (token.len() - 1);
+        saved(b).cmp(&saved(a)).then_with(|| a.0.cmp(b.0))
+    });
+    tokens.truncate(limit);
+    tokens.into_iter().map(|(token, _)| token).collect()
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+/// Words, words with the space before them, and whitespace-delimited runs
+fn text_tokens<'a>(data: &'a [u8], counts: &mut HashMap<&'a [u8], usize>) {
+    let mut i = 0;
+    while i < data.len() {
+        if is_word_byte(data[i]) {
+            let start = i;
+            while i < data.len() && is_word_byte(data[i]) {
+                i += 1;
+            }
+            *counts.entry(&data[start..i]).or_insert(0) += 1;
+            if start > 0 && data[start - 1] == b' ' {
+                *counts.entry(&data[start - 1..i]).or_insert(0) += 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+
+    // Runs that are a single word were counted above
+    for run in data.split(|b| b.is_ascii_whitespace()) {
+        if !run.is_empty() && !run.iter().all(|&b| is_word_byte(b)) {
+            *counts.entry(run).or_insert(0) += 1;
+        }
+    }
+}
+
+/// Keys with their quotes and colon, with and without the separator
+/// before them, quoted values, and runs of punctuation between them
+fn json_tokens<'a>(data: &'a [u8], counts: &mut HashMap<&'a [u8], usize>) {
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != b'"' {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        while i < data.len() && data[i] != b'"' {
+            i += if data[i] == b'\\' { 2 } else { 1 };
+        }
+        if i >= data.len() {
+            break;
+        }
+        i += 1;
+
+        let mut after = i;
+        while after < data.len() && data[after].is_ascii_whitespace() {
+            after += 1;
+        }
+        if after < data.len() && data[after] == b':' {
+            *counts.entry(&data[start..=after]).or_insert(0) += 1;
+            if start > 0 && matches!(data[start - 1], b',' | b'{') {
+                *counts.entry(&data[start - 1..=after]).or_insert(0) += 1;
+            }
+        } else {
+            *counts.entry(&data[start..i]).or_insert(0) += 1;
+        }
+    }
+
+    for run in data.split(|b| !JSON_PUNCTUATION.contains(b)) {
+        if run.len() >= 2 {
+            *counts.entry(run).or_insert(0) += 1;
+        }
+    }
+}
+
+/// Fields at offsets aligned to their width, for each width tried
+fn binary_fields<'a>(data: &'a [u8], counts: &mut HashMap<&'a [u8], usize>) {
+    for width in FIELD_WIDTHS {
+        for field in data.chunks_exact(width) {
+            *counts.entry(field).or_insert(0) += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::planner::plan_symbols_limited;
+
+    fn ndjson_logs(lines: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        for i in 0..lines {
+            out.extend_from_slice(format!(
+                "{{\"timestamp\":{},\"user\":{},\"latency_ms\":{},\"path\":\"/v1/items/{}\"}}\n",
+                1_700_000_000 + i * 7919 % 1000,
+                i * 31 % 997,
+                i * 13 % 250,
+                i % 17,
+            ).as_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn detects_text_json_and_binary() {
+        assert_eq!(detect_content(b"plain words, in a sentence.\n"), ContentKind::Text);
+        assert_eq!(detect_content("caf\u{e9} na\u{ef}ve".as_bytes()), ContentKind::Text);
+        assert_eq!(detect_content(b"  {\"a\": [1, 2]}"), ContentKind::Json);
+        assert_eq!(detect_content(b"[1, 2, 3]"), ContentKind::Json);
+        assert_eq!(detect_content(&ndjson_logs(10)), ContentKind::Json);
+        // Cut partway through a character, as samples are
+        assert_eq!(detect_content(&"caf\u{e9}".as_bytes()[..4]), ContentKind::Text);
+
+        assert_eq!(detect_content(&[0xff, 0xfe, 0x00, 0x01]), ContentKind::Binary);
+        let records: Vec<u8> = (0..100u32).flat_map(|i| i.to_le_bytes()).collect();
+        assert_eq!(detect_content(&records), ContentKind::Binary);
+    }
+
+    #[test]
+    fn ndjson_keys_become_symbols() {
+        let data = ndjson_logs(400);
+        let candidates = token_candidates(ContentKind::Json, &data, 64, 64);
+        for key in [&b"\"timestamp\":"[..], b"\"latency_ms\":", b",\"user\":"] {
+            assert!(candidates.contains(&key), "{}", String::from_utf8_lossy(key));
+        }
+
+        let symbols = plan_symbols_limited(&data, 64, 64, 1);
+        for key in [&b"\"timestamp\":"[..], b"\"user\":", b"\"latency_ms\":", b"\"path\":"] {
+            assert!(
+                symbols.iter().any(|s| s.bytes.windows(key.len()).any(|w| w == key)),
+                "no symbol covers {}",
+                String::from_utf8_lossy(key),
+            );
+        }
+    }
+
+    #[test]
+    fn binary_fields_are_aligned() {
+        // 16-byte records: a counter, a fixed tag and a fixed trailer
+        let mut data = Vec::new();
+        for i in 0..200u32 {
+            data.extend_from_slice(&i.to_le_bytes());
+            data.extend_from_slice(&0xdead_beefu32.to_le_bytes());
+            data.extend_from_slice(&[0x42; 8]);
+        }
+        let candidates = token_candidates(ContentKind::Binary, &data, 64, 64);
+        assert!(candidates.contains(&&0xdead_beefu32.to_le_bytes()[..]));
+        assert!(candidates.contains(&&[0x42; 8][..]));
+        for candidate in &candidates {
+            let width = candidate.len();
+            assert!(FIELD_WIDTHS.contains(&width));
+            assert!(data.chunks_exact(width).any(|field| field == *candidate));
+        }
+    }
+}
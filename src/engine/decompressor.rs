@@ -1,147 +1,439 @@
-use crate::storage::dictionary::Dictionary;
-use crate::engine::huffman::{HuffmanTable, HuffmanNode};
-use std::collections::HashMap;
-
-fn rebuild_tree(encode_table: &HashMap<u32, Vec<bool>>) -> HuffmanNode {
-    let mut root = HuffmanNode {
-        freq: 0,
-        token: None,
-        left: None,
-        right: None,
+use crate::storage::{dictionary::Dictionary, metadata::ObjectMetadata};
+use crate::engine::entropy::{EntropyCoder, HuffmanCoder};
+use crate::engine::huffman::LegacyHuffmanTable;
+use crate::engine::error::DecompressError;
+use crate::engine::container::{self, ContainerHeader, split_index, CODEC_V1, CODEC_V2, MAGIC, TRAILER_LEN};
+use crate::engine::block::{decode_blocks, decode_block_at, read_index, BLOCK_SIZE};
+use crate::engine::hash::sha256;
+use crate::engine::codec::codec_by_id;
+use crate::engine::lz77::{MATCH_TOKEN, MIN_MATCH};
+use crate::utils::varint::decode_varint;
+
+/// Decompresses a blob written by `compress`, validating its container
+/// header against `dict` and the decoded content. Headerless blobs from
+/// codec version 1 are decoded with the legacy table reader and without
+/// those checks.
+pub fn decompress(
+    data: &[u8],
+    dict: &Dictionary,
+) -> Result<Vec<u8>, DecompressError> {
+    let Some((header, payload)) = ContainerHeader::read(data)? else {
+        return decode_legacy_payload(data, dict);
     };
     
-    for (&token, code) in encode_table {
-        let mut current = &mut root;
-        
-        for &bit in code {
-            if bit {
-                if current.right.is_none() {
-                    current.right = Some(Box::new(HuffmanNode {
-                        freq: 0,
-                        token: None,
-                        left: None,
-                        right: None,
-                    }));
-                }
-                current = current.right.as_mut().unwrap();
-            } else {
-                if current.left.is_none() {
-                    current.left = Some(Box::new(HuffmanNode {
-                        freq: 0,
-                        token: None,
-                        left: None,
-                        right: None,
-                    }));
-                }
-                current = current.left.as_mut().unwrap();
-            }
-        }
-        
-        current.token = Some(token);
+    check_dictionary(&header.dict_id, dict)?;
+    
+    let out = if header.codec_version == CODEC_V2 {
+        decode_payload(payload, dict)?
+    } else {
+        decode_blocks(payload, dict, header.codec_version)?
+    };
+    
+    if out.len() as u64 != header.original_size {
+        return Err(DecompressError::LengthMismatch {
+            expected: header.original_size,
+            actual: out.len() as u64,
+        });
+    }
+    if sha256(&out) != header.content_hash {
+        return Err(DecompressError::ChecksumMismatch);
     }
     
-    root
+    Ok(out)
 }
 
-pub fn decompress(
+/// Reconstructs `len` bytes of the original content starting at `offset`.
+/// Indexed containers decode only the blocks covering the range; older
+/// formats are decoded whole. The content hash covers the whole object, so
+/// a partial read is checked per block but not against the hash.
+pub fn decompress_range(
     data: &[u8],
     dict: &Dictionary,
-) -> Vec<u8> {
-    if data.len() < 4 {
-        return Vec::new();
-    }
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>, DecompressError> {
+    let header = match ContainerHeader::read(data)? {
+        Some((header, _)) if container::has_index(header.codec_version) => header,
+        _ => {
+            let out = decompress(data, dict)?;
+            let range = checked_range(offset, len, out.len() as u64)?;
+            return Ok(out[range.start as usize..range.end as usize].to_vec());
+        }
+    };
     
-    let mut offset = 0;
+    check_dictionary(&header.dict_id, dict)?;
+    let range = checked_range(offset, len, header.original_size)?;
+    if range.is_empty() {
+        return Ok(Vec::new());
+    }
     
-    // Read Huffman table size
-    let table_size = u32::from_be_bytes([
-        data[offset], data[offset + 1], data[offset + 2], data[offset + 3]
-    ]) as usize;
-    offset += 4;
+    let before_trailer = &data[..data.len() - TRAILER_LEN];
+    let index = read_index(split_index(before_trailer)?.1);
     
-    // Reconstruct Huffman table
-    let mut encode_table = HashMap::new();
+    // Last block starting at or before the range, then every block up to its end
+    let first = index.partition_point(|entry| entry.raw_offset <= range.start).saturating_sub(1);
+    let Some(skip_from) = index.get(first).map(|entry| entry.raw_offset).filter(|&o| o <= range.start) else {
+        return Err(DecompressError::TruncatedBlock);
+    };
+    let covering = &index[first..index.partition_point(|entry| entry.raw_offset < range.end)];
+    // Blocks hold at most BLOCK_SIZE bytes, whatever the range asked for
+    let mut out = Vec::with_capacity(covering.len() * BLOCK_SIZE);
+    let mut block_start = skip_from;
     
-    for _ in 0..table_size {
-        if offset + 6 > data.len() {
-            return Vec::new();
-        }
-        
-        let token = u32::from_be_bytes([
-            data[offset], data[offset + 1], data[offset + 2], data[offset + 3]
-        ]);
-        offset += 4;
-        
-        let code_len = data[offset] as usize;
-        offset += 1;
-        
-        let code_bytes_len = data[offset] as usize;
-        offset += 1;
-        
-        if offset + code_bytes_len > data.len() {
-            return Vec::new();
+    for entry in covering {
+        let record = usize::try_from(entry.offset).ok()
+            .and_then(|start| before_trailer.get(start..))
+            .ok_or(DecompressError::TruncatedBlock)?;
+        if entry.raw_offset != block_start {
+            return Err(DecompressError::TruncatedBlock);
         }
-        
-        let code_bytes = &data[offset..offset + code_bytes_len];
-        offset += code_bytes_len;
-        
-        // Reconstruct code bits
-        let mut code = Vec::new();
-        let mut bits_read = 0;
-        
-        for &byte in code_bytes {
-            for bit_pos in 0..8 {
-                if bits_read >= code_len {
-                    break;
+        let (block, _) = decode_block_at(record, dict, header.codec_version)?;
+        block_start += block.len() as u64;
+        out.extend(block);
+    }
+    
+    let skip = (range.start - skip_from) as usize;
+    if out.len() < skip + len as usize {
+        return Err(DecompressError::LengthMismatch {
+            expected: (skip as u64) + len,
+            actual: out.len() as u64,
+        });
+    }
+    out.drain(..skip);
+    out.truncate(len as usize);
+    Ok(out)
+}
+
+pub(crate) fn checked_range(offset: u64, len: u64, size: u64) -> Result<std::ops::Range<u64>, DecompressError> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(offset..end),
+        _ => Err(DecompressError::OutOfRange { offset, len, size }),
+    }
+}
+
+/// Dictionary id recorded for content compressed against `dict`: the
+/// frozen id, or the generation of a dictionary that is still growing
+pub fn dictionary_id(dict: &Dictionary) -> String {
+    if dict.frozen {
+        dict.id.clone()
+    } else {
+        dict.generation_id()
+    }
+}
+
+/// Id carried by objects written against the mutable dictionary before
+/// generations were recorded
+pub const LEGACY_MUTABLE_ID: &str = "mutable";
+
+pub(crate) fn check_dictionary(expected: &str, dict: &Dictionary) -> Result<(), DecompressError> {
+    let found = dictionary_id(dict);
+    if expected != LEGACY_MUTABLE_ID && expected != found {
+        return Err(DecompressError::DictionaryMismatch {
+            expected: expected.to_string(),
+            found,
+        });
+    }
+    Ok(())
+}
+
+/// Version 2 payload: a canonical Huffman table and stream
+pub(crate) fn decode_payload(
+    data: &[u8],
+    dict: &Dictionary,
+) -> Result<Vec<u8>, DecompressError> {
+    let tokens = HuffmanCoder.decode(data, usize::MAX)?;
+    detokenize(&tokens, &[], dict, usize::MAX)
+}
+
+/// Headerless version 1 blob: a legacy Huffman table, stream length (u32)
+/// and stream
+pub(crate) fn decode_legacy_payload(
+    data: &[u8],
+    dict: &Dictionary,
+) -> Result<Vec<u8>, DecompressError> {
+    if data.len() < 4 {
+        return Err(DecompressError::TruncatedHeader);
+    }
+    let (huffman_table, offset) = LegacyHuffmanTable::read_table(data)
+        .ok_or(DecompressError::BadCodeTable)?;
+
+    let compressed_size = data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize)
+        .ok_or(DecompressError::TruncatedHeader)?;
+    let compressed_data = data.get(offset + 4..offset + 4 + compressed_size)
+        .ok_or(DecompressError::TruncatedHeader)?;
+
+    let tokens = huffman_table.decode(compressed_data)?;
+    detokenize(&tokens, &[], dict, usize::MAX)
+}
+
+/// Expands tokens into at most `max_len` bytes. Each `MATCH_TOKEN` takes
+/// its length and distance from `match_stream` and copies earlier output.
+pub(crate) fn detokenize(
+    tokens: &[u32],
+    mut match_stream: &[u8],
+    dict: &Dictionary,
+    max_len: usize,
+) -> Result<Vec<u8>, DecompressError> {
+    // Convert tokens back to bytes - optimized for large files
+    let mut out = Vec::with_capacity(tokens.len().saturating_mul(4).min(max_len)); // Pre-allocate
+    for &token in tokens {
+        if token <= 255 {
+            out.push(token as u8);
+        } else if token == MATCH_TOKEN {
+            let (len, used) = decode_varint(match_stream).ok_or(DecompressError::BadBackReference)?;
+            match_stream = &match_stream[used..];
+            let (distance, used) = decode_varint(match_stream).ok_or(DecompressError::BadBackReference)?;
+            match_stream = &match_stream[used..];
+
+            let len = usize::try_from(len).ok()
+                .and_then(|len| len.checked_add(MIN_MATCH))
+                .filter(|&len| len <= max_len.saturating_sub(out.len()))
+                .ok_or(DecompressError::BadBackReference)?;
+            let distance = usize::try_from(distance).ok()
+                .filter(|&distance| distance > 0 && distance <= out.len())
+                .ok_or(DecompressError::BadBackReference)?;
+
+            let start = out.len() - distance;
+            if distance >= len {
+                out.extend_from_within(start..start + len);
+            } else {
+                // The copy overlaps its own output, repeating the last `distance` bytes
+                for i in start..start + len {
+                    out.push(out[i]);
                 }
-                let bit = (byte >> (7 - bit_pos)) & 1 == 1;
-                code.push(bit);
-                bits_read += 1;
-            }
-            if bits_read >= code_len {
-                break;
             }
+        } else if let Some(bytes) = dict.decode.get(&token) {
+            out.extend_from_slice(bytes);
+        } else {
+            return Err(DecompressError::UnknownToken(token));
         }
-        
-        encode_table.insert(token, code);
     }
-    
-    // Read compressed data size
-    if offset + 4 > data.len() {
-        return Vec::new();
+    if !match_stream.is_empty() {
+        return Err(DecompressError::BadBackReference);
     }
     
-    let compressed_size = u32::from_be_bytes([
-        data[offset], data[offset + 1], data[offset + 2], data[offset + 3]
-    ]) as usize;
-    offset += 4;
-    
-    if offset + compressed_size > data.len() {
-        return Vec::new();
+    Ok(out)
+}
+
+/// Decompresses a stored object and checks the result against what its
+/// metadata recorded at upload time.
+pub fn decompress_object(
+    data: &[u8],
+    meta: &ObjectMetadata,
+    dict: &Dictionary,
+) -> Result<Vec<u8>, DecompressError> {
+    if !container::is_supported(meta.codec_version) {
+        return Err(DecompressError::UnsupportedVersion(meta.codec_version));
     }
+    check_dictionary(&meta.dict_id, dict)?;
     
-    let compressed_data = &data[offset..offset + compressed_size];
-    
-    // Create Huffman table and decode
-    let mut huffman_table = HuffmanTable {
-        encode_table: encode_table.clone(),
-        decode_tree: None,
+    // The recorded version decides the table reader: objects from before
+    // the container are headerless, everything later must be framed
+    let out = if meta.codec_version == CODEC_V1 {
+        decode_legacy_payload(data, dict)?
+    } else if !data.starts_with(&MAGIC) {
+        return Err(DecompressError::MissingHeader(meta.codec_version));
+    } else {
+        let codec = codec_by_id(&meta.codec)
+            .ok_or_else(|| DecompressError::UnknownCodec(meta.codec.clone()))?;
+        codec.decompress(data, dict)?
     };
     
-    // Rebuild decode tree from encode table
-    huffman_table.decode_tree = Some(Box::new(rebuild_tree(&encode_table)));
+    if out.len() as u64 != meta.original_size {
+        return Err(DecompressError::LengthMismatch {
+            expected: meta.original_size,
+            actual: out.len() as u64,
+        });
+    }
     
-    let tokens = huffman_table.decode(compressed_data);
+    Ok(out)
+}
+
+/// `decompress_range` for a stored object, checked against its metadata
+/// the way `decompress_object` checks whole objects. Chunked objects are
+/// read through `ChunkStore::assemble_range`.
+pub fn decompress_object_range(
+    data: &[u8],
+    meta: &ObjectMetadata,
+    dict: &Dictionary,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>, DecompressError> {
+    if !container::is_supported(meta.codec_version) {
+        return Err(DecompressError::UnsupportedVersion(meta.codec_version));
+    }
+    check_dictionary(&meta.dict_id, dict)?;
+    let range = checked_range(offset, len, meta.original_size)?;
     
-    // Convert tokens back to bytes - optimized for large files
-    let mut out = Vec::with_capacity(tokens.len() * 4); // Pre-allocate
-    for &token in &tokens {
-        if let Some(bytes) = dict.decode.get(&token) {
-            out.extend_from_slice(bytes);
-        } else if token <= 255 {
-            out.push(token as u8);
+    if meta.codec_version == CODEC_V1 {
+        let out = decode_legacy_payload(data, dict)?;
+        return out.get(range.start as usize..range.end as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or(DecompressError::LengthMismatch { expected: meta.original_size, actual: out.len() as u64 });
+    }
+    if !data.starts_with(&MAGIC) {
+        return Err(DecompressError::MissingHeader(meta.codec_version));
+    }
+    // Every codec but chunking writes a container `decompress_range` reads
+    codec_by_id(&meta.codec).ok_or_else(|| DecompressError::UnknownCodec(meta.codec.clone()))?;
+    decompress_range(data, dict, offset, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `tokens` in the headerless layout the baseline compressor
+    /// produced, with each token's code given as a string of bits
+    fn legacy_blob(codes: &[(u32, &str)], tokens: &[u32]) -> Vec<u8> {
+        let pack = |bits: &str| -> (Vec<u8>, u8) {
+            let mut bytes = vec![0u8; bits.len().div_ceil(8)];
+            for (i, bit) in bits.bytes().enumerate() {
+                if bit == b'1' {
+                    bytes[i / 8] |= 1 << (7 - i % 8);
+                }
+            }
+            (bytes, (bits.len() % 8) as u8)
+        };
+
+        let mut out = (codes.len() as u32).to_be_bytes().to_vec();
+        for &(token, code) in codes {
+            let (code_bytes, _) = pack(code);
+            out.extend_from_slice(&token.to_be_bytes());
+            out.push(code.len() as u8);
+            out.push(code_bytes.len() as u8);
+            out.extend(code_bytes);
         }
+
+        let stream: String = tokens.iter()
+            .map(|token| codes.iter().find(|(t, _)| t == token).unwrap().1)
+            .collect();
+        let (bytes, tail) = pack(&stream);
+        out.extend_from_slice(&(bytes.len() as u32 + 1).to_be_bytes());
+        out.push(tail);
+        out.extend(bytes);
+        out
+    }
+
+    fn dict_with(symbols: &[(u32, &[u8])]) -> Dictionary {
+        let mut dict = Dictionary::new("test");
+        for &(token, bytes) in symbols {
+            dict.insert_symbol(bytes.to_vec(), token);
+        }
+        dict
+    }
+
+    #[test]
+    fn decodes_baseline_blob() {
+        // 'a' = 0, 'b' = 10, symbol 300 = 11; "a b 300 a" packs into six bits
+        let blob = [
+            0, 0, 0, 3,
+            0, 0, 0, 97, 1, 1, 0b0000_0000,
+            0, 0, 0, 98, 2, 1, 0b1000_0000,
+            0, 0, 1, 44, 2, 1, 0b1100_0000,
+            0, 0, 0, 2,
+            6, 0b0101_1000,
+        ];
+        let dict = dict_with(&[(300, b"xyz")]);
+        assert_eq!(decompress(&blob, &dict).unwrap(), b"abxyza");
+    }
+
+    #[test]
+    fn decodes_baseline_blob_with_long_codes() {
+        // A degenerate tree whose deepest codes need two code bytes
+        let codes: Vec<(u32, String)> = (0..11u32)
+            .map(|i| (b'a' as u32 + i, if i < 10 { format!("{}0", "1".repeat(i as usize)) } else { "1".repeat(10) }))
+            .collect();
+        let codes: Vec<(u32, &str)> = codes.iter().map(|(t, c)| (*t, c.as_str())).collect();
+        let input = b"kjihgfedcbaabcdefghijk";
+        let tokens: Vec<u32> = input.iter().map(|&b| b as u32).collect();
+
+        let blob = legacy_blob(&codes, &tokens);
+        assert_eq!(decompress(&blob, &Dictionary::new("test")).unwrap(), input);
+    }
+
+    #[test]
+    fn baseline_blob_with_single_token() {
+        let blob = legacy_blob(&[(b'z' as u32, "0")], &[b'z' as u32; 9]);
+        assert_eq!(decompress(&blob, &Dictionary::new("test")).unwrap(), b"zzzzzzzzz");
+    }
+
+    #[test]
+    fn rejects_malformed_baseline_blob() {
+        let dict = Dictionary::new("test");
+        let blob = legacy_blob(&[(b'a' as u32, "0"), (b'b' as u32, "1")], b"abba".map(u32::from).as_slice());
+
+        // Cut inside the table, the stream length and the stream
+        for len in [3, 9, 16, blob.len() - 1] {
+            assert!(decompress(&blob[..len], &dict).is_err(), "accepted {} bytes", len);
+        }
+
+        // One code a prefix of another
+        let blob = legacy_blob(&[(b'a' as u32, "0"), (b'b' as u32, "01")], &[b'b' as u32]);
+        assert!(matches!(decompress(&blob, &dict), Err(DecompressError::BadCodeTable)));
+
+        // A token the dictionary does not know
+        let blob = legacy_blob(&[(b'a' as u32, "0"), (999, "1")], &[999]);
+        assert!(matches!(decompress(&blob, &dict), Err(DecompressError::UnknownToken(999))));
+    }
+
+    #[test]
+    fn metadata_version_selects_table_reader() {
+        let blob = legacy_blob(&[(b'a' as u32, "0"), (b'b' as u32, "1")], b"abba".map(u32::from).as_slice());
+        let dict = Dictionary::new("test");
+        let mut meta = ObjectMetadata::new(
+            "key".to_string(), [0; 32], [0; 32], LEGACY_MUTABLE_ID.to_string(), 4, blob.len() as u64, None,
+        );
+
+        meta.codec_version = CODEC_V1;
+        assert_eq!(decompress_object(&blob, &meta, &dict).unwrap(), b"abba");
+
+        meta.codec_version = container::CODEC_VERSION;
+        assert!(matches!(
+            decompress_object(&blob, &meta, &dict),
+            Err(DecompressError::MissingHeader(_))
+        ));
+    }
+
+    #[test]
+    fn object_ranges_decode_covering_blocks() {
+        use crate::engine::codec::{Codec, StoredCodec, SymbolCodec, CHUNKED_CODEC};
+        use crate::storage::symbols::SymbolStore;
+
+        let dir = std::env::temp_dir().join(format!("symvea-decompressor-ranges-{}", std::process::id()));
+        let symbol_store = SymbolStore::new(dir.to_string_lossy().to_string());
+        let input: Vec<u8> = (0..3 * BLOCK_SIZE as u32).map(|i| (i % 251) as u8 ^ (i / 4096) as u8).collect();
+        let dict = Dictionary::new("test");
+        let config = crate::engine::config::EngineConfig::default();
+
+        for codec in [&SymbolCodec as &dyn Codec, &StoredCodec] {
+            let (blob, ..) = codec.encode(&input, &dict, &symbol_store, "key", &config, Vec::new());
+            let mut meta = ObjectMetadata::new(
+                "key".to_string(), [0; 32], [0; 32], dictionary_id(&dict), input.len() as u64, blob.len() as u64, None,
+            );
+            meta.codec = codec.id().to_string();
+
+            let size = input.len() as u64;
+            for (offset, len) in [(0, 10), (BLOCK_SIZE as u64 - 5, 10), (100, 2 * BLOCK_SIZE as u64), (size - 1, 1), (size, 0)] {
+                let range = decompress_object_range(&blob, &meta, &dict, offset, len).unwrap();
+                assert_eq!(range, &input[offset as usize..(offset + len) as usize]);
+            }
+            assert!(matches!(
+                decompress_object_range(&blob, &meta, &dict, size - 1, 2),
+                Err(DecompressError::OutOfRange { .. })
+            ));
+            assert!(matches!(
+                decompress_object_range(&blob, &meta, &dict, 0, u64::MAX),
+                Err(DecompressError::OutOfRange { .. })
+            ));
+
+            meta.codec = CHUNKED_CODEC.to_string();
+            assert!(matches!(
+                decompress_object_range(&blob, &meta, &dict, 0, 1),
+                Err(DecompressError::UnknownCodec(_))
+            ));
+        }
+        std::fs::remove_dir_all(&dir).ok();
     }
-    
-    out
 }
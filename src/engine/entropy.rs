@@ -0,0 +1,142 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+use crate::engine::{
+    error::DecompressError,
+    huffman::HuffmanTable,
+    rans::RansTable,
+};
+
+#[derive(Debug)]
+pub enum EntropyError {
+    Truncated,
+    InvalidCode,
+    UnknownToken(u32),
+}
+
+impl fmt::Display for EntropyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntropyError::Truncated =>
+                write!(f, "truncated entropy-coded stream"),
+            EntropyError::InvalidCode =>
+                write!(f, "invalid code in entropy-coded stream"),
+            EntropyError::UnknownToken(t) =>
+                write!(f, "token {} has no code", t),
+        }
+    }
+}
+
+impl std::error::Error for EntropyError {}
+
+/// Turns a token sequence into bytes and back. Each coder builds its model
+/// from the tokens it is given and stores it in front of the coded data.
+pub trait EntropyCoder {
+    /// Appends the model and the coded `tokens` to `out`. Returns false,
+    /// leaving `out` untouched, if this coder cannot represent them.
+    fn encode(&self, tokens: &[u32], out: &mut Vec<u8>) -> bool;
+
+    /// Decodes data written by `encode`, rejecting streams that claim more
+    /// than `max_tokens` tokens.
+    fn decode(&self, data: &[u8], max_tokens: usize) -> Result<Vec<u32>, DecompressError>;
+}
+
+/// Entropy coder choice, as configured and as tagged on each block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntropyCoderKind {
+    #[default]
+    Huffman,
+    Rans,
+}
+
+impl EntropyCoderKind {
+    pub fn tag(self) -> u8 {
+        match self {
+            EntropyCoderKind::Huffman => 0,
+            EntropyCoderKind::Rans => 1,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(EntropyCoderKind::Huffman),
+            1 => Some(EntropyCoderKind::Rans),
+            _ => None,
+        }
+    }
+
+    pub fn coder(self) -> &'static dyn EntropyCoder {
+        match self {
+            EntropyCoderKind::Huffman => &HuffmanCoder,
+            EntropyCoderKind::Rans => &RansCoder,
+        }
+    }
+}
+
+/// Canonical Huffman code: table, stream length (u32), stream. This is also
+/// the whole payload of a version 2 blob.
+pub struct HuffmanCoder;
+
+impl EntropyCoder for HuffmanCoder {
+    fn encode(&self, tokens: &[u32], out: &mut Vec<u8>) -> bool {
+        let huffman_table = HuffmanTable::build(tokens);
+        let huffman_data = huffman_table.encode(tokens)
+            .expect("huffman table covers every token it was built from");
+
+        huffman_table.write_table(out);
+        out.extend_from_slice(&(huffman_data.len() as u32).to_be_bytes());
+        out.extend(huffman_data);
+        true
+    }
+
+    fn decode(&self, data: &[u8], _max_tokens: usize) -> Result<Vec<u32>, DecompressError> {
+        if data.len() < 4 {
+            return Err(DecompressError::TruncatedHeader);
+        }
+
+        // Read canonical Huffman table (code lengths only)
+        let (huffman_table, offset) = HuffmanTable::read_table(data)
+            .ok_or(DecompressError::BadCodeTable)?;
+
+        // Read compressed data size
+        let compressed_size = data.get(offset..offset + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize)
+            .ok_or(DecompressError::TruncatedHeader)?;
+        let compressed_data = data.get(offset + 4..offset + 4 + compressed_size)
+            .ok_or(DecompressError::TruncatedHeader)?;
+
+        Ok(huffman_table.decode(compressed_data)?)
+    }
+}
+
+/// Static rANS: normalized frequency table, token count (u32), stream.
+/// Spends fractional bits per token, which pays off on skewed inputs.
+pub struct RansCoder;
+
+impl EntropyCoder for RansCoder {
+    fn encode(&self, tokens: &[u32], out: &mut Vec<u8>) -> bool {
+        let Some(table) = RansTable::build(tokens) else {
+            return false;
+        };
+
+        table.write_table(out);
+        out.extend_from_slice(&(tokens.len() as u32).to_be_bytes());
+        table.encode(tokens, out);
+        true
+    }
+
+    fn decode(&self, data: &[u8], max_tokens: usize) -> Result<Vec<u32>, DecompressError> {
+        let (table, offset) = RansTable::read_table(data)
+            .ok_or(DecompressError::BadCodeTable)?;
+
+        let count = data.get(offset..offset + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize)
+            .ok_or(DecompressError::TruncatedHeader)?;
+        if count > max_tokens {
+            return Err(DecompressError::CorruptStream(EntropyError::InvalidCode));
+        }
+
+        Ok(table.decode(&data[offset + 4..], count)?)
+    }
+}
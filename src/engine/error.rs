@@ -0,0 +1,104 @@
+use std::fmt;
+use crate::engine::entropy::EntropyError;
+
+#[derive(Debug)]
+pub enum DecompressError {
+    TruncatedHeader,
+    BadCodeTable,
+    CorruptStream(EntropyError),
+    UnknownToken(u32),
+    DictionaryMismatch { expected: String, found: String },
+    LengthMismatch { expected: u64, actual: u64 },
+    UnsupportedVersion(u16),
+    MissingHeader(u16),
+    ChecksumMismatch,
+    TruncatedBlock,
+    OutOfRange { offset: u64, len: u64, size: u64 },
+    UnknownCoder(u8),
+    UnknownCodec(String),
+    BadBackReference,
+    MissingChunk(String),
+    Io(std::io::Error),
+}
+
+impl DecompressError {
+    /// Stable identifier for machine-readable output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DecompressError::TruncatedHeader => "truncated_header",
+            DecompressError::BadCodeTable => "bad_code_table",
+            DecompressError::CorruptStream(_) => "corrupt_stream",
+            DecompressError::UnknownToken(_) => "unknown_token",
+            DecompressError::DictionaryMismatch { .. } => "dictionary_mismatch",
+            DecompressError::LengthMismatch { .. } => "length_mismatch",
+            DecompressError::UnsupportedVersion(_) => "unsupported_version",
+            DecompressError::MissingHeader(_) => "missing_header",
+            DecompressError::ChecksumMismatch => "checksum_mismatch",
+            DecompressError::TruncatedBlock => "truncated_block",
+            DecompressError::OutOfRange { .. } => "out_of_range",
+            DecompressError::UnknownCoder(_) => "unknown_coder",
+            DecompressError::UnknownCodec(_) => "unknown_codec",
+            DecompressError::BadBackReference => "bad_back_reference",
+            DecompressError::MissingChunk(_) => "missing_chunk",
+            DecompressError::Io(_) => "io",
+        }
+    }
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompressError::TruncatedHeader =>
+                write!(f, "truncated compressed header"),
+            DecompressError::BadCodeTable =>
+                write!(f, "invalid huffman code table"),
+            DecompressError::CorruptStream(e) =>
+                write!(f, "corrupt token stream: {}", e),
+            DecompressError::UnknownToken(t) =>
+                write!(f, "token {} is not in the dictionary", t),
+            DecompressError::DictionaryMismatch { expected, found } =>
+                write!(f, "dictionary mismatch: object needs {}, have {}", expected, found),
+            DecompressError::LengthMismatch { expected, actual } =>
+                write!(f, "length mismatch: expected {} bytes, decoded {}", expected, actual),
+            DecompressError::UnsupportedVersion(v) =>
+                write!(f, "unsupported codec version {}", v),
+            DecompressError::MissingHeader(v) =>
+                write!(f, "codec version {} object has no container header", v),
+            DecompressError::ChecksumMismatch =>
+                write!(f, "decoded content does not match its recorded hash"),
+            DecompressError::TruncatedBlock =>
+                write!(f, "truncated or malformed block"),
+            DecompressError::OutOfRange { offset, len, size } =>
+                write!(f, "range {}+{} is outside the {} byte object", offset, len, size),
+            DecompressError::UnknownCoder(tag) =>
+                write!(f, "unknown entropy coder {}", tag),
+            DecompressError::UnknownCodec(id) =>
+                write!(f, "unknown codec '{}'", id),
+            DecompressError::BadBackReference =>
+                write!(f, "back-reference outside the decoded block"),
+            DecompressError::MissingChunk(hash) =>
+                write!(f, "chunk {} is not in the chunk store", hash),
+            DecompressError::Io(e) =>
+                write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecompressError {}
+
+impl From<EntropyError> for DecompressError {
+    fn from(e: EntropyError) -> Self {
+        DecompressError::CorruptStream(e)
+    }
+}
+
+impl From<std::io::Error> for DecompressError {
+    fn from(e: std::io::Error) -> Self {
+        // Running out of input mid-container means the blob was cut short
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            DecompressError::TruncatedBlock
+        } else {
+            DecompressError::Io(e)
+        }
+    }
+}
@@ -0,0 +1,120 @@
+/// Leading bytes of formats whose contents are already entropy coded
+const COMPRESSED_MAGIC: &[(&[u8], &str)] = &[
+    (&[0x1f, 0x8b], "gzip"),
+    (b"PK\x03\x04", "zip"),
+    (b"\x89PNG\r\n\x1a\n", "png"),
+    (&[0xff, 0xd8, 0xff], "jpeg"),
+    (&[0x28, 0xb5, 0x2f, 0xfd], "zstd"),
+    (b"\xfd7zXZ\x00", "xz"),
+    (b"7z\xbc\xaf\x27\x1c", "7z"),
+];
+
+/// Shortest sample whose entropy says anything: smaller ones read as high
+/// entropy just from having few repeats
+const MIN_ENTROPY_SAMPLE: usize = 4096;
+
+/// Bytes looked at when estimating entropy
+const ENTROPY_SAMPLE: usize = 64 * 1024;
+
+/// Order-0 entropy, in bits per byte, above which symbols and Huffman
+/// coding cannot win back the container and table overhead
+const MAX_USEFUL_ENTROPY: f64 = 7.8;
+
+/// Names the format of already-compressed input, if it has a known magic
+pub fn sniff_compressed(data: &[u8]) -> Option<&'static str> {
+    COMPRESSED_MAGIC.iter()
+        .find(|(magic, _)| data.starts_with(magic))
+        .map(|&(_, name)| name)
+        .or_else(|| is_bzip2(data).then_some("bzip2"))
+}
+
+/// "BZh" alone starts plenty of text, so bzip2 also needs the block size
+/// digit and the magic of the first block
+fn is_bzip2(data: &[u8]) -> bool {
+    matches!(data, [b'B', b'Z', b'h', b'1'..=b'9', 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, ..])
+}
+
+/// Shannon entropy of the byte histogram, in bits per byte
+pub fn byte_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0u64; 256];
+    for &b in data {
+        counts[b as usize] += 1;
+    }
+
+    let total = data.len() as f64;
+    counts.iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Why `data` should be stored rather than compressed, judged from its
+/// magic number or the entropy of a leading sample
+pub fn incompressible_reason(data: &[u8]) -> Option<String> {
+    if let Some(format) = sniff_compressed(data) {
+        return Some(format!("{} data", format));
+    }
+
+    let sample = &data[..data.len().min(ENTROPY_SAMPLE)];
+    if sample.len() < MIN_ENTROPY_SAMPLE {
+        return None;
+    }
+
+    let entropy = byte_entropy(sample);
+    if entropy > MAX_USEFUL_ENTROPY {
+        return Some(format!("entropy {:.2} bits/byte", entropy));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    #[test]
+    fn sniffs_full_headers_only() {
+        assert_eq!(sniff_compressed(&[0x1f, 0x8b, 8, 0]), Some("gzip"));
+        assert_eq!(sniff_compressed(b"PK\x03\x04rest"), Some("zip"));
+        assert_eq!(sniff_compressed(b"BZh91AY&SY\x00\x01"), Some("bzip2"));
+        assert_eq!(sniff_compressed(b"BZh91AY&S"), None);
+        assert_eq!(sniff_compressed(b"BZh0AY&SY"), None);
+        assert_eq!(sniff_compressed(b"BZh is how the notes begin"), None);
+        assert_eq!(sniff_compressed(b""), None);
+    }
+
+    #[test]
+    fn entropy_of_known_histograms() {
+        assert_eq!(byte_entropy(b""), 0.0);
+        assert_eq!(byte_entropy(&[7; 100]), 0.0);
+        assert_eq!(byte_entropy(b"abababab"), 1.0);
+        let every_byte: Vec<u8> = (0..=255).collect();
+        assert_eq!(byte_entropy(&every_byte), 8.0);
+    }
+
+    #[test]
+    fn entropy_needs_a_full_sample() {
+        let data = random(MIN_ENTROPY_SAMPLE);
+        assert_eq!(incompressible_reason(&data[..MIN_ENTROPY_SAMPLE - 1]), None);
+        assert!(incompressible_reason(&data).unwrap().starts_with("entropy"));
+
+        let text = b"BZh, the note said. ".repeat(400);
+        assert_eq!(incompressible_reason(&text), None);
+    }
+}
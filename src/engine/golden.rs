@@ -0,0 +1,340 @@
+//! Golden vectors: fixed inputs and the exact blobs they compress to.
+//! Compression is a pure function of the input, the dictionary and the
+//! level, so equal uploads store equal blobs and any reported blob can be
+//! reproduced byte for byte. A hash that stops matching means the stored
+//! format or the planner changed, which has to be deliberate.
+
+use std::path::PathBuf;
+
+use crate::dedup::DedupWriter;
+use crate::engine::{
+    codec::{choose_codec, encode_or_store},
+    config::{CompressionLevel, EngineConfig},
+    hash::sha256,
+};
+use crate::storage::{
+    chunks::ChunkStore, dictionary::Dictionary, registry::DictionaryRegistry, symbols::SymbolStore,
+};
+
+struct GoldenVector {
+    input: &'static str,
+    level: CompressionLevel,
+    /// SHA-256 of the compressed blob
+    expected: &'static str,
+}
+
+/// Each input at each level. The blobs are coded against an empty
+/// mutable dictionary, so the dictionary is whatever the input teaches it.
+const GOLDEN_VECTORS: &[GoldenVector] = &[
+    GoldenVector {
+        input: "short",
+        level: CompressionLevel::Fast,
+        expected: "1c112f5941f2b7bcc1114f1c9119fc32d14fb5948c2ae887f91ab064ee443bf3",
+    },
+    GoldenVector {
+        input: "short",
+        level: CompressionLevel::Default,
+        expected: "1c112f5941f2b7bcc1114f1c9119fc32d14fb5948c2ae887f91ab064ee443bf3",
+    },
+    GoldenVector {
+        input: "short",
+        level: CompressionLevel::Max,
+        expected: "1c112f5941f2b7bcc1114f1c9119fc32d14fb5948c2ae887f91ab064ee443bf3",
+    },
+    GoldenVector {
+        input: "text",
+        level: CompressionLevel::Fast,
+        expected: "b147c44013ea83888703039a906b3076b034d214a079666a32067e035735e5de",
+    },
+    GoldenVector {
+        input: "text",
+        level: CompressionLevel::Default,
+        expected: "b147c44013ea83888703039a906b3076b034d214a079666a32067e035735e5de",
+    },
+    GoldenVector {
+        input: "text",
+        level: CompressionLevel::Max,
+        expected: "783790c87b747434a486a6264388f9212a82640ab3d9854dd74aa7c960396ef5",
+    },
+    GoldenVector {
+        input: "ndjson",
+        level: CompressionLevel::Fast,
+        expected: "583058d4e32c7e7d78cf20ff90b3f44969a8e7d8762797aed35418f44660e7ae",
+    },
+    GoldenVector {
+        input: "ndjson",
+        level: CompressionLevel::Default,
+        expected: "e48e4a639b7791232d223dcd2b547d5498b94158b36c3681057aea017e55c3f3",
+    },
+    GoldenVector {
+        input: "ndjson",
+        level: CompressionLevel::Max,
+        expected: "9475dc62a5cd839e00f55105ff40e69f4104b5362839ef07a613aa69bfebf6b2",
+    },
+    GoldenVector {
+        input: "records",
+        level: CompressionLevel::Fast,
+        expected: "babbbaa744e0b1e4064349d77cbefd77acbb2116e606c9914e0ac5b39f0c0ca8",
+    },
+    GoldenVector {
+        input: "records",
+        level: CompressionLevel::Default,
+        expected: "babbbaa744e0b1e4064349d77cbefd77acbb2116e606c9914e0ac5b39f0c0ca8",
+    },
+    GoldenVector {
+        input: "records",
+        level: CompressionLevel::Max,
+        expected: "263bed98534f878205a1a1820f29580a95f9eb3fe4c179a4828c325797831d89",
+    },
+    GoldenVector {
+        input: "random",
+        level: CompressionLevel::Fast,
+        expected: "b59e9b7eafc3f1abcc9994ea7773f6d0280caa149d0701c00a3f2b0b06f70b9e",
+    },
+    GoldenVector {
+        input: "random",
+        level: CompressionLevel::Default,
+        expected: "b59e9b7eafc3f1abcc9994ea7773f6d0280caa149d0701c00a3f2b0b06f70b9e",
+    },
+    GoldenVector {
+        input: "random",
+        level: CompressionLevel::Max,
+        expected: "b59e9b7eafc3f1abcc9994ea7773f6d0280caa149d0701c00a3f2b0b06f70b9e",
+    },
+];
+
+/// Inputs stored as deduplicated chunks, as uploads of `dedup_chunks`
+/// servers are. The hash covers every chunk blob in object order.
+const CHUNKED_VECTORS: &[GoldenVector] = &[
+    GoldenVector {
+        input: "text",
+        level: CompressionLevel::Default,
+        expected: "104d18a0e27a0892c4aada5b563f678c63d86c5c9aadf6b8fb01a119b6d14c4e",
+    },
+    GoldenVector {
+        input: "ndjson",
+        level: CompressionLevel::Fast,
+        expected: "69a3097bfcda253e610b76d704b566b7189a2deaa2bef1e26552d4887b9230e0",
+    },
+    GoldenVector {
+        input: "ndjson",
+        level: CompressionLevel::Max,
+        expected: "5c9029b061bcfa4ce645a76923f5b6157d9f675127c34e74faa0196828d0ab1a",
+    },
+];
+
+/// Scratch directory for one compression, removed when dropped
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("symvea-golden-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        Self(dir)
+    }
+
+    fn path(&self) -> String {
+        self.0.to_string_lossy().to_string()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Compresses `input` the way a single upload is and returns the blob and
+/// what it decodes to
+fn compress(input: &[u8], level: CompressionLevel, name: &str) -> (Vec<u8>, Vec<u8>) {
+    let scratch = Scratch::new(name);
+    let symbol_store = SymbolStore::new(scratch.path());
+    let mut dict = Dictionary::new("golden");
+    let config = EngineConfig::default().with_level(level);
+    let codec = choose_codec(input);
+    let learned = codec.learn(input, &mut dict, &symbol_store, &config);
+    let (codec, (blob, ..)) = encode_or_store(codec, input, &dict, &symbol_store, name, &config, learned);
+    let decoded = codec.decompress(&blob, &dict).unwrap();
+    (blob, decoded)
+}
+
+/// Stores `input` through `DedupWriter` and returns its chunk blobs, in
+/// object order, and the object read back from them
+fn compress_chunked(input: &[u8], level: CompressionLevel, name: &str) -> (Vec<u8>, Vec<u8>) {
+    let scratch = Scratch::new(&format!("chunked-{}", name));
+    let dictionaries = DictionaryRegistry::new(scratch.path(), Dictionary::new("golden"));
+    let symbol_store = SymbolStore::new(scratch.path());
+    let chunk_store = ChunkStore::new(scratch.path());
+    let config = EngineConfig { dedup_chunks: true, ..EngineConfig::default().with_level(level) };
+
+    let mut writer = DedupWriter::new(name.to_string(), None, config);
+    writer.push(input, &dictionaries, &symbol_store, &chunk_store).unwrap();
+    let meta = writer.finish(&dictionaries, &symbol_store, &chunk_store).unwrap();
+
+    let mut blobs = Vec::new();
+    for chunk in &meta.chunks {
+        blobs.extend(chunk_store.get(&chunk.hash).unwrap().unwrap().data);
+    }
+    let decoded = chunk_store.assemble(&meta, &dictionaries).unwrap();
+    (blobs, decoded)
+}
+
+/// Checks the vectors for `input`, compressing each twice, and lists all
+/// mismatches at once so changed hashes can be updated together
+fn check(
+    vectors: &[GoldenVector],
+    input: &str,
+    compress: impl Fn(&[u8], CompressionLevel, &str) -> (Vec<u8>, Vec<u8>),
+) {
+    let mut failures = Vec::new();
+    for vector in vectors.iter().filter(|vector| vector.input == input) {
+        let input = golden_input(vector.input);
+        let name = format!("{}-{}", vector.input, vector.level);
+        let (blob, decoded) = compress(&input, vector.level, &name);
+        let (again, _) = compress(&input, vector.level, &name);
+
+        let actual = hex::encode(sha256(&blob));
+        if blob != again {
+            failures.push(format!("{}: two runs produced different blobs", name));
+        } else if decoded != input {
+            failures.push(format!("{}: blob does not decode to the input", name));
+        } else if actual != vector.expected {
+            failures.push(format!("{}: expected {}, got {}", name, vector.expected, actual));
+        }
+    }
+    assert!(failures.is_empty(), "golden vectors differ:\n{}", failures.join("\n"));
+}
+
+/// Builds a golden input. Everything is derived from fixed seeds, so the
+/// inputs are the same on every platform and never have to be shipped.
+fn golden_input(name: &str) -> Vec<u8> {
+    match name {
+        "short" => b"golden vector: a short input that still repeats, still repeats".to_vec(),
+        "text" => text(96 * 1024),
+        "ndjson" => ndjson(300 * 1024),
+        "records" => records(128 * 1024),
+        "random" => {
+            let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+            (0..64 * 1024).map(|_| rng.next() as u8).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+const WORDS: [&str; 16] = [
+    "the", "block", "symbol", "dictionary", "stream", "object", "server", "upload",
+    "planner", "token", "generation", "chunk", "coder", "entropy", "level", "store",
+];
+
+fn text(len: usize) -> Vec<u8> {
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        let words = 6 + rng.below(10);
+        for i in 0..words {
+            let word = WORDS[rng.below(WORDS.len())];
+            if i == 0 {
+                out.push(word.as_bytes()[0].to_ascii_uppercase());
+                out.extend_from_slice(&word.as_bytes()[1..]);
+            } else {
+                out.push(b' ');
+                out.extend_from_slice(word.as_bytes());
+            }
+        }
+        out.extend_from_slice(b".\n");
+    }
+    out.truncate(len);
+    out
+}
+
+fn ndjson(len: usize) -> Vec<u8> {
+    const LEVELS: [&str; 4] = ["debug", "info", "warn", "error"];
+    let mut rng = XorShift(0xda94_2042_e4dd_58b5);
+    let mut out = Vec::with_capacity(len);
+    let mut timestamp = 1_700_000_000_000u64;
+    while out.len() < len {
+        timestamp += rng.below(1000) as u64;
+        let line = format!(
+            "{{\"ts\":{},\"level\":\"{}\",\"service\":\"{}\",\"request_id\":\"{:016x}\",\"latency_ms\":{},\"message\":\"{} {} {}\"}}\n",
+            timestamp,
+            LEVELS[rng.below(LEVELS.len())],
+            WORDS[rng.below(4)],
+            rng.next(),
+            rng.below(5000),
+            WORDS[rng.below(WORDS.len())],
+            WORDS[rng.below(WORDS.len())],
+            WORDS[rng.below(WORDS.len())],
+        );
+        out.extend_from_slice(line.as_bytes());
+    }
+    out.truncate(len);
+    out
+}
+
+/// Fixed-width little-endian records: id, kind, flags, value, checksum
+fn records(len: usize) -> Vec<u8> {
+    let mut rng = XorShift(0x6a09_e667_f3bc_c909);
+    let mut out = Vec::with_capacity(len);
+    let mut id = 0u64;
+    while out.len() < len {
+        id += 1;
+        out.extend_from_slice(&id.to_le_bytes());
+        out.extend_from_slice(&(rng.below(8) as u32).to_le_bytes());
+        out.extend_from_slice(&0x8000_0001u32.to_le_bytes());
+        out.extend_from_slice(&(rng.below(1 << 20) as u64).to_le_bytes());
+        out.extend_from_slice(&(rng.next() as u32).to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+    }
+    out.truncate(len);
+    out
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[test]
+fn short_blobs_match() {
+    check(GOLDEN_VECTORS, "short", compress);
+}
+
+#[test]
+fn text_blobs_match() {
+    check(GOLDEN_VECTORS, "text", compress);
+}
+
+#[test]
+fn ndjson_blobs_match() {
+    check(GOLDEN_VECTORS, "ndjson", compress);
+}
+
+#[test]
+fn records_blobs_match() {
+    check(GOLDEN_VECTORS, "records", compress);
+}
+
+#[test]
+fn random_blobs_match() {
+    check(GOLDEN_VECTORS, "random", compress);
+}
+
+#[test]
+fn chunked_text_blobs_match() {
+    check(CHUNKED_VECTORS, "text", compress_chunked);
+}
+
+#[test]
+fn chunked_ndjson_blobs_match() {
+    check(CHUNKED_VECTORS, "ndjson", compress_chunked);
+}
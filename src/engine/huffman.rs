@@ -1,202 +1,574 @@
-use std::collections::{HashMap, BinaryHeap};
-use std::cmp::Ordering;
-
-#[derive(Debug, Clone)]
-pub struct HuffmanNode {
-    pub freq: usize,
-    pub token: Option<u32>,
-    pub left: Option<Box<HuffmanNode>>,
-    pub right: Option<Box<HuffmanNode>>,
-}
+use std::collections::HashMap;
+use crate::utils::varint::{encode_varint, decode_varint};
+use crate::utils::bits::{BitReader, BitWriter};
+use crate::engine::entropy::EntropyError;
 
-impl PartialEq for HuffmanNode {
-    fn eq(&self, other: &Self) -> bool {
-        self.freq == other.freq
-    }
-}
+/// Hard cap on code length; only exceeded when the alphabet itself needs
+/// more bits than this to be addressed at all.
+pub const MAX_CODE_LEN: u8 = 20;
 
-impl Eq for HuffmanNode {}
+/// Codes up to this length decode with a single table lookup
+const LOOKUP_BITS: u8 = 11;
 
-impl PartialOrd for HuffmanNode {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
+/// Tokens below this index get a dense encode slot instead of a hash lookup
+const DENSE_TOKENS: u32 = 1 << 16;
 
-impl Ord for HuffmanNode {
-    fn cmp(&self, other: &Self) -> Ordering {
-        other.freq.cmp(&self.freq) // Reverse for min-heap
-    }
+#[derive(Debug, Clone, Copy, Default)]
+struct LookupEntry {
+    token: u32,
+    // 0 marks a prefix of some longer code, resolved by the slow path
+    len: u8,
 }
 
 pub struct HuffmanTable {
-    pub encode_table: HashMap<u32, Vec<bool>>,
-    pub decode_tree: Option<Box<HuffmanNode>>,
+    /// `(token, code length)` sorted by token; this is all that is serialized
+    pub code_lengths: Vec<(u32, u8)>,
+    // Encode side: (code, length) per token
+    dense_codes: Vec<(u32, u8)>,
+    sparse_codes: HashMap<u32, (u32, u8)>,
+    // Decode side: first-level lookup plus canonical tables for long codes
+    lookup: Vec<LookupEntry>,
+    count_per_len: Vec<u32>,
+    canonical_tokens: Vec<u32>,
 }
 
 impl HuffmanTable {
     pub fn build(tokens: &[u32]) -> Self {
         let mut freq_map = HashMap::new();
         for &token in tokens {
-            *freq_map.entry(token).or_insert(0) += 1;
+            *freq_map.entry(token).or_insert(0u64) += 1;
         }
 
-        if freq_map.len() <= 1 {
-            // Special case: only one unique token
-            let mut encode_table = HashMap::new();
-            if let Some(&token) = freq_map.keys().next() {
-                encode_table.insert(token, vec![false]); // Single bit
+        // Sorted by token so ties break the same way on every run
+        let mut freqs: Vec<(u32, u64)> = freq_map.into_iter().collect();
+        freqs.sort_unstable();
+
+        let lengths = limited_code_lengths(&freqs, MAX_CODE_LEN);
+        let code_lengths = freqs.iter().map(|&(token, _)| token).zip(lengths).collect();
+
+        Self::from_code_lengths(code_lengths)
+            .expect("package-merge produced an invalid prefix code")
+    }
+
+    /// Rebuilds the canonical code from `(token, length)` pairs. Codes are
+    /// assigned in order of (length, token), so the lengths alone fully
+    /// determine the table. Returns `None` if the lengths are not a valid
+    /// prefix code.
+    pub fn from_code_lengths(mut code_lengths: Vec<(u32, u8)>) -> Option<Self> {
+        code_lengths.sort_unstable();
+        if code_lengths.windows(2).any(|w| w[0].0 == w[1].0) {
+            return None;
+        }
+
+        // Kraft inequality, scaled so the longest permitted code is one unit
+        let mut kraft = 0u64;
+        for &(_, len) in &code_lengths {
+            if len == 0 || len > 32 {
+                return None;
             }
-            return Self {
-                encode_table,
-                decode_tree: None,
-            };
+            kraft += 1u64 << (32 - len);
+        }
+        if kraft > 1u64 << 32 {
+            return None;
         }
 
-        let mut heap = BinaryHeap::new();
-        for (token, freq) in freq_map {
-            heap.push(HuffmanNode {
-                freq,
-                token: Some(token),
-                left: None,
-                right: None,
-            });
-        }
-
-        // Build Huffman tree
-        while heap.len() > 1 {
-            let right = heap.pop().unwrap();
-            let left = heap.pop().unwrap();
-            
-            heap.push(HuffmanNode {
-                freq: left.freq + right.freq,
-                token: None,
-                left: Some(Box::new(left)),
-                right: Some(Box::new(right)),
-            });
-        }
-
-        let root = heap.pop().unwrap();
-        let mut encode_table = HashMap::new();
-        
-        fn build_codes(node: &HuffmanNode, code: Vec<bool>, table: &mut HashMap<u32, Vec<bool>>) {
-            if let Some(token) = node.token {
-                table.insert(token, if code.is_empty() { vec![false] } else { code });
-            } else {
-                if let Some(ref left) = node.left {
-                    let mut left_code = code.clone();
-                    left_code.push(false);
-                    build_codes(left, left_code, table);
-                }
-                if let Some(ref right) = node.right {
-                    let mut right_code = code.clone();
-                    right_code.push(true);
-                    build_codes(right, right_code, table);
+        let mut canonical_order: Vec<(u8, u32)> = code_lengths.iter().map(|&(t, l)| (l, t)).collect();
+        canonical_order.sort_unstable();
+
+        let max_len = canonical_order.last().map_or(0, |&(l, _)| l);
+        let mut count_per_len = vec![0u32; max_len as usize + 1];
+        let mut dense_codes = Vec::new();
+        let mut sparse_codes = HashMap::new();
+        let mut lookup = vec![LookupEntry::default(); 1 << LOOKUP_BITS];
+        let mut code = 0u32;
+        let mut prev_len = 0u8;
+
+        for &(len, token) in &canonical_order {
+            code = if len - prev_len >= 32 { 0 } else { code << (len - prev_len) };
+            prev_len = len;
+            count_per_len[len as usize] += 1;
+
+            if token < DENSE_TOKENS {
+                if dense_codes.len() <= token as usize {
+                    dense_codes.resize(token as usize + 1, (0, 0));
                 }
+                dense_codes[token as usize] = (code, len);
+            } else {
+                sparse_codes.insert(token, (code, len));
+            }
+
+            if len <= LOOKUP_BITS {
+                let shift = LOOKUP_BITS - len;
+                let start = (code as usize) << shift;
+                lookup[start..start + (1 << shift)].fill(LookupEntry { token, len });
             }
+
+            code = code.wrapping_add(1);
         }
 
-        build_codes(&root, Vec::new(), &mut encode_table);
+        Some(Self {
+            code_lengths,
+            dense_codes,
+            sparse_codes,
+            lookup,
+            count_per_len,
+            canonical_tokens: canonical_order.into_iter().map(|(_, t)| t).collect(),
+        })
+    }
 
-        Self {
-            encode_table,
-            decode_tree: Some(Box::new(root)),
+    #[inline]
+    fn code_for(&self, token: u32) -> Option<(u32, u8)> {
+        if token < DENSE_TOKENS {
+            self.dense_codes.get(token as usize).copied().filter(|&(_, len)| len > 0)
+        } else {
+            self.sparse_codes.get(&token).copied()
         }
     }
 
-    pub fn encode(&self, tokens: &[u32]) -> Vec<u8> {
-        let mut bits = Vec::new();
-        
-        for &token in tokens {
-            if let Some(code) = self.encode_table.get(&token) {
-                bits.extend(code);
-            }
+    /// Serialized table: entry count, then per entry the token as a varint
+    /// delta from the previous token and its code length.
+    pub fn write_table(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.code_lengths.len() as u32).to_be_bytes());
+
+        let mut prev_token = 0u32;
+        for &(token, len) in &self.code_lengths {
+            encode_varint((token - prev_token) as u64, out);
+            out.push(len);
+            prev_token = token;
         }
+    }
 
-        // Pack bits into bytes
-        let mut bytes = Vec::new();
-        let mut current_byte = 0u8;
-        let mut bit_count = 0;
+    /// Parses a table written by `write_table`, returning it and the number
+    /// of bytes consumed.
+    pub fn read_table(data: &[u8]) -> Option<(Self, usize)> {
+        let count = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let mut offset = 4;
 
-        for bit in bits {
-            if bit {
-                current_byte |= 1 << (7 - bit_count);
-            }
-            bit_count += 1;
+        // Every entry takes at least two bytes
+        if count > data.len().saturating_sub(offset) / 2 {
+            return None;
+        }
 
-            if bit_count == 8 {
-                bytes.push(current_byte);
-                current_byte = 0;
-                bit_count = 0;
+        let mut code_lengths = Vec::with_capacity(count);
+        let mut token = 0u64;
+        for i in 0..count {
+            let (delta, used) = decode_varint(&data[offset..])?;
+            offset += used;
+            if i > 0 && delta == 0 {
+                return None;
             }
+            token += delta;
+            let len = *data.get(offset)?;
+            offset += 1;
+            code_lengths.push((u32::try_from(token).ok()?, len));
+        }
+
+        Some((Self::from_code_lengths(code_lengths)?, offset))
+    }
+
+    /// Packs the codes for `tokens`, prefixed by one byte giving the number
+    /// of used bits in the final byte (0 when it is full).
+    pub fn encode(&self, tokens: &[u32]) -> Result<Vec<u8>, EntropyError> {
+        let mut writer = BitWriter::with_capacity(tokens.len() / 2 + 1);
+        writer.write(0, 8); // placeholder for the tail bit count
+
+        for &token in tokens {
+            let (code, len) = self.code_for(token).ok_or(EntropyError::UnknownToken(token))?;
+            writer.write(code, len);
         }
 
-        // Handle remaining bits
-        if bit_count > 0 {
-            bytes.push(current_byte);
+        let (mut bytes, tail) = writer.finish();
+        bytes[0] = tail;
+        Ok(bytes)
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u32>, EntropyError> {
+        let Some((&last_byte_bits, bytes)) = data.split_first() else {
+            return Ok(Vec::new());
+        };
+        if last_byte_bits > 7 {
+            return Err(EntropyError::InvalidCode);
         }
 
-        // Prepend bit count for last byte
-        let mut result = vec![bit_count as u8];
-        result.extend(bytes);
-        result
+        let total_bits = match last_byte_bits {
+            0 => bytes.len() * 8,
+            n if !bytes.is_empty() => (bytes.len() - 1) * 8 + n as usize,
+            _ => return Err(EntropyError::Truncated),
+        };
+
+        let mut tokens = Vec::with_capacity(total_bits / 4);
+        let mut reader = BitReader::new(bytes);
+
+        while reader.position() < total_bits {
+            let entry = self.lookup[reader.peek(LOOKUP_BITS) as usize];
+            let (token, len) = if entry.len > 0 {
+                (entry.token, entry.len)
+            } else {
+                self.decode_long(&mut reader)?
+            };
+
+            reader.consume(len);
+            if reader.position() > total_bits {
+                return Err(EntropyError::Truncated);
+            }
+            tokens.push(token);
+        }
+
+        Ok(tokens)
     }
 
-    pub fn decode(&self, data: &[u8]) -> Vec<u32> {
-        if data.is_empty() {
-            return Vec::new();
+    /// Canonical decode one bit at a time, for codes that miss the lookup
+    /// table. Does not consume; returns the token and its code length.
+    fn decode_long(&self, reader: &mut BitReader) -> Result<(u32, u8), EntropyError> {
+        let max_len = (self.count_per_len.len() - 1) as u8;
+        let bits = reader.peek(max_len);
+
+        let mut code = 0u32;
+        let mut first = 0u32;
+        let mut index = 0u32;
+        for len in 1..=max_len {
+            code |= (bits >> (max_len - len)) & 1;
+            let count = self.count_per_len[len as usize];
+            if code.wrapping_sub(first) < count {
+                return Ok((self.canonical_tokens[(index + code - first) as usize], len));
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
         }
 
-        let last_byte_bits = data[0] as usize;
-        let bytes = &data[1..];
-        
-        if bytes.is_empty() {
-            return Vec::new();
+        Err(EntropyError::InvalidCode)
+    }
+}
+
+/// Table layout of headerless version 1 blobs, written before code lengths
+/// were canonical: entry count (u32), then per entry the token (u32), code
+/// length (u8), code byte count (u8) and the code bits, most significant
+/// first. Only read, so objects stored by those builds still decode.
+pub struct LegacyHuffmanTable {
+    // Code tree; node 0 is the root and child index 0 means no child
+    nodes: Vec<LegacyNode>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct LegacyNode {
+    children: [u32; 2],
+    token: Option<u32>,
+}
+
+impl LegacyHuffmanTable {
+    /// Parses a legacy table, returning it and the number of bytes
+    /// consumed. Returns `None` if the codes are not a prefix code.
+    pub fn read_table(data: &[u8]) -> Option<(Self, usize)> {
+        let count = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let mut offset = 4;
+
+        // Every entry takes at least seven bytes
+        if count > data.len().saturating_sub(offset) / 7 {
+            return None;
         }
 
-        let Some(ref root) = self.decode_tree else {
-            // Single token case - optimized
-            if let Some((&token, _)) = self.encode_table.iter().next() {
-                let total_bits = if last_byte_bits > 0 {
-                    (bytes.len() - 1) * 8 + last_byte_bits
-                } else {
-                    bytes.len() * 8
-                };
-                return vec![token; total_bits];
+        let mut nodes = vec![LegacyNode::default()];
+        for _ in 0..count {
+            let token = u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?);
+            let code_len = *data.get(offset + 4)? as usize;
+            let code_bytes_len = *data.get(offset + 5)? as usize;
+            offset += 6;
+            let code_bytes = data.get(offset..offset + code_bytes_len)?;
+            offset += code_bytes_len;
+            if code_len == 0 || code_len > code_bytes_len * 8 {
+                return None;
             }
-            return Vec::new();
+
+            let mut node = 0;
+            for i in 0..code_len {
+                if nodes[node].token.is_some() {
+                    return None;
+                }
+                let bit = ((code_bytes[i / 8] >> (7 - i % 8)) & 1) as usize;
+                if nodes[node].children[bit] == 0 {
+                    nodes.push(LegacyNode::default());
+                    nodes[node].children[bit] = (nodes.len() - 1) as u32;
+                }
+                node = nodes[node].children[bit] as usize;
+            }
+            if nodes[node].token.is_some() || nodes[node].children != [0, 0] {
+                return None;
+            }
+            nodes[node].token = Some(token);
+        }
+
+        Some((Self { nodes }, offset))
+    }
+
+    /// Decodes a stream in the layout `HuffmanTable::encode` still writes:
+    /// the used bits of the final byte, then the packed codes
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u32>, EntropyError> {
+        let Some((&last_byte_bits, bytes)) = data.split_first() else {
+            return Ok(Vec::new());
         };
+        if last_byte_bits > 7 {
+            return Err(EntropyError::InvalidCode);
+        }
 
-        let mut tokens = Vec::new();
-        let mut current_node = root.as_ref();
-        
-        // Pre-calculate total bits to avoid repeated calculations
-        let total_bytes = bytes.len();
-        
-        for (i, &byte) in bytes.iter().enumerate() {
-            let bits_in_byte = if i == total_bytes - 1 && last_byte_bits > 0 {
-                last_byte_bits
-            } else {
-                8
+        let total_bits = match last_byte_bits {
+            0 => bytes.len() * 8,
+            n if !bytes.is_empty() => (bytes.len() - 1) * 8 + n as usize,
+            _ => return Err(EntropyError::Truncated),
+        };
+
+        let mut tokens = Vec::with_capacity(total_bits / 4);
+        let mut node = 0;
+        for i in 0..total_bits {
+            let bit = (bytes[i / 8] >> (7 - i % 8)) & 1;
+            node = match self.nodes[node].children[bit as usize] {
+                0 => return Err(EntropyError::InvalidCode),
+                child => child as usize,
             };
+            if let Some(token) = self.nodes[node].token {
+                tokens.push(token);
+                node = 0;
+            }
+        }
+        if node != 0 {
+            return Err(EntropyError::Truncated);
+        }
 
-            // Process all bits in this byte at once
-            for bit_pos in 0..bits_in_byte {
-                let bit = (byte >> (7 - bit_pos)) & 1 == 1;
-                
-                current_node = if bit {
-                    current_node.right.as_ref().unwrap()
-                } else {
-                    current_node.left.as_ref().unwrap()
-                };
-
-                if let Some(token) = current_node.token {
-                    tokens.push(token);
-                    current_node = root.as_ref();
-                }
+        Ok(tokens)
+    }
+}
+
+/// Optimal code lengths with no code longer than `max_len`, by the
+/// package-merge algorithm. `freqs` must be sorted by token; the result is
+/// parallel to it.
+fn limited_code_lengths(freqs: &[(u32, u64)], max_len: u8) -> Vec<u8> {
+    let n = freqs.len();
+    match n {
+        0 => return Vec::new(),
+        1 => return vec![1],
+        _ => {}
+    }
+
+    // A complete code over n symbols needs at least ceil(log2 n) bits
+    let min_len = (usize::BITS - (n - 1).leading_zeros()) as u8;
+    let max_len = max_len.max(min_len);
+
+    // Leaves by ascending weight, ties by token order
+    let mut leaves: Vec<usize> = (0..n).collect();
+    leaves.sort_by_key(|&i| freqs[i].1);
+
+    #[derive(Clone, Copy)]
+    enum Item {
+        Leaf(usize),
+        Package,
+    }
+
+    // levels[0] is the deepest list; each following list merges the leaves
+    // with pairwise packages of the list before it
+    let mut levels: Vec<Vec<(u64, Item)>> = Vec::with_capacity(max_len as usize);
+    levels.push(leaves.iter().map(|&i| (freqs[i].1, Item::Leaf(i))).collect());
+
+    for _ in 1..max_len {
+        let prev = levels.last().unwrap();
+        let packages = prev.chunks_exact(2).map(|pair| (pair[0].0 + pair[1].0, Item::Package));
+
+        let mut merged = Vec::with_capacity(n + prev.len() / 2);
+        let mut leaf_iter = leaves.iter().map(|&i| (freqs[i].1, Item::Leaf(i))).peekable();
+        let mut package_iter = packages.peekable();
+        loop {
+            // Leaves win ties so the merge is stable
+            let take_leaf = match (leaf_iter.peek(), package_iter.peek()) {
+                (Some(l), Some(p)) => l.0 <= p.0,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if take_leaf {
+                merged.push(leaf_iter.next().unwrap());
+            } else {
+                merged.push(package_iter.next().unwrap());
+            }
+        }
+        levels.push(merged);
+    }
+
+    // Take the cheapest 2n - 2 items of the top list; each chosen package
+    // pulls its two children from the list below. A leaf's code length is
+    // the number of lists it is chosen in.
+    let mut lengths = vec![0u8; n];
+    let mut take = 2 * n - 2;
+    for level in levels.iter().rev() {
+        let mut packages = 0;
+        for &(_, item) in &level[..take] {
+            match item {
+                Item::Leaf(i) => lengths[i] += 1,
+                Item::Package => packages += 1,
             }
         }
+        take = 2 * packages;
+    }
+
+    lengths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn roundtrip(tokens: &[u32]) -> Vec<u8> {
+        let table = HuffmanTable::build(tokens);
+        let mut out = Vec::new();
+        table.write_table(&mut out);
+        let (read, used) = HuffmanTable::read_table(&out).unwrap();
+        assert_eq!(used, out.len());
+        assert_eq!(read.code_lengths, table.code_lengths);
+
+        let stream = table.encode(tokens).unwrap();
+        assert_eq!(read.decode(&stream).unwrap(), tokens);
+        out
+    }
+
+    /// Frequencies that grow like the Fibonacci sequence give an
+    /// unrestricted Huffman code one level deeper per symbol
+    fn fibonacci_tokens(symbols: u32) -> Vec<u32> {
+        let (mut a, mut b) = (1u64, 1u64);
+        let mut tokens = Vec::new();
+        for token in 0..symbols {
+            tokens.extend(std::iter::repeat_n(token, a as usize));
+            (a, b) = (b, (a + b).min(1 << 16));
+        }
         tokens
     }
-}
\ No newline at end of file
+
+    fn kraft_sum(code_lengths: &[(u32, u8)]) -> f64 {
+        code_lengths.iter().map(|&(_, len)| 0.5f64.powi(len as i32)).sum()
+    }
+
+    #[test]
+    fn roundtrips() {
+        roundtrip(&[]);
+        roundtrip(&[7]);
+        roundtrip(&[7; 100]);
+        roundtrip(&[1, 2, 1, 2, 2]);
+        roundtrip(&(0..300).collect::<Vec<_>>());
+        roundtrip(&[0, 255, 256, 70_000, 16_777_216, u32::MAX - 1, 255, 0, 70_000]);
+        roundtrip(&fibonacci_tokens(30));
+    }
+
+    #[test]
+    fn package_merge_bounds_code_length() {
+        let tokens = fibonacci_tokens(40);
+        let table = HuffmanTable::build(&tokens);
+        assert_eq!(table.code_lengths.len(), 40);
+        assert!(table.code_lengths.iter().all(|&(_, len)| len <= MAX_CODE_LEN));
+        assert_eq!(table.code_lengths.iter().map(|&(_, len)| len).max(), Some(MAX_CODE_LEN));
+        // Limited but still complete
+        assert_eq!(kraft_sum(&table.code_lengths), 1.0);
+    }
+
+    #[test]
+    fn package_merge_is_optimal_when_unconstrained() {
+        // Dyadic frequencies have an exact Huffman code
+        let counts = [(0u32, 8u64), (1, 4), (2, 2), (3, 1), (4, 1)];
+        assert_eq!(limited_code_lengths(&counts, MAX_CODE_LEN), vec![1, 2, 3, 4, 4]);
+        assert_eq!(limited_code_lengths(&counts, 3), vec![1, 3, 3, 3, 3]);
+        assert_eq!(limited_code_lengths(&[(5, 1)], MAX_CODE_LEN), vec![1]);
+    }
+
+    #[test]
+    fn alphabet_wider_than_cap_still_addressable() {
+        // Ten symbols need four bits however tight the cap
+        let counts: Vec<(u32, u64)> = (0..10).map(|t| (t, 1 + t as u64)).collect();
+        let lengths = limited_code_lengths(&counts, 2);
+        assert!(lengths.iter().all(|&len| len <= 4));
+        let code_lengths: Vec<(u32, u8)> = (0..10).zip(lengths).collect();
+        assert_eq!(kraft_sum(&code_lengths), 1.0);
+        assert!(HuffmanTable::from_code_lengths(code_lengths).is_some());
+    }
+
+    #[test]
+    fn rejects_truncated_table() {
+        let table = roundtrip(&(0..50).chain(0..25).collect::<Vec<_>>());
+        for len in 0..table.len() {
+            assert!(HuffmanTable::read_table(&table[..len]).is_none(), "accepted {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_code_lengths() {
+        let table = |entries: &[(u64, u8)]| {
+            let mut out = (entries.len() as u32).to_be_bytes().to_vec();
+            for &(delta, len) in entries {
+                encode_varint(delta, &mut out);
+                out.push(len);
+            }
+            out
+        };
+
+        assert!(HuffmanTable::read_table(&table(&[(1, 1), (1, 1)])).is_some());
+        // Over-subscribed: three codes of one bit
+        assert!(HuffmanTable::read_table(&table(&[(1, 1), (1, 1), (1, 1)])).is_none());
+        // Zero and over-long lengths
+        assert!(HuffmanTable::read_table(&table(&[(1, 0), (1, 1)])).is_none());
+        assert!(HuffmanTable::read_table(&table(&[(1, 33), (1, 1)])).is_none());
+        // The same token twice
+        assert!(HuffmanTable::read_table(&table(&[(1, 1), (0, 1)])).is_none());
+        // Token past u32
+        assert!(HuffmanTable::read_table(&table(&[(1 << 32, 1)])).is_none());
+        // Entry count larger than the data could hold
+        let mut huge = table(&[(1, 1), (1, 1)]);
+        huge[..4].copy_from_slice(&1000u32.to_be_bytes());
+        assert!(HuffmanTable::read_table(&huge).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_legacy_table() {
+        // 'a' = 0 and 'b' = 1, as the baseline wrote them
+        let valid = [0, 0, 0, 2, 0, 0, 0, 97, 1, 1, 0x00, 0, 0, 0, 98, 1, 1, 0x80];
+        let (table, used) = LegacyHuffmanTable::read_table(&valid).unwrap();
+        assert_eq!(used, valid.len());
+        assert_eq!(table.decode(&[4, 0b0110_0000]).unwrap(), vec![97, 98, 98, 97]);
+
+        for len in 0..valid.len() {
+            assert!(LegacyHuffmanTable::read_table(&valid[..len]).is_none(), "accepted {} bytes", len);
+        }
+        // Code length longer than its bytes
+        let mut long = valid;
+        long[8] = 9;
+        assert!(LegacyHuffmanTable::read_table(&long).is_none());
+        // Both tokens on the same code
+        let mut same = valid;
+        same[17] = 0x00;
+        assert!(LegacyHuffmanTable::read_table(&same).is_none());
+    }
+
+    #[test]
+    fn long_codes_take_the_slow_path() {
+        let tokens = fibonacci_tokens(30);
+        let table = HuffmanTable::build(&tokens);
+        assert!(table.code_lengths.iter().any(|&(_, len)| len > LOOKUP_BITS));
+        let stream = table.encode(&tokens).unwrap();
+        assert_eq!(table.decode(&stream).unwrap(), tokens);
+    }
+
+    #[test]
+    fn rejects_malformed_stream() {
+        // One token with the one-bit code 0; code 1 is unassigned
+        let table = HuffmanTable::build(&[9, 9, 9]);
+        assert_eq!(table.decode(&[3, 0b0000_0000]).unwrap(), vec![9, 9, 9]);
+
+        assert!(matches!(table.decode(&[8, 0]), Err(EntropyError::InvalidCode)));
+        assert!(matches!(table.decode(&[3]), Err(EntropyError::Truncated)));
+        assert!(matches!(table.decode(&[3, 0b0100_0000]), Err(EntropyError::InvalidCode)));
+        assert!(matches!(table.encode(&[10]), Err(EntropyError::UnknownToken(10))));
+
+        // A stream that stops inside a code
+        let tokens = fibonacci_tokens(30);
+        let table = HuffmanTable::build(&tokens);
+        let mut stream = table.encode(&[0]).unwrap();
+        let bits = table.code_lengths[0].1 as usize;
+        stream[0] = ((bits - 1) % 8) as u8;
+        stream.truncate(1 + (bits - 1).div_ceil(8));
+        assert!(matches!(table.decode(&stream), Err(EntropyError::Truncated)));
+    }
+}
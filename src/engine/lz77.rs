@@ -0,0 +1,160 @@
+/// Shortest repeat worth a back-reference. Shorter fragments are left to
+/// the symbol dictionary, which codes them in a single token.
+pub const MIN_MATCH: usize = 32;
+
+/// Token standing for one back-reference; its length and distance are
+/// stored as varints in the block's match stream
+pub const MATCH_TOKEN: u32 = u32::MAX;
+
+/// Bytes hashed to find match candidates
+const HASH_LEN: usize = 8;
+const HASH_BITS: u32 = 16;
+
+/// Candidates examined per position before settling for the best so far
+const MAX_CHAIN: usize = 16;
+
+const NO_POS: u32 = u32::MAX;
+
+/// A repeat of `len` bytes at `pos`, copied from `distance` bytes back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackReference {
+    pub pos: usize,
+    pub len: usize,
+    pub distance: usize,
+}
+
+/// Greedy longest-match search over hash chains. Matches may overlap the
+/// bytes they produce (distance < len), which covers runs and periodic data.
+pub fn find_back_references(data: &[u8]) -> Vec<BackReference> {
+    let n = data.len();
+    let mut matches = Vec::new();
+    if n < MIN_MATCH {
+        return matches;
+    }
+
+    let mut head = vec![NO_POS; 1 << HASH_BITS];
+    let mut prev = vec![NO_POS; n];
+    let insert = |pos: usize, head: &mut [u32], prev: &mut [u32]| {
+        let h = hash(&data[pos..pos + HASH_LEN]);
+        prev[pos] = head[h];
+        head[h] = pos as u32;
+    };
+
+    let mut i = 0;
+    while i + MIN_MATCH <= n {
+        let mut best_len = 0;
+        let mut best_distance = 0;
+
+        let mut candidate = head[hash(&data[i..i + HASH_LEN])];
+        let mut chain = 0;
+        while candidate != NO_POS && chain < MAX_CHAIN {
+            let c = candidate as usize;
+            // Only a candidate that also matches at `best_len` can beat it
+            if data.get(c + best_len) == data.get(i + best_len) {
+                let len = data[c..].iter().zip(&data[i..]).take_while(|(a, b)| a == b).count();
+                if len > best_len {
+                    best_len = len;
+                    best_distance = i - c;
+                }
+            }
+            candidate = prev[c];
+            chain += 1;
+        }
+
+        if best_len >= MIN_MATCH {
+            matches.push(BackReference { pos: i, len: best_len, distance: best_distance });
+            let end = i + best_len;
+            while i < end && i + HASH_LEN <= n {
+                insert(i, &mut head, &mut prev);
+                i += 1;
+            }
+            i = end;
+        } else {
+            insert(i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+
+    matches
+}
+
+#[inline]
+fn hash(bytes: &[u8]) -> usize {
+    let v = u64::from_le_bytes(bytes[..HASH_LEN].try_into().unwrap());
+    (v.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - HASH_BITS)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rebuilds `data` from its literals and back-references, checking each
+    /// reference copies bytes that are already there
+    fn replay(data: &[u8], references: &[BackReference]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for reference in references {
+            assert!(reference.len >= MIN_MATCH);
+            assert!(reference.pos >= out.len());
+            out.extend_from_slice(&data[out.len()..reference.pos]);
+            assert!(reference.distance > 0 && reference.distance <= out.len());
+            let start = out.len() - reference.distance;
+            for i in start..start + reference.len {
+                out.push(out[i]);
+            }
+        }
+        out.extend_from_slice(&data[out.len()..]);
+        out
+    }
+
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect()
+    }
+
+    #[test]
+    fn references_reproduce_input() {
+        let mut data = noise(3000, 1);
+        let copy = data[100..900].to_vec();
+        data.extend_from_slice(&copy);
+        data.extend(noise(500, 2));
+        data.extend_from_slice(&copy[..40]);
+
+        let references = find_back_references(&data);
+        assert_eq!(references.len(), 2);
+        assert_eq!(references[0], BackReference { pos: 3000, len: 800, distance: 2900 });
+        assert_eq!(replay(&data, &references), data);
+    }
+
+    #[test]
+    fn runs_overlap_their_own_output() {
+        let mut data = b"header:".to_vec();
+        data.extend(std::iter::repeat_n(b'z', 1000));
+        let references = find_back_references(&data);
+        assert_eq!(references.len(), 1);
+        assert_eq!(references[0].distance, 1);
+        assert_eq!(replay(&data, &references), data);
+
+        let periodic = b"0123456789".repeat(50);
+        let references = find_back_references(&periodic);
+        assert_eq!(references[0].distance, 10);
+        assert_eq!(replay(&periodic, &references), periodic);
+    }
+
+    #[test]
+    fn nothing_below_min_match() {
+        assert!(find_back_references(b"").is_empty());
+        assert!(find_back_references(&[b'a'; MIN_MATCH - 1]).is_empty());
+        assert!(find_back_references(&noise(10_000, 3)).is_empty());
+
+        // A repeat one byte too short
+        let mut data = noise(200, 4);
+        let copy = data[10..10 + MIN_MATCH - 1].to_vec();
+        data.extend(copy);
+        assert!(find_back_references(&data).is_empty());
+    }
+}
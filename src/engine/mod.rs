@@ -4,8 +4,24 @@ pub mod tokenizer;
 pub mod compressor;
 pub mod decompressor;
 pub mod planner;
+pub mod suffix_array;
 pub mod hash;
+pub mod error;
+pub mod container;
+pub mod block;
+pub mod stream;
 pub mod huffman;
+pub mod rans;
+pub mod entropy;
+pub mod estimate;
+pub mod codec;
+pub mod lz77;
+pub mod train;
+pub mod prune;
+pub mod chunker;
+pub mod content;
+#[cfg(test)]
+mod golden;
 
 pub use compressor::*;
 pub use decompressor::*;
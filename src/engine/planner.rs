@@ -1,45 +1,284 @@
-use std::collections::HashMap;
+use std::collections::BinaryHeap;
 use crate::engine::symbols::Symbol;
+use crate::engine::content::{detect_content, token_candidates};
+use crate::engine::suffix_array::{suffix_array, lcp_array};
+use crate::engine::tokenizer::{tokenize, SymbolMatcher};
+use crate::utils::limits::MAX_SYMBOL_SIZE;
 
-pub fn plan_symbols(
+/// Rough per-object cost of a used token: its Huffman table entry.
+const SYMBOL_OVERHEAD: isize = 8;
+
+/// Repeated substring found in the sample: the suffixes `sa[left..left + count]`
+/// all start with the same `len` bytes.
+struct Candidate {
+    left: usize,
+    len: usize,
+    count: usize,
+}
+
+/// Plans up to `max_symbols` of the best repeated substrings of `data`,
+/// none longer than `max_len` and each saving at least `min_gain` bytes.
+/// All of `data` is planned from; callers bound the suffix sorting time by
+/// choosing how much to pass in.
+pub fn plan_symbols_limited(
     data: &[u8],
     max_len: usize,
+    max_symbols: usize,
+    min_gain: isize,
 ) -> Vec<Symbol> {
-    let mut freq = HashMap::<Vec<u8>, usize>::new();
-    
-    // Sample data for large files to avoid O(n²) complexity
-    let sample_data = if data.len() > 1024 * 1024 {
-        let sample_size = (data.len() / 20).min(256 * 1024); // 5% sample, max 256KB
-        &data[..sample_size]
-    } else {
-        data
+    let min_gain = min_gain.max(1);
+    let max_len = max_len.min(MAX_SYMBOL_SIZE);
+    if max_len < 2 || data.len() < 2 {
+        return Vec::new();
+    }
+
+    let sa = suffix_array(data);
+    let mut candidates = repeated_substrings(data, &sa, max_len, min_gain);
+    candidates.extend(content_candidates(data, &sa, max_len, max_symbols, min_gain));
+    let selected = select_symbols(data, &sa, candidates, max_symbols, min_gain);
+
+    settle_gains(data, selected, min_gain)
+}
+
+fn gain(count: usize, len: usize) -> isize {
+    if count == 0 {
+        return 0;
+    }
+    (count as isize * len as isize) - (count as isize * 2) - SYMBOL_OVERHEAD
+}
+
+/// Walks the LCP intervals of the suffix array. Every interval is a distinct
+/// substring that repeats once per suffix in it, so each one yields a single
+/// candidate of its full length (capped at `max_len`) without enumerating
+/// every shorter n-gram inside it.
+fn repeated_substrings(data: &[u8], sa: &[u32], max_len: usize, min_gain: isize) -> Vec<Candidate> {
+    let lcp = lcp_array(data, sa);
+
+    let mut candidates = Vec::new();
+    // (lcp, left boundary in the suffix array)
+    let mut stack: Vec<(usize, usize)> = vec![(0, 0)];
+
+    // A trailing zero closes every interval still open at the end
+    let boundaries = lcp[1..].iter().map(|&l| l as usize).chain(std::iter::once(0));
+    for (i, current) in (1..).zip(boundaries) {
+        let mut left = i - 1;
+
+        while current < stack.last().map_or(0, |&(l, _)| l) {
+            let (interval_lcp, interval_left) = stack.pop().unwrap();
+            left = interval_left;
+
+            let parent_lcp = current.max(stack.last().map_or(0, |&(l, _)| l));
+
+            // Intervals nested below max_len all cap to the same prefix;
+            // only the outermost (largest count) one is kept
+            if interval_lcp >= 2 && parent_lcp < max_len {
+                candidates.push(Candidate {
+                    left: interval_left,
+                    len: interval_lcp.min(max_len),
+                    count: i - interval_left,
+                });
+            }
+        }
+
+        if current > stack.last().map_or(0, |&(l, _)| l) {
+            stack.push((current, left));
+        }
+    }
+
+    candidates.retain(|c| gain(c.count, c.len) >= min_gain);
+    candidates
+}
+
+/// Tokens suggested by the kind of content in `data`, located in the
+/// suffix array so they compete with the repeats on the same terms
+fn content_candidates(data: &[u8], sa: &[u32], max_len: usize, max_symbols: usize, min_gain: isize) -> Vec<Candidate> {
+    let kind = detect_content(data);
+    token_candidates(kind, data, max_len, max_symbols * 4).into_iter()
+        .map(|token| {
+            let (left, count) = pattern_interval(data, sa, token);
+            Candidate { left, len: token.len(), count }
+        })
+        // Token counts are estimates; the suffix array has the real ones
+        .filter(|c| c.count >= 2 && gain(c.count, c.len) >= min_gain)
+        .collect()
+}
+
+/// Range of the suffix array holding the suffixes that start with `pattern`
+fn pattern_interval(data: &[u8], sa: &[u32], pattern: &[u8]) -> (usize, usize) {
+    let prefix = |pos: u32| {
+        let suffix = &data[pos as usize..];
+        &suffix[..suffix.len().min(pattern.len())]
     };
-    
-    // Limit max_len for performance
-    let effective_max_len = max_len.min(16);
+    let left = sa.partition_point(|&pos| prefix(pos) < pattern);
+    let right = sa.partition_point(|&pos| prefix(pos) <= pattern);
+    (left, right - left)
+}
+
+/// Lazy greedy selection. Occurrences claimed by an accepted symbol are
+/// removed from the sample, so overlapping candidates ("abc", "bcd", "abcd")
+/// are re-scored against what is left instead of all being promoted.
+/// Gains only shrink as coverage grows, so a candidate whose re-counted gain
+/// still beats the next heap entry is the true best and can be accepted.
+fn select_symbols(
+    data: &[u8],
+    sa: &[u32],
+    candidates: Vec<Candidate>,
+    max_symbols: usize,
+    min_gain: isize,
+) -> Vec<(usize, usize)> {
+    let mut covered = vec![false; data.len()];
+    let mut positions = Vec::new();
+    let mut selected = Vec::new();
+
+    // (gain estimate, tie-break on lower index, candidate index)
+    let mut heap: BinaryHeap<(isize, std::cmp::Reverse<usize>)> = candidates.iter()
+        .enumerate()
+        .map(|(idx, c)| (gain(c.count, c.len), std::cmp::Reverse(idx)))
+        .collect();
+
+    while let Some((estimate, std::cmp::Reverse(idx))) = heap.pop() {
+        if selected.len() >= max_symbols || estimate < min_gain {
+            break;
+        }
 
-    for len in 2..=effective_max_len {
-        for i in 0..sample_data.len().saturating_sub(len) {
-            freq.entry(sample_data[i..i+len].to_vec())
-                .and_modify(|v| *v += 1)
-                .or_insert(1);
+        let c = &candidates[idx];
+        positions.clear();
+        positions.extend(sa[c.left..c.left + c.count].iter().map(|&p| p as usize));
+        positions.sort_unstable();
+
+        // Non-overlapping occurrences that are not already claimed
+        let mut uses = Vec::new();
+        let mut next_free = 0;
+        for &pos in &positions {
+            if pos >= next_free && !covered[pos..pos + c.len].contains(&true) {
+                uses.push(pos);
+                next_free = pos + c.len;
+            }
+        }
+
+        let real_gain = gain(uses.len(), c.len);
+        if real_gain < min_gain {
+            continue;
+        }
+        if real_gain < heap.peek().map_or(0, |&(g, _)| g) {
+            heap.push((real_gain, std::cmp::Reverse(idx)));
+            continue;
+        }
+
+        for &pos in &uses {
+            covered[pos..pos + c.len].fill(true);
+        }
+        selected.push((sa[c.left] as usize, c.len));
+    }
+
+    selected
+}
+
+/// Tokenizes the sample with the selected set and keeps only symbols the
+/// longest-match tokenizer actually emits, recording that as their gain.
+/// Dropping a symbol can shift matches onto others, so repeat until stable.
+fn settle_gains(data: &[u8], mut selected: Vec<(usize, usize)>, min_gain: isize) -> Vec<Symbol> {
+    let mut scored = Vec::new();
+
+    for _ in 0..4 {
+        let uses = measure_uses(data, &selected);
+        scored = selected.iter()
+            .zip(uses)
+            .map(|(&(pos, len), count)| ((pos, len), gain(count, len)))
+            .filter(|&(_, g)| g >= min_gain)
+            .collect::<Vec<_>>();
+
+        if scored.len() == selected.len() {
+            break;
         }
+        selected = scored.iter().map(|&(s, _)| s).collect();
     }
 
-    let mut symbols = Vec::new();
-    let mut token = 256u32;
+    scored.sort_by(|a, b| {
+        let (a_pos, a_len) = a.0;
+        let (b_pos, b_len) = b.0;
+        b.1.cmp(&a.1)
+            .then_with(|| data[a_pos..a_pos + a_len].cmp(&data[b_pos..b_pos + b_len]))
+    });
+
+    scored.into_iter()
+        .zip(256u32..)
+        .map(|(((pos, len), gain), token)| Symbol::new(data[pos..pos + len].to_vec(), token, gain))
+        .collect()
+}
 
-    for (bytes, count) in freq {
-        let gain = (count as isize * bytes.len() as isize)
-            - (count as isize * 2);
+/// How many times the tokenizer emits each selected symbol over `data`.
+fn measure_uses(data: &[u8], selected: &[(usize, usize)]) -> Vec<usize> {
+    let matcher = SymbolMatcher::new(
+        selected.iter().zip(256u32..).map(|(&(pos, len), token)| (&data[pos..pos + len], token)),
+    );
 
-        if gain > 0 {
-            symbols.push(Symbol::new(bytes, token, gain));
-            token += 1;
+    let mut uses = vec![0usize; selected.len()];
+    for token in tokenize(data, &matcher) {
+        if token > 255 {
+            uses[(token - 256) as usize] += 1;
         }
     }
+    uses
+}
 
-    symbols.sort_by(|a, b| b.gain.cmp(&a.gain));
-    symbols.truncate(1000); // Limit symbol count
-    symbols
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(data: &[u8], max_len: usize, max_symbols: usize) -> Vec<Vec<u8>> {
+        plan_symbols_limited(data, max_len, max_symbols, 1).into_iter().map(|s| s.bytes).collect()
+    }
+
+    #[test]
+    fn finds_repeated_phrase() {
+        let data = b"the quick brown fox; the quick brown fox; the quick brown fox; ".repeat(20);
+        let symbols = plan(&data, 64, 16);
+        assert!(symbols.iter().any(|s| s.len() >= 20));
+        for symbol in &symbols {
+            assert!(data.windows(symbol.len()).any(|w| w == symbol.as_slice()));
+        }
+    }
+
+    #[test]
+    fn respects_limits() {
+        let data: Vec<u8> = (0..200u32).flat_map(|i| format!("record-{:03} status=ok latency=12ms\n", i % 40).into_bytes()).collect();
+        for (max_len, max_symbols) in [(4, 100), (16, 3), (64, 1)] {
+            let symbols = plan_symbols_limited(&data, max_len, max_symbols, 1);
+            assert!(symbols.len() <= max_symbols);
+            for (symbol, token) in symbols.iter().zip(256u32..) {
+                assert!(symbol.bytes.len() >= 2 && symbol.bytes.len() <= max_len);
+                assert_eq!(symbol.token, token);
+                assert!(data.windows(symbol.bytes.len()).any(|w| w == symbol.bytes.as_slice()));
+            }
+        }
+    }
+
+    #[test]
+    fn nothing_to_plan_without_repeats() {
+        assert!(plan(b"", 64, 16).is_empty());
+        assert!(plan(b"a", 64, 16).is_empty());
+        assert!(plan(b"abcdefghijklmnopqrstuvwxyz", 64, 16).is_empty());
+        assert!(plan(b"abababab", 1, 16).is_empty());
+    }
+
+    #[test]
+    fn plan_is_deterministic() {
+        let data = b"alpha beta gamma delta, alpha beta, gamma delta alpha; ".repeat(50);
+        assert_eq!(plan(&data, 32, 50), plan(&data, 32, 50));
+    }
+
+    #[test]
+    fn pattern_interval_counts_occurrences() {
+        let data = b"abracadabra";
+        let sa = suffix_array(data);
+        for pattern in [&b"a"[..], b"abra", b"bra", b"cad", b"zzz", b"abracadabra!"] {
+            let (left, count) = pattern_interval(data, &sa, pattern);
+            let expected = data.windows(pattern.len()).filter(|w| *w == pattern).count();
+            assert_eq!(count, expected, "{:?}", std::str::from_utf8(pattern));
+            for &pos in &sa[left..left + count] {
+                assert!(data[pos as usize..].starts_with(pattern));
+            }
+        }
+    }
 }
@@ -0,0 +1,231 @@
+//! Eviction of symbols that earn too little across the corpus, run before
+//! a dictionary is frozen.
+
+use crate::engine::hash::sha256;
+use crate::storage::{StorageEngine, dictionary::Dictionary, symbols::SymbolStore};
+use crate::utils::limits::{MAX_DICT_ENTRIES, USER_TOKEN_BASE};
+
+// Survivors are renumbered from 256 and must stay below the user range
+const _: () = assert!(MAX_DICT_ENTRIES <= (USER_TOKEN_BASE - 256) as usize);
+
+/// A symbol dropped by `prune_dictionary`
+#[derive(Debug, Clone)]
+pub struct PrunedSymbol {
+    pub token: u32,
+    pub bytes: Vec<u8>,
+    pub bytes_contributed: u64,
+    pub occurrences: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PruneReport {
+    pub symbols_before: usize,
+    pub symbols_after: usize,
+    /// Symbols below the threshold, then the least useful of the rest if
+    /// more than `MAX_DICT_ENTRIES` remained
+    pub evicted: Vec<PrunedSymbol>,
+    pub kept_contribution: u64,
+    pub evicted_contribution: u64,
+    /// Tokens coded with the kept and evicted symbols
+    pub kept_occurrences: u64,
+    pub evicted_occurrences: u64,
+}
+
+/// Stored size of the corpus, and the ratio it would have without the
+/// evicted symbols
+#[derive(Debug, Clone, Default)]
+pub struct RatioEstimate {
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+    pub estimated_compressed_bytes: u64,
+}
+
+impl RatioEstimate {
+    pub fn ratio(&self) -> f64 {
+        ratio(self.original_bytes, self.compressed_bytes)
+    }
+
+    pub fn estimated_ratio(&self) -> f64 {
+        ratio(self.original_bytes, self.estimated_compressed_bytes)
+    }
+}
+
+/// Returns `dict` without the symbols whose `total_bytes_contributed` is
+/// below `min_contribution`, keeping at most `MAX_DICT_ENTRIES`. Survivors
+/// keep their relative order but are renumbered from 256, so the result
+/// must be saved under a new id rather than replace a published generation.
+pub fn prune_dictionary(
+    dict: &Dictionary,
+    symbol_store: &SymbolStore,
+    min_contribution: u64,
+) -> (Dictionary, PruneReport) {
+    prune_to(dict, symbol_store, min_contribution, MAX_DICT_ENTRIES)
+}
+
+fn prune_to(
+    dict: &Dictionary,
+    symbol_store: &SymbolStore,
+    min_contribution: u64,
+    max_entries: usize,
+) -> (Dictionary, PruneReport) {
+    let mut report = PruneReport {
+        symbols_before: dict.decode.len(),
+        ..Default::default()
+    };
+
+    let candidates: Vec<PrunedSymbol> = dict.symbols().into_iter()
+        .map(|(token, bytes)| {
+            let hash = hex::encode(&sha256(bytes)[..16]);
+            let usage = symbol_store.get_corpus_usage(&hash).ok();
+            PrunedSymbol {
+                token,
+                bytes: bytes.to_vec(),
+                bytes_contributed: usage.as_ref().map_or(0, |u| u.total_bytes_contributed),
+                occurrences: usage.as_ref().map_or(0, |u| u.total_occurrences),
+            }
+        })
+        .collect();
+
+    let (mut kept, mut evicted): (Vec<_>, Vec<_>) = candidates.into_iter()
+        .partition(|symbol| symbol.bytes_contributed >= min_contribution);
+
+    if kept.len() > max_entries {
+        kept.sort_by(|a, b| b.bytes_contributed.cmp(&a.bytes_contributed).then(a.token.cmp(&b.token)));
+        evicted.extend(kept.drain(max_entries..));
+        kept.sort_by_key(|symbol| symbol.token);
+    }
+    evicted.sort_by_key(|symbol| symbol.token);
+
+    let mut pruned = Dictionary::new(dict.id.clone());
+    pruned.created_at = dict.created_at;
+    pruned.version = dict.version.clone();
+    for (i, symbol) in kept.iter().enumerate() {
+        pruned.insert_symbol(symbol.bytes.clone(), 256 + i as u32);
+    }
+
+    report.symbols_after = pruned.decode.len();
+    report.kept_contribution = kept.iter().map(|s| s.bytes_contributed).sum();
+    report.kept_occurrences = kept.iter().map(|s| s.occurrences).sum();
+    report.evicted_contribution = evicted.iter().map(|s| s.bytes_contributed).sum();
+    report.evicted_occurrences = evicted.iter().map(|s| s.occurrences).sum();
+    report.evicted = evicted;
+    (pruned, report)
+}
+
+/// Rough effect of a prune on the objects in `storage`. Each evicted
+/// occurrence turns one symbol token into one literal token per byte, and
+/// every token is assumed to cost the corpus average. Back references may
+/// win some of that back, so this leans pessimistic.
+pub async fn estimate_ratio<S: StorageEngine>(storage: &S, report: &PruneReport) -> anyhow::Result<RatioEstimate> {
+    let mut estimate = RatioEstimate::default();
+    let mut literal_bytes = 0u64;
+
+    for key in storage.list("").await? {
+        // Deleted since listing, or unreadable
+        let Ok(Some(meta)) = storage.get_metadata(&key).await else {
+            continue;
+        };
+        estimate.original_bytes += meta.original_size;
+        estimate.compressed_bytes += meta.compressed_size;
+        literal_bytes += meta.token_breakdown.literal_bytes;
+    }
+
+    let tokens = literal_bytes + report.kept_occurrences + report.evicted_occurrences;
+    let extra_tokens = report.evicted_contribution.saturating_sub(report.evicted_occurrences);
+    let extra_bytes = if tokens == 0 {
+        0
+    } else {
+        (extra_tokens as f64 * estimate.compressed_bytes as f64 / tokens as f64).round() as u64
+    };
+    estimate.estimated_compressed_bytes = estimate.compressed_bytes + extra_bytes;
+    Ok(estimate)
+}
+
+fn ratio(original: u64, compressed: u64) -> f64 {
+    if compressed == 0 {
+        0.0
+    } else {
+        original as f64 / compressed as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use crate::storage::{ObjectMetadata, local::LocalStorage};
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("symvea-prune-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    /// A dictionary of `(token, bytes, bytes contributed)`, with the
+    /// contributions recorded in a symbol store under `dir`
+    fn corpus(dir: &Path, symbols: &[(u32, &[u8], u64)]) -> (Dictionary, SymbolStore) {
+        let symbol_store = SymbolStore::new(dir.to_string_lossy().to_string());
+        let mut dict = Dictionary::new("global");
+        for &(token, bytes, contributed) in symbols {
+            dict.insert_symbol(bytes.to_vec(), token);
+            let hash = hex::encode(&sha256(bytes)[..16]);
+            let occurrences = contributed / bytes.len() as u64;
+            symbol_store.add_usage(&hash, "obj", bytes.len() as u64, occurrences).unwrap();
+        }
+        (dict, symbol_store)
+    }
+
+    #[test]
+    fn evicts_below_threshold_and_renumbers_from_256() {
+        let dir = dir("threshold");
+        let (dict, symbol_store) = corpus(&dir, &[(256, b"alpha", 100), (300, b"beta", 8), (400, b"gamma", 50)]);
+        let (pruned, report) = prune_dictionary(&dict, &symbol_store, 10);
+
+        assert_eq!(pruned.symbols(), vec![(256, b"alpha".as_slice()), (257, b"gamma".as_slice())]);
+        assert_eq!(pruned.next_token(), Some(258));
+        assert_eq!((report.symbols_before, report.symbols_after), (3, 2));
+        assert_eq!(report.evicted.iter().map(|s| s.token).collect::<Vec<_>>(), vec![300]);
+        assert_eq!((report.kept_contribution, report.evicted_contribution), (150, 8));
+        assert_eq!((report.kept_occurrences, report.evicted_occurrences), (30, 2));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn caps_entries_by_contribution() {
+        let dir = dir("cap");
+        let (dict, symbol_store) = corpus(&dir, &[(256, b"aa", 40), (257, b"bb", 90), (258, b"cc", 40), (259, b"dd", 60)]);
+        let (pruned, report) = prune_to(&dict, &symbol_store, 0, 3);
+
+        // Ties go to the earlier token; survivors keep their order
+        assert_eq!(pruned.symbols(), vec![(256, b"aa".as_slice()), (257, b"bb".as_slice()), (258, b"dd".as_slice())]);
+        assert_eq!(report.evicted.iter().map(|s| s.token).collect::<Vec<_>>(), vec![258]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn estimates_ratio_from_stored_metadata() {
+        let dir = dir("estimate");
+        let storage = LocalStorage::new(dir.clone());
+        for (key, compressed) in [("a", 300), ("b", 200)] {
+            let mut meta = ObjectMetadata::new(key.to_string(), [0; 32], [0; 32], "dict".to_string(), 1000, compressed, None);
+            meta.token_breakdown.literal_bytes = 100;
+            storage.put(key, b"", &meta).await.unwrap();
+        }
+        let report = PruneReport {
+            kept_occurrences: 200,
+            evicted_occurrences: 100,
+            evicted_contribution: 500,
+            ..Default::default()
+        };
+
+        // 400 more tokens at the corpus average of one byte each
+        let estimate = estimate_ratio(&storage, &report).await.unwrap();
+        assert_eq!((estimate.original_bytes, estimate.compressed_bytes), (2000, 500));
+        assert_eq!(estimate.estimated_compressed_bytes, 900);
+        assert_eq!(estimate.ratio(), 4.0);
+
+        let empty = estimate_ratio(&LocalStorage::new(dir.join("none")), &report).await.unwrap();
+        assert_eq!((empty.estimated_compressed_bytes, empty.ratio()), (0, 0.0));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use crate::engine::entropy::EntropyError;
+use crate::utils::varint::{encode_varint, decode_varint};
+
+/// Lower bound of the coder state; renormalizing a byte at a time keeps it
+/// in `[RANS_L, RANS_L << 8)`
+const RANS_L: u32 = 1 << 23;
+
+/// Frequencies are scaled to sum to `1 << prob_bits`
+const MIN_PROB_BITS: u8 = 14;
+
+/// Largest scale the 32-bit state supports with byte-wise renormalization
+const MAX_PROB_BITS: u8 = 16;
+
+pub struct RansTable {
+    prob_bits: u8,
+    /// `(token, normalized frequency)` sorted by token; all that is serialized
+    freqs: Vec<(u32, u32)>,
+    /// Start of each token's slot range, parallel to `freqs`
+    cum: Vec<u32>,
+}
+
+impl RansTable {
+    /// Models `tokens`. Returns `None` if the alphabet is too large for the
+    /// frequency scale.
+    pub fn build(tokens: &[u32]) -> Option<Self> {
+        let mut freq_map = HashMap::new();
+        for &token in tokens {
+            *freq_map.entry(token).or_insert(0u64) += 1;
+        }
+
+        let mut counts: Vec<(u32, u64)> = freq_map.into_iter().collect();
+        counts.sort_unstable();
+        if counts.is_empty() {
+            return None;
+        }
+
+        // Leave every token room to be scaled well above a single slot
+        let needed = (usize::BITS - (counts.len() * 2).leading_zeros()) as u8;
+        let prob_bits = needed.max(MIN_PROB_BITS);
+        if prob_bits > MAX_PROB_BITS {
+            return None;
+        }
+
+        let freqs = normalize(&counts, 1 << prob_bits);
+        Self::from_freqs(prob_bits, freqs)
+    }
+
+    /// Rebuilds a table from normalized frequencies, which must be non-zero,
+    /// sorted by token and sum to exactly `1 << prob_bits`.
+    pub fn from_freqs(prob_bits: u8, freqs: Vec<(u32, u32)>) -> Option<Self> {
+        if !(1..=MAX_PROB_BITS).contains(&prob_bits) {
+            return None;
+        }
+        if freqs.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return None;
+        }
+
+        let mut cum = Vec::with_capacity(freqs.len());
+        let mut total = 0u32;
+        for &(_, freq) in &freqs {
+            if freq == 0 {
+                return None;
+            }
+            cum.push(total);
+            total = total.checked_add(freq)?;
+        }
+        if total != 1 << prob_bits {
+            return None;
+        }
+
+        Some(Self { prob_bits, freqs, cum })
+    }
+
+    /// Serialized table: scale bits, entry count, then per entry the token
+    /// as a varint delta from the previous token and its frequency.
+    pub fn write_table(&self, out: &mut Vec<u8>) {
+        out.push(self.prob_bits);
+        out.extend_from_slice(&(self.freqs.len() as u32).to_be_bytes());
+
+        let mut prev_token = 0u32;
+        for &(token, freq) in &self.freqs {
+            encode_varint((token - prev_token) as u64, out);
+            encode_varint(freq as u64, out);
+            prev_token = token;
+        }
+    }
+
+    /// Parses a table written by `write_table`, returning it and the number
+    /// of bytes consumed.
+    pub fn read_table(data: &[u8]) -> Option<(Self, usize)> {
+        let prob_bits = *data.first()?;
+        let count = u32::from_be_bytes(data.get(1..5)?.try_into().ok()?) as usize;
+        let mut offset = 5;
+
+        // Every entry takes at least two bytes
+        if count > data.len().saturating_sub(offset) / 2 {
+            return None;
+        }
+
+        let mut freqs = Vec::with_capacity(count);
+        let mut token = 0u64;
+        for _ in 0..count {
+            let (delta, used) = decode_varint(&data[offset..])?;
+            offset += used;
+            token += delta;
+            let (freq, used) = decode_varint(&data[offset..])?;
+            offset += used;
+            freqs.push((u32::try_from(token).ok()?, u32::try_from(freq).ok()?));
+        }
+
+        Some((Self::from_freqs(prob_bits, freqs)?, offset))
+    }
+
+    /// Appends the coded `tokens`, all of which must be in the table
+    pub fn encode(&self, tokens: &[u32], out: &mut Vec<u8>) {
+        let index: HashMap<u32, usize> = self.freqs.iter()
+            .enumerate()
+            .map(|(i, &(token, _))| (token, i))
+            .collect();
+
+        // rANS is last-in first-out: encode backwards and reverse the bytes
+        // so the decoder reads forwards
+        let mut bytes = Vec::with_capacity(tokens.len() / 2 + 4);
+        let mut x = RANS_L;
+        for token in tokens.iter().rev() {
+            let i = index[token];
+            let freq = self.freqs[i].1;
+            let x_max = ((RANS_L >> self.prob_bits) << 8) * freq;
+            while x >= x_max {
+                bytes.push(x as u8);
+                x >>= 8;
+            }
+            x = ((x / freq) << self.prob_bits) + (x % freq) + self.cum[i];
+        }
+
+        bytes.extend_from_slice(&x.to_le_bytes());
+        bytes.reverse();
+        out.extend(bytes);
+    }
+
+    pub fn decode(&self, data: &[u8], count: usize) -> Result<Vec<u32>, EntropyError> {
+        let mask = (1u32 << self.prob_bits) - 1;
+        let mut slots = vec![0u16; 1 << self.prob_bits];
+        for (i, (&start, &(_, freq))) in self.cum.iter().zip(&self.freqs).enumerate() {
+            slots[start as usize..(start + freq) as usize].fill(i as u16);
+        }
+
+        let mut x = u32::from_be_bytes(data.get(..4).ok_or(EntropyError::Truncated)?.try_into().unwrap());
+        let mut pos = 4;
+        let mut tokens = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let slot = x & mask;
+            let i = slots[slot as usize] as usize;
+            let (token, freq) = self.freqs[i];
+            tokens.push(token);
+
+            x = freq * (x >> self.prob_bits) + slot - self.cum[i];
+            while x < RANS_L {
+                let byte = *data.get(pos).ok_or(EntropyError::Truncated)?;
+                x = (x << 8) | byte as u32;
+                pos += 1;
+            }
+        }
+
+        // The encoder started from RANS_L, so a clean stream ends there
+        if x != RANS_L || pos != data.len() {
+            return Err(EntropyError::InvalidCode);
+        }
+        Ok(tokens)
+    }
+}
+
+/// Scales counts to sum to `total`, keeping every token at least one slot.
+/// `counts` must be sorted by token and hold fewer than `total / 2` entries.
+fn normalize(counts: &[(u32, u64)], total: u32) -> Vec<(u32, u32)> {
+    let sum: u64 = counts.iter().map(|&(_, c)| c).sum();
+    let mut freqs: Vec<(u32, u32)> = counts.iter()
+        .map(|&(token, c)| (token, ((c * total as u64 / sum) as u32).max(1)))
+        .collect();
+
+    let assigned: u32 = freqs.iter().map(|&(_, f)| f).sum();
+    if assigned < total {
+        // Rounding down lost slots; the most frequent token absorbs them
+        let top = (0..freqs.len()).max_by_key(|&i| (freqs[i].1, std::cmp::Reverse(i))).unwrap();
+        freqs[top].1 += total - assigned;
+    } else {
+        // Slots given to rare tokens are taken back from the most frequent
+        let mut excess = assigned - total;
+        let mut order: Vec<usize> = (0..freqs.len()).collect();
+        order.sort_by_key(|&i| (std::cmp::Reverse(freqs[i].1), i));
+        while excess > 0 {
+            for &i in &order {
+                if excess == 0 {
+                    break;
+                }
+                if freqs[i].1 > 1 {
+                    freqs[i].1 -= 1;
+                    excess -= 1;
+                }
+            }
+        }
+    }
+
+    freqs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(tokens: &[u32]) {
+        let table = RansTable::build(tokens).unwrap();
+        let mut out = Vec::new();
+        table.write_table(&mut out);
+        let (read, used) = RansTable::read_table(&out).unwrap();
+        assert_eq!(used, out.len());
+        assert_eq!(read.freqs, table.freqs);
+
+        let mut stream = Vec::new();
+        table.encode(tokens, &mut stream);
+        assert_eq!(read.decode(&stream, tokens.len()).unwrap(), tokens);
+    }
+
+    fn skewed(len: usize) -> Vec<u32> {
+        let mut state = 0x2545_F491u32;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            // Mostly small tokens, with a long tail
+            (state % 1000).min(state % 7) + if state.is_multiple_of(97) { 70_000 } else { 0 }
+        }).collect()
+    }
+
+    #[test]
+    fn roundtrips() {
+        roundtrip(&[5]);
+        roundtrip(&[5; 1000]);
+        roundtrip(&[1, 2, 3, 1, 2, 1]);
+        roundtrip(&skewed(20_000));
+        roundtrip(&(0..5000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn frequencies_fill_the_scale() {
+        let tokens = skewed(5000);
+        let table = RansTable::build(&tokens).unwrap();
+        let total: u32 = table.freqs.iter().map(|&(_, f)| f).sum();
+        assert_eq!(total, 1 << table.prob_bits);
+        assert!(table.freqs.iter().all(|&(_, f)| f > 0));
+    }
+
+    #[test]
+    fn alphabet_too_large_for_scale() {
+        assert!(RansTable::build(&[]).is_none());
+        assert!(RansTable::build(&(0..1 << 15).collect::<Vec<_>>()).is_none());
+        assert!(RansTable::build(&(0..(1 << 15) - 1).collect::<Vec<_>>()).is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_frequencies() {
+        assert!(RansTable::from_freqs(1, vec![(0, 1), (1, 1)]).is_some());
+        // Wrong sum, zero frequency, unsorted or repeated tokens
+        assert!(RansTable::from_freqs(2, vec![(0, 1), (1, 1)]).is_none());
+        assert!(RansTable::from_freqs(1, vec![(0, 2), (1, 0)]).is_none());
+        assert!(RansTable::from_freqs(1, vec![(1, 1), (0, 1)]).is_none());
+        assert!(RansTable::from_freqs(1, vec![(0, 1), (0, 1)]).is_none());
+        // Scale outside what the state supports
+        assert!(RansTable::from_freqs(0, vec![(0, 1)]).is_none());
+        assert!(RansTable::from_freqs(MAX_PROB_BITS + 1, vec![(0, 1 << (MAX_PROB_BITS + 1))]).is_none());
+        // Sum overflowing u32
+        assert!(RansTable::from_freqs(16, vec![(0, u32::MAX), (1, 2)]).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_table() {
+        let table = RansTable::build(&skewed(2000)).unwrap();
+        let mut out = Vec::new();
+        table.write_table(&mut out);
+        for len in 0..out.len() {
+            assert!(RansTable::read_table(&out[..len]).is_none(), "accepted {} bytes", len);
+        }
+
+        // Entry count larger than the data could hold
+        out[1..5].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(RansTable::read_table(&out).is_none());
+    }
+
+    #[test]
+    fn rejects_damaged_stream() {
+        let tokens = skewed(2000);
+        let table = RansTable::build(&tokens).unwrap();
+        let mut stream = Vec::new();
+        table.encode(&tokens, &mut stream);
+
+        assert!(matches!(table.decode(&stream[..3], tokens.len()), Err(EntropyError::Truncated)));
+        assert!(table.decode(&stream[..stream.len() - 1], tokens.len()).is_err());
+        assert!(table.decode(&stream, tokens.len() - 1).is_err());
+
+        let mut extra = stream.clone();
+        extra.push(0);
+        assert!(table.decode(&extra, tokens.len()).is_err());
+
+        let mut flipped = stream.clone();
+        flipped[0] ^= 0x40;
+        assert!(table.decode(&flipped, tokens.len()).map_or(true, |decoded| decoded != tokens));
+    }
+}
@@ -0,0 +1,243 @@
+//! Read/Write driver for decoding the block container. Encoding streams
+//! through `BlockEncoder`, which chunked uploads push into as they arrive.
+
+use std::io::{self, Read, Write};
+use sha2::{Sha256, Digest};
+
+use crate::engine::{
+    block::{BLOCK_HEADER_LEN, read_block_header, decode_block},
+    codec::codec_by_id,
+    container::{self, ContainerHeader, has_index, CODEC_V3, CODEC_VERSION, INDEX_ENTRY_LEN, MAGIC, TRAILER_LEN},
+    decompressor::{decompress, decompress_object, check_dictionary},
+    error::DecompressError,
+};
+use crate::storage::{dictionary::Dictionary, metadata::ObjectMetadata};
+
+/// Magic, codec version and dict id length: enough to tell the format apart
+const PREFIX_PROBE: usize = MAGIC.len() + 2 + 1;
+
+/// Decompresses a blob from `reader` into `writer`, returning the number of
+/// bytes written. Block containers are decoded one block at a time; older
+/// single-stream formats are read whole and decoded in memory. The hash in
+/// the trailer is only checked at the end, after every block was written.
+pub fn decompress_stream<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    dict: &Dictionary,
+) -> Result<u64, DecompressError> {
+    let mut prefix = vec![0u8; PREFIX_PROBE];
+    let probed = read_up_to(&mut reader, &mut prefix)?;
+    prefix.truncate(probed);
+
+    if !is_block_container(&prefix) {
+        reader.read_to_end(&mut prefix)?;
+        let out = decompress(&prefix, dict)?;
+        writer.write_all(&out)?;
+        return Ok(out.len() as u64);
+    }
+
+    let mut dict_id = vec![0u8; prefix[PREFIX_PROBE - 1] as usize];
+    reader.read_exact(&mut dict_id)?;
+    prefix.extend(dict_id);
+    let codec_version = check_prefix(&prefix, dict)?;
+
+    let mut check = StreamCheck::new();
+    loop {
+        let mut end = [0u8; 4];
+        reader.read_exact(&mut end)?;
+        if u32::from_be_bytes(end) == 0 {
+            if has_index(codec_version) {
+                let mut index = vec![0u8; check.index_len()];
+                reader.read_exact(&mut index)?;
+                check.check_index(&index)?;
+            }
+            let mut trailer = [0u8; TRAILER_LEN];
+            reader.read_exact(&mut trailer)?;
+            return check.finish(&trailer);
+        }
+
+        let mut header = [0u8; BLOCK_HEADER_LEN];
+        header[..4].copy_from_slice(&end);
+        reader.read_exact(&mut header[4..])?;
+        let (raw_len, payload_len) = read_block_header(&header)?;
+
+        let mut payload = vec![0u8; payload_len];
+        reader.read_exact(&mut payload)?;
+        let block = decode_block(&payload, raw_len, dict, codec_version)?;
+
+        check.update(&block);
+        writer.write_all(&block)?;
+    }
+}
+
+/// `decompress_object` into `writer`: block containers are decoded a block
+/// at a time, objects from before them in memory. Returns the number of
+/// bytes written.
+pub fn decompress_object_stream<W: Write>(
+    data: &[u8],
+    meta: &ObjectMetadata,
+    dict: &Dictionary,
+    mut writer: W,
+) -> Result<u64, DecompressError> {
+    if meta.codec_version < CODEC_V3 {
+        let out = decompress_object(data, meta, dict)?;
+        writer.write_all(&out)?;
+        return Ok(out.len() as u64);
+    }
+    if !container::is_supported(meta.codec_version) {
+        return Err(DecompressError::UnsupportedVersion(meta.codec_version));
+    }
+    codec_by_id(&meta.codec).ok_or_else(|| DecompressError::UnknownCodec(meta.codec.clone()))?;
+
+    let written = decompress_stream(data, writer, dict)?;
+    if written != meta.original_size {
+        return Err(DecompressError::LengthMismatch {
+            expected: meta.original_size,
+            actual: written,
+        });
+    }
+    Ok(written)
+}
+
+fn is_block_container(prefix: &[u8]) -> bool {
+    prefix.len() == PREFIX_PROBE
+        && prefix.starts_with(&MAGIC)
+        && (CODEC_V3..=CODEC_VERSION).contains(&u16::from_be_bytes([prefix[4], prefix[5]]))
+}
+
+fn check_prefix(prefix: &[u8], dict: &Dictionary) -> Result<u16, DecompressError> {
+    let Some((codec_version, dict_id, _)) = ContainerHeader::read_prefix(prefix)? else {
+        return Err(DecompressError::TruncatedHeader);
+    };
+    check_dictionary(&dict_id, dict)?;
+    Ok(codec_version)
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Running size and hash of decoded output, checked against the index and
+/// trailer
+struct StreamCheck {
+    hasher: Sha256,
+    size: u64,
+    blocks: usize,
+}
+
+impl StreamCheck {
+    fn new() -> Self {
+        Self {
+            hasher: Sha256::new(),
+            size: 0,
+            blocks: 0,
+        }
+    }
+
+    fn update(&mut self, block: &[u8]) {
+        self.hasher.update(block);
+        self.size += block.len() as u64;
+        self.blocks += 1;
+    }
+
+    /// Index entries plus their count, for the blocks seen so far
+    fn index_len(&self) -> usize {
+        self.blocks * INDEX_ENTRY_LEN + 4
+    }
+
+    fn check_index(&self, index: &[u8]) -> Result<(), DecompressError> {
+        let (_, count) = index.split_last_chunk::<4>().ok_or(DecompressError::TruncatedBlock)?;
+        if u32::from_be_bytes(*count) as usize != self.blocks {
+            return Err(DecompressError::TruncatedBlock);
+        }
+        Ok(())
+    }
+
+    fn finish(self, trailer: &[u8; TRAILER_LEN]) -> Result<u64, DecompressError> {
+        let (original_size, content_hash) = ContainerHeader::read_trailer(trailer);
+        if self.size != original_size {
+            return Err(DecompressError::LengthMismatch {
+                expected: original_size,
+                actual: self.size,
+            });
+        }
+        if <[u8; 32]>::from(self.hasher.finalize()) != content_hash {
+            return Err(DecompressError::ChecksumMismatch);
+        }
+        Ok(self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{block::{BlockEncoder, BLOCK_SIZE}, decompressor::dictionary_id, entropy::EntropyCoderKind};
+
+    fn encode(data: &[u8], dict: &Dictionary) -> Vec<u8> {
+        let mut encoder = BlockEncoder::new(dict, EntropyCoderKind::Huffman);
+        encoder.push(data);
+        let mut blob = encoder.take_output();
+        blob.extend(encoder.finish().0);
+        blob
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(len);
+        for i in 0.. {
+            if out.len() >= len {
+                break;
+            }
+            out.extend_from_slice(format!("line {} of {} ", i, i % 13).as_bytes());
+        }
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn decodes_block_by_block() {
+        let mut dict = Dictionary::new("test");
+        dict.insert_symbol(b"line ".to_vec(), 256);
+        let data = sample(BLOCK_SIZE * 2 + 77);
+        let blob = encode(&data, &dict);
+
+        let mut out = Vec::new();
+        assert_eq!(decompress_stream(&blob[..], &mut out, &dict).unwrap(), data.len() as u64);
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn rejects_block_lengths_before_allocating() {
+        let dict = Dictionary::new("test");
+        let blob = encode(&sample(1000), &dict);
+        let header = ContainerHeader::prefix_len(&dictionary_id(&dict));
+
+        for (field, len) in [(0, BLOCK_SIZE as u32 + 1), (4, u32::MAX)] {
+            let mut corrupt = blob.clone();
+            corrupt[header + field..header + field + 4].copy_from_slice(&len.to_be_bytes());
+            assert!(matches!(
+                decompress_stream(&corrupt[..], io::sink(), &dict),
+                Err(DecompressError::TruncatedBlock)
+            ));
+        }
+    }
+
+    #[test]
+    fn checks_hash_after_last_block() {
+        let dict = Dictionary::new("test");
+        let mut blob = encode(&sample(1000), &dict);
+        *blob.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decompress_stream(&blob[..], io::sink(), &dict),
+            Err(DecompressError::ChecksumMismatch)
+        ));
+    }
+}
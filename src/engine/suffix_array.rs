@@ -0,0 +1,119 @@
+/// Suffix array by prefix doubling: suffixes are sorted on rank pairs of
+/// length `k`, then `2k`, until every rank is distinct.
+pub fn suffix_array(data: &[u8]) -> Vec<u32> {
+    let n = data.len();
+    let mut sa: Vec<u32> = (0..n as u32).collect();
+    if n < 2 {
+        return sa;
+    }
+
+    let mut rank: Vec<u32> = data.iter().map(|&b| b as u32).collect();
+    let mut next_rank = vec![0u32; n];
+    let mut k = 1;
+
+    loop {
+        // Rank 0 is reserved for "past the end" so shorter suffixes sort first
+        let key = |i: u32| {
+            let i = i as usize;
+            let second = if i + k < n { rank[i + k] + 1 } else { 0 };
+            ((rank[i] as u64) << 32) | second as u64
+        };
+
+        sa.sort_unstable_by_key(|&i| key(i));
+
+        next_rank[sa[0] as usize] = 0;
+        for w in 1..n {
+            let bump = (key(sa[w]) != key(sa[w - 1])) as u32;
+            next_rank[sa[w] as usize] = next_rank[sa[w - 1] as usize] + bump;
+        }
+        std::mem::swap(&mut rank, &mut next_rank);
+
+        if rank[sa[n - 1] as usize] as usize == n - 1 {
+            break;
+        }
+        k *= 2;
+    }
+
+    sa
+}
+
+/// Kasai's algorithm: `lcp[i]` is the longest common prefix of the suffixes
+/// at `sa[i - 1]` and `sa[i]`, with `lcp[0] = 0`.
+pub fn lcp_array(data: &[u8], sa: &[u32]) -> Vec<u32> {
+    let n = data.len();
+    let mut lcp = vec![0u32; n];
+    let mut inverse = vec![0usize; n];
+    for (i, &pos) in sa.iter().enumerate() {
+        inverse[pos as usize] = i;
+    }
+
+    let mut h = 0usize;
+    for pos in 0..n {
+        let idx = inverse[pos];
+        if idx == 0 {
+            h = 0;
+            continue;
+        }
+        let prev = sa[idx - 1] as usize;
+        while pos + h < n && prev + h < n && data[pos + h] == data[prev + h] {
+            h += 1;
+        }
+        lcp[idx] = h as u32;
+        h = h.saturating_sub(1);
+    }
+
+    lcp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive_suffix_array(data: &[u8]) -> Vec<u32> {
+        let mut sa: Vec<u32> = (0..data.len() as u32).collect();
+        sa.sort_by_key(|&i| &data[i as usize..]);
+        sa
+    }
+
+    fn inputs() -> Vec<Vec<u8>> {
+        let mut state = 0x1234_5678u32;
+        let noise: Vec<u8> = (0..500).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            b'a' + (state % 3) as u8
+        }).collect();
+        vec![
+            Vec::new(),
+            b"x".to_vec(),
+            b"banana".to_vec(),
+            b"mississippi".to_vec(),
+            vec![b'a'; 64],
+            b"abcabcabcabd".to_vec(),
+            (0..=255u8).rev().collect(),
+            noise,
+        ]
+    }
+
+    #[test]
+    fn matches_naive_sort() {
+        for data in inputs() {
+            assert_eq!(suffix_array(&data), naive_suffix_array(&data), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn lcp_matches_naive() {
+        for data in inputs() {
+            let sa = suffix_array(&data);
+            let lcp = lcp_array(&data, &sa);
+            assert_eq!(lcp.len(), data.len());
+            for i in 1..sa.len() {
+                let a = &data[sa[i - 1] as usize..];
+                let b = &data[sa[i] as usize..];
+                let expected = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+                assert_eq!(lcp[i] as usize, expected);
+            }
+        }
+    }
+}
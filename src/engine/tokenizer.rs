@@ -1,27 +1,129 @@
-use crate::engine::symbols::Symbol;
+const NO_NODE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    // Sorted by byte so lookups can binary search
+    children: Vec<(u8, u32)>,
+    token: Option<u32>,
+}
+
+/// Compiled byte trie over a symbol set.
+///
+/// Built once per dictionary; each input position is matched by walking at
+/// most `max_len` bytes, so tokenizing is linear in the input size no matter
+/// how many symbols the dictionary holds.
+#[derive(Debug, Clone)]
+pub struct SymbolMatcher {
+    // Dense transitions out of the root, the hottest node by far
+    root: Box<[u32; 256]>,
+    root_tokens: Box<[Option<u32>; 256]>,
+    nodes: Vec<TrieNode>,
+    max_len: usize,
+}
+
+impl SymbolMatcher {
+    pub fn new<'a, I>(symbols: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a [u8], u32)>,
+    {
+        let mut matcher = Self {
+            root: Box::new([NO_NODE; 256]),
+            root_tokens: Box::new([None; 256]),
+            nodes: Vec::new(),
+            max_len: 0,
+        };
+
+        for (bytes, token) in symbols {
+            matcher.insert(bytes, token);
+        }
+
+        matcher
+    }
+
+    fn insert(&mut self, bytes: &[u8], token: u32) {
+        let Some((&first, rest)) = bytes.split_first() else {
+            return;
+        };
+
+        self.max_len = self.max_len.max(bytes.len());
+
+        if rest.is_empty() {
+            self.root_tokens[first as usize] = Some(token);
+            return;
+        }
+
+        let mut node = self.root[first as usize];
+        if node == NO_NODE {
+            node = self.push_node();
+            self.root[first as usize] = node;
+        }
+
+        for &byte in rest {
+            let children = &self.nodes[node as usize].children;
+            node = match children.binary_search_by_key(&byte, |&(b, _)| b) {
+                Ok(idx) => children[idx].1,
+                Err(idx) => {
+                    let child = self.push_node();
+                    self.nodes[node as usize].children.insert(idx, (byte, child));
+                    child
+                }
+            };
+        }
+
+        self.nodes[node as usize].token = Some(token);
+    }
+
+    fn push_node(&mut self) -> u32 {
+        self.nodes.push(TrieNode::default());
+        (self.nodes.len() - 1) as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max_len == 0
+    }
+
+    /// Longest symbol that is a prefix of `input`, as `(token, len)`.
+    pub fn longest_match(&self, input: &[u8]) -> Option<(u32, usize)> {
+        let (&first, rest) = input.split_first()?;
+
+        let mut best = self.root_tokens[first as usize].map(|t| (t, 1));
+        let mut node = self.root[first as usize];
+
+        for (i, &byte) in rest.iter().enumerate() {
+            if node == NO_NODE {
+                break;
+            }
+            let children = &self.nodes[node as usize].children;
+            node = match children.binary_search_by_key(&byte, |&(b, _)| b) {
+                Ok(idx) => children[idx].1,
+                Err(_) => break,
+            };
+            if let Some(token) = self.nodes[node as usize].token {
+                best = Some((token, i + 2));
+            }
+        }
+
+        best
+    }
+}
 
 pub fn tokenize(
     input: &[u8],
-    symbols: &[Symbol],
+    matcher: &SymbolMatcher,
 ) -> Vec<u32> {
-    let mut out = Vec::new();
+    let mut out = Vec::with_capacity(input.len() / 2);
     let mut i = 0;
 
-    while i < input.len() {
-        let mut best_match = None;
-        let mut best_len = 0;
+    if matcher.is_empty() {
+        out.extend(input.iter().map(|&b| b as u32));
+        return out;
+    }
 
+    while i < input.len() {
         // Find the longest matching symbol
-        for s in symbols {
-            if input[i..].starts_with(&s.bytes) && s.bytes.len() > best_len {
-                best_match = Some(s.token);
-                best_len = s.bytes.len();
-            }
-        }
-
-        if let Some(token) = best_match {
+        if let Some((token, len)) = matcher.longest_match(&input[i..]) {
             out.push(token);
-            i += best_len;
+            i += len;
         } else {
             out.push(input[i] as u32);
             i += 1;
@@ -0,0 +1,186 @@
+//! Offline dictionary training from a directory of sample files.
+
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::engine::{
+    config::EngineConfig,
+    estimate::incompressible_reason,
+    planner::plan_symbols_limited,
+};
+use crate::storage::dictionary::Dictionary;
+
+/// Bytes of sample handed to the planner, which plans from all of it
+pub const TRAINING_SAMPLE: usize = 1024 * 1024;
+
+/// What went into a trained dictionary
+#[derive(Debug, Clone, Default)]
+pub struct TrainingReport {
+    pub files_sampled: usize,
+    /// Files left out because they are already compressed or random
+    pub files_skipped: usize,
+    pub sample_bytes: usize,
+    pub symbols: usize,
+}
+
+/// Plans up to `max_symbols` symbols from the files under `dir` and
+/// returns the frozen dictionary. Every file contributes a leading slice of
+/// the sample, so a few large files cannot crowd out the rest.
+pub fn train_dictionary(
+    dir: &Path,
+    max_symbols: usize,
+    config: &EngineConfig,
+) -> io::Result<(Dictionary, TrainingReport)> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    // Walk order is filesystem dependent; sorting keeps training repeatable
+    files.sort();
+
+    let (sample, mut report) = build_sample(&files)?;
+    let symbols = plan_symbols_limited(&sample, config.max_symbol_len, max_symbols, config.min_gain_bytes);
+
+    let mut dict = Dictionary::new("global");
+    for symbol in symbols {
+        dict.insert_symbol(symbol.bytes, symbol.token);
+    }
+    dict.freeze();
+
+    report.sample_bytes = sample.len();
+    report.symbols = dict.encode.len();
+    Ok((dict, report))
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<(PathBuf, u64)>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            let len = entry.metadata()?.len();
+            if len > 0 {
+                files.push((entry.path(), len));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Concatenates a leading slice of each file, splitting `TRAINING_SAMPLE`
+/// evenly and handing the share of short files on to longer ones
+fn build_sample(files: &[(PathBuf, u64)]) -> io::Result<(Vec<u8>, TrainingReport)> {
+    let mut by_len: Vec<usize> = (0..files.len()).collect();
+    by_len.sort_by_key(|&i| files[i].1);
+
+    let mut shares = vec![0usize; files.len()];
+    let mut budget = TRAINING_SAMPLE;
+    for (done, &i) in by_len.iter().enumerate() {
+        let share = budget / (files.len() - done);
+        shares[i] = share.min(files[i].1 as usize);
+        budget -= shares[i];
+    }
+
+    let mut report = TrainingReport::default();
+    let mut sample = Vec::with_capacity(TRAINING_SAMPLE);
+    for ((path, _), &share) in files.iter().zip(&shares) {
+        if share == 0 {
+            continue;
+        }
+        let mut slice = Vec::with_capacity(share);
+        std::fs::File::open(path)?.take(share as u64).read_to_end(&mut slice)?;
+
+        if incompressible_reason(&slice).is_some() {
+            report.files_skipped += 1;
+            continue;
+        }
+        report.files_sampled += 1;
+        sample.extend(slice);
+    }
+
+    Ok((sample, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("symvea-train-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        dir
+    }
+
+    fn text(len: usize, seed: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(len);
+        let mut i = seed;
+        while out.len() < len {
+            out.extend(format!("level=info service=api request_id={} status=200 path=/v1/items\n", i).bytes());
+            i += 1;
+        }
+        out.truncate(len);
+        out
+    }
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    #[test]
+    fn training_is_repeatable_and_frozen_within_size() {
+        let dir = dir("repeat");
+        std::fs::write(dir.join("a.log"), text(20_000, 0)).unwrap();
+        std::fs::write(dir.join("nested").join("b.log"), text(30_000, 7)).unwrap();
+
+        let config = EngineConfig::default();
+        let (first, report) = train_dictionary(&dir, 8, &config).unwrap();
+        let (second, _) = train_dictionary(&dir, 8, &config).unwrap();
+        assert!(first.frozen);
+        assert!(report.symbols > 0 && report.symbols <= 8);
+        assert_eq!(report.symbols, first.encode.len());
+        // Ids also cover the freeze time; the symbols are what must repeat
+        assert_eq!(first.generation_id(), second.generation_id());
+        assert_eq!(first.symbols(), second.symbols());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn incompressible_files_are_skipped() {
+        let dir = dir("skip");
+        std::fs::write(dir.join("text.log"), text(20_000, 0)).unwrap();
+        std::fs::write(dir.join("random.bin"), random(20_000)).unwrap();
+        std::fs::write(dir.join("empty"), b"").unwrap();
+
+        let (_, report) = train_dictionary(&dir, 8, &EngineConfig::default()).unwrap();
+        assert_eq!((report.files_sampled, report.files_skipped), (1, 1));
+        assert_eq!(report.sample_bytes, 20_000);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn short_files_hand_their_share_on() {
+        let dir = dir("shares");
+        let files: Vec<(PathBuf, u64)> = [100, TRAINING_SAMPLE, 2 * TRAINING_SAMPLE].iter().enumerate()
+            .map(|(i, &len)| {
+                let path = dir.join(format!("{}.log", i));
+                std::fs::write(&path, text(len, i)).unwrap();
+                (path, len as u64)
+            })
+            .collect();
+
+        let (sample, report) = build_sample(&files).unwrap();
+        assert_eq!(sample.len(), TRAINING_SAMPLE);
+        assert_eq!(report.files_sampled, 3);
+        let share = (TRAINING_SAMPLE - 100) / 2;
+        assert_eq!(&sample[..100], &text(100, 0)[..]);
+        assert_eq!(&sample[100..100 + share], &text(share, 1)[..]);
+        assert_eq!(&sample[100 + share..], &text(TRAINING_SAMPLE - 100 - share, 2)[..]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -11,6 +11,8 @@ mod utils;
 mod engine;
 mod coordination;
 mod metrics;
+mod recompress;
+mod dedup;
 
 use tracing::info;
 use startup::StartupValidator;
@@ -52,10 +54,28 @@ enum Commands {
     },
     ListSymbols,
     FreezeDictionary,
+    Recompress {
+        #[arg(long, help = "Frozen dictionary to move objects onto")]
+        to_dict: String,
+        #[arg(long, help = "Only objects whose key starts with this")]
+        prefix: Option<String>,
+    },
+    TrainDictionary {
+        #[arg(long, help = "Directory of sample files")]
+        input: String,
+        #[arg(long, default_value_t = 1000, help = "Maximum number of symbols")]
+        size: usize,
+        #[arg(long, help = "Output file (default: <data>/dictionary_<id>.json)")]
+        output: Option<String>,
+    },
     Symbol {
         #[command(subcommand)]
         symbol_cmd: SymbolCommands,
     },
+    Dict {
+        #[command(subcommand)]
+        dict_cmd: DictCommands,
+    },
     Analytics,
     Proof,
     Test,
@@ -74,6 +94,22 @@ enum SymbolCommands {
     ListStability,
 }
 
+#[derive(clap::Subcommand)]
+enum DictCommands {
+    List,
+    Show { id: String },
+    Diff { a: String, b: String },
+    /// Make a frozen global dictionary the one the server starts with
+    Activate { id: String },
+    Prune {
+        id: String,
+        #[arg(long, help = "Minimum bytes a symbol must have contributed (default: engine setting)")]
+        min_contribution: Option<u64>,
+        #[arg(long, help = "Report what would be evicted without writing anything")]
+        dry_run: bool,
+    },
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt()
@@ -154,8 +190,8 @@ async fn main() -> anyhow::Result<()> {
         Some(Commands::VerifyCorpus) => {
             match StartupValidator::new(&data_dir) {
                 Ok(validator) => {
-                    match validator.validate_and_start() {
-                        Ok(_) => {
+                    match validator.validate_and_start().and_then(|_| validator.verify_objects()) {
+                        Ok(failures) if failures.is_empty() => {
                             if cli.json {
                                 println!("{}", serde_json::json!({"status": "passed"}));
                             } else {
@@ -164,6 +200,24 @@ async fn main() -> anyhow::Result<()> {
                                 println!("✅ Corpus verification PASSED");
                             }
                         }
+                        Ok(failures) => {
+                            if cli.json {
+                                let objects: Vec<_> = failures.iter().map(|f| serde_json::json!({
+                                    "key": f.key,
+                                    "kind": f.error.kind(),
+                                    "error": f.error.to_string(),
+                                })).collect();
+                                println!("{}", serde_json::json!({"status": "failed", "objects": objects}));
+                            } else {
+                                println!("🔍 Verifying Corpus Integrity");
+                                println!("=============================");
+                                for f in &failures {
+                                    println!("❌ {}: {}", f.key, f.error);
+                                }
+                                println!("❌ Corpus verification FAILED: {} objects could not be decoded", failures.len());
+                            }
+                            return Err(anyhow::anyhow!("{} objects could not be decoded", failures.len()));
+                        }
                         Err(e) => {
                             if cli.json {
                                 println!("{}", serde_json::json!({"error": e.to_string()}));
@@ -352,12 +406,156 @@ async fn main() -> anyhow::Result<()> {
             return Ok(());
         }
         Some(Commands::FreezeDictionary) => {
-            if cli.json {
-                println!("{}", serde_json::json!({"message": "Use client command: symvea-client freeze-dictionary"}));
-            } else {
-                println!("🧊 Freezing Dictionary");
-                println!("====================");
-                println!("✅ Use client command: symvea-client freeze-dictionary");
+            use crate::protocol::{frame::{Frame, write_frame}, handshake::{read_handshake, write_handshake}};
+            
+            // The mutable dictionary only lives in the running daemon
+            let addr = listen_addr.replace("0.0.0.0", "127.0.0.1");
+            let result = async {
+                let mut stream = tokio::net::TcpStream::connect(&addr).await?;
+                write_handshake(&mut stream).await?;
+                read_handshake(&mut stream).await?;
+                write_frame(&mut stream, Frame::FreezeDictionary).await?;
+                write_frame(&mut stream, Frame::Close).await
+            }.await;
+            
+            match result {
+                Ok(()) => {
+                    if cli.json {
+                        println!("{}", serde_json::json!({"success": true, "server": addr}));
+                    } else {
+                        println!("🧊 Freezing Dictionary");
+                        println!("====================");
+                        println!("✅ Freeze requested from server at {}", addr);
+                    }
+                }
+                Err(e) => {
+                    if cli.json {
+                        println!("{}", serde_json::json!({"error": e.to_string(), "server": addr}));
+                    } else {
+                        println!("🧊 Freezing Dictionary");
+                        println!("====================");
+                        println!("❌ Could not reach server at {}: {}", addr, e);
+                        println!("   To build a frozen dictionary offline, use train-dictionary");
+                    }
+                    return Err(e);
+                }
+            }
+            return Ok(());
+        }
+        Some(Commands::Recompress { to_dict, prefix }) => {
+            use crate::coordination::ObjectLocks;
+            use crate::recompress::Recompressor;
+            use crate::storage::{
+                chunks::ChunkStore, dictionary::Dictionary, local::LocalStorage, registry::DictionaryRegistry, symbols::SymbolStore,
+            };
+            use std::sync::Arc;
+            
+            let recompressor = Recompressor::new(
+                Arc::new(LocalStorage::new(std::path::PathBuf::from(&data_dir))),
+                Arc::new(ChunkStore::new(&data_dir)),
+                Arc::new(DictionaryRegistry::new(data_dir.clone(), Dictionary::new("global"))),
+                Arc::new(SymbolStore::new(&data_dir)),
+                Arc::new(ObjectLocks::new()),
+            );
+            match recompressor.run(&to_dict, prefix.as_deref()).await {
+                Ok(report) => {
+                    if cli.json {
+                        let failures: Vec<_> = report.failures.iter()
+                            .map(|f| serde_json::json!({"key": f.key, "error": f.error.to_string()}))
+                            .collect();
+                        println!("{}", serde_json::json!({
+                            "to_dict": to_dict,
+                            "examined": report.examined,
+                            "migrated": report.migrated,
+                            "unchanged": report.unchanged,
+                            "bytes_before": report.bytes_before,
+                            "bytes_after": report.bytes_after,
+                            "grown": report.grown,
+                            "failures": failures
+                        }));
+                    } else {
+                        println!("♻️  Recompressing onto {}", to_dict);
+                        println!("==========================================");
+                        println!("   Examined: {}", report.examined);
+                        println!("   Migrated: {} ({} -> {} bytes)", report.migrated, report.bytes_before, report.bytes_after);
+                        println!("   Already on target: {}", report.unchanged);
+                        for key in &report.grown {
+                            println!("   ⚠️  {}: no smaller on the new dictionary", key);
+                        }
+                        for failure in &report.failures {
+                            println!("   ❌ {}: {}", failure.key, failure.error);
+                        }
+                    }
+                    if !report.failures.is_empty() {
+                        return Err(anyhow::anyhow!("{} objects could not be recompressed", report.failures.len()));
+                    }
+                }
+                Err(e) => {
+                    if cli.json {
+                        println!("{}", serde_json::json!({"error": e.to_string()}));
+                    } else {
+                        println!("❌ Recompress failed: {}", e);
+                    }
+                    return Err(e);
+                }
+            }
+            return Ok(());
+        }
+        Some(Commands::TrainDictionary { input, size, output }) => {
+            use crate::engine::{config::EngineConfig, train::train_dictionary};
+            use crate::storage::dictionary::Dictionary;
+            
+            let trained = train_dictionary(std::path::Path::new(&input), size, &EngineConfig::default())
+                .map_err(anyhow::Error::from)
+                .and_then(|(dict, report)| {
+                    let path = output.unwrap_or_else(|| Dictionary::path(&data_dir, &dict.id));
+                    dict.save(&path)?;
+                    Ok((dict, report, path))
+                });
+            
+            match trained {
+                Ok((dict, report, path)) => {
+                    // The server keeps starting with the active dictionary
+                    // until this one is activated, which needs it in the
+                    // data directory
+                    let activate = (path == Dictionary::path(&data_dir, &dict.id))
+                        .then(|| format!("dict activate {}", dict.id));
+                    if cli.json {
+                        println!("{}", serde_json::json!({
+                            "success": true,
+                            "dict_id": dict.id,
+                            "output": path,
+                            "activate": activate,
+                            "symbols": report.symbols,
+                            "files_sampled": report.files_sampled,
+                            "files_skipped": report.files_skipped,
+                            "sample_bytes": report.sample_bytes
+                        }));
+                    } else {
+                        println!("🧠 Training Dictionary");
+                        println!("=====================");
+                        println!("✅ Dictionary {} written to {}", dict.id, path);
+                        println!("   Symbols: {}", report.symbols);
+                        println!("   Files sampled: {} ({} bytes)", report.files_sampled, report.sample_bytes);
+                        if report.files_skipped > 0 {
+                            println!("   Files skipped as incompressible: {}", report.files_skipped);
+                        }
+                        match &activate {
+                            Some(command) => println!("   To start the server with it, run `{}`", command),
+                            None => println!("   Copy it into {} and activate it to start the server with it", data_dir),
+                        }
+                    }
+                }
+                Err(e) => {
+                    if cli.json {
+                        println!("{}", serde_json::json!({"error": e.to_string()}));
+                    } else {
+                        println!("🧠 Training Dictionary");
+                        println!("=====================");
+                        println!("❌ Training failed: {}", e);
+                    }
+                    return Err(e);
+                }
             }
             return Ok(());
         }
@@ -551,6 +749,209 @@ async fn main() -> anyhow::Result<()> {
             }
             return Ok(());
         }
+        Some(Commands::Dict { dict_cmd }) => {
+            use crate::engine::{
+                config::EngineConfig,
+                decompressor::dictionary_id,
+                hash::sha256,
+                prune::{estimate_ratio, prune_dictionary},
+            };
+            use crate::storage::{dictionary::Dictionary, local::LocalStorage, registry::DictionaryRegistry, symbols::SymbolStore};
+            
+            let registry = DictionaryRegistry::new(data_dir.clone(), Dictionary::new("global"));
+            let symbol_store = SymbolStore::new(&data_dir);
+            // Usage is recorded per symbol content, so it is shared by every
+            // dictionary holding the same bytes
+            let describe = |token: u32, bytes: &[u8]| {
+                let hash = hex::encode(&sha256(bytes)[..16]);
+                let usage = symbol_store.get_corpus_usage(&hash).ok();
+                serde_json::json!({
+                    "token": token,
+                    "text": bytes.escape_ascii().to_string(),
+                    "hex": hex::encode(bytes),
+                    "bytes_contributed": usage.as_ref().map_or(0, |u| u.total_bytes_contributed),
+                    "occurrences": usage.as_ref().map_or(0, |u| u.total_occurrences),
+                    "objects": usage.as_ref().map_or(0, |u| u.objects.len()),
+                })
+            };
+            let print_symbol = |marker: &str, symbol: &serde_json::Value| {
+                println!("   {} {:>10}  \"{}\"  {}", marker, symbol["token"], symbol["text"].as_str().unwrap_or(""), symbol["hex"].as_str().unwrap_or(""));
+                println!("     {:>10}  {} bytes contributed, {} occurrences in {} objects", "",
+                         symbol["bytes_contributed"], symbol["occurrences"], symbol["objects"]);
+            };
+            
+            let result = match dict_cmd {
+                DictCommands::List => registry.list().map(|entries| {
+                    if cli.json {
+                        println!("{}", serde_json::json!({
+                            "total_dictionaries": entries.len(),
+                            "dictionaries": entries
+                        }));
+                    } else {
+                        println!("📚 Dictionaries ({})", entries.len());
+                        println!("==================");
+                        for entry in &entries {
+                            let kind = match (&entry.user, entry.frozen) {
+                                (Some(user), _) => format!("user {}", user),
+                                (None, true) => "frozen".to_string(),
+                                (None, false) => "generation".to_string(),
+                            };
+                            println!("   {}  {:<16} {:>6} symbols  created {}", entry.id, kind, entry.symbols, entry.created_at);
+                            if let Some(base) = &entry.base {
+                                println!("   {:>32}  layered on {}", "", base);
+                            }
+                        }
+                    }
+                }),
+                DictCommands::Show { id } => registry.find(&id).map(|dict| {
+                    let symbols: Vec<_> = dict.symbols().into_iter()
+                        .map(|(token, bytes)| describe(token, bytes))
+                        .collect();
+                    if cli.json {
+                        println!("{}", serde_json::json!({
+                            "dict_id": dictionary_id(&dict),
+                            "frozen": dict.frozen,
+                            "created_at": dict.created_at,
+                            "frozen_at": dict.frozen_at,
+                            "user": dict.user,
+                            "base": dict.base,
+                            "total_symbols": symbols.len(),
+                            "symbols": symbols
+                        }));
+                    } else {
+                        println!("📖 Dictionary {}", dictionary_id(&dict));
+                        println!("==================");
+                        println!("   Frozen: {}", dict.frozen);
+                        if let Some(user) = &dict.user {
+                            println!("   User: {}", user);
+                        }
+                        if let Some(base) = &dict.base {
+                            println!("   Base: {}", base);
+                        }
+                        println!("   Symbols: {}", symbols.len());
+                        for symbol in &symbols {
+                            print_symbol(" ", symbol);
+                        }
+                    }
+                }),
+                DictCommands::Diff { a, b } => registry.find(&a).and_then(|dict_a| {
+                    let dict_b = registry.find(&b)?;
+                    let mut added = Vec::new();
+                    let mut removed = Vec::new();
+                    let mut changed = Vec::new();
+                    for (token, bytes) in dict_b.symbols() {
+                        match dict_a.decode.get(&token) {
+                            None => added.push(describe(token, bytes)),
+                            Some(old) if old.as_slice() != bytes => changed.push(serde_json::json!({
+                                "from": describe(token, old),
+                                "to": describe(token, bytes),
+                            })),
+                            Some(_) => {}
+                        }
+                    }
+                    for (token, bytes) in dict_a.symbols() {
+                        if !dict_b.decode.contains_key(&token) {
+                            removed.push(describe(token, bytes));
+                        }
+                    }
+                    
+                    if cli.json {
+                        println!("{}", serde_json::json!({
+                            "from": dictionary_id(&dict_a),
+                            "to": dictionary_id(&dict_b),
+                            "added": added,
+                            "removed": removed,
+                            "changed": changed
+                        }));
+                    } else {
+                        println!("🔀 Dictionary Diff");
+                        println!("==================");
+                        println!("   From: {} ({} symbols)", dictionary_id(&dict_a), dict_a.decode.len());
+                        println!("   To:   {} ({} symbols)", dictionary_id(&dict_b), dict_b.decode.len());
+                        println!("   Added: {}, removed: {}, changed: {}", added.len(), removed.len(), changed.len());
+                        for symbol in &added {
+                            print_symbol("+", symbol);
+                        }
+                        for symbol in &removed {
+                            print_symbol("-", symbol);
+                        }
+                        for symbol in &changed {
+                            print_symbol("-", &symbol["from"]);
+                            print_symbol("+", &symbol["to"]);
+                        }
+                    }
+                    Ok(())
+                }),
+                DictCommands::Activate { id } => registry.find(&id).and_then(|dict| {
+                    let dict_id = dictionary_id(&dict);
+                    registry.activate(&dict_id)?;
+                    if cli.json {
+                        println!("{}", serde_json::json!({"success": true, "dict_id": dict_id}));
+                    } else {
+                        println!("✅ Dictionary {} is active; restart the server to use it", dict_id);
+                    }
+                    Ok(())
+                }),
+                DictCommands::Prune { id, min_contribution, dry_run } => async {
+                    let dict = registry.find(&id)?;
+                    if dict.user.is_some() {
+                        return Err(anyhow::anyhow!("{} is a user dictionary; only global dictionaries are pruned", dictionary_id(&dict)));
+                    }
+                    let min_contribution = min_contribution.unwrap_or(EngineConfig::default().prune_min_contribution);
+                    let (mut pruned, report) = prune_dictionary(&dict, &symbol_store, min_contribution);
+                    let storage = LocalStorage::new(std::path::PathBuf::from(&data_dir));
+                    let estimate = estimate_ratio(&storage, &report).await?;
+                    let output = if dry_run {
+                        None
+                    } else {
+                        let dict_id = pruned.freeze();
+                        let path = Dictionary::path(&data_dir, &dict_id);
+                        pruned.save(&path)?;
+                        Some((dict_id, path))
+                    };
+                    
+                    if cli.json {
+                        let evicted: Vec<_> = report.evicted.iter()
+                            .map(|s| describe(s.token, &s.bytes))
+                            .collect();
+                        println!("{}", serde_json::json!({
+                            "dict_id": dictionary_id(&dict),
+                            "dry_run": dry_run,
+                            "min_contribution": min_contribution,
+                            "symbols_before": report.symbols_before,
+                            "symbols_after": report.symbols_after,
+                            "evicted_contribution": report.evicted_contribution,
+                            "corpus_ratio": estimate.ratio(),
+                            "estimated_ratio": estimate.estimated_ratio(),
+                            "pruned_dict_id": output.as_ref().map(|(id, _)| id),
+                            "output": output.as_ref().map(|(_, path)| path),
+                            "evicted": evicted
+                        }));
+                    } else {
+                        println!("✂️  Pruning Dictionary {}{}", dictionary_id(&dict), if dry_run { " (dry run)" } else { "" });
+                        println!("==================");
+                        println!("   Threshold: {} bytes contributed", min_contribution);
+                        println!("   Symbols: {} -> {} ({} evicted)", report.symbols_before, report.symbols_after, report.evicted.len());
+                        println!("   Evicted symbols contributed {} bytes", report.evicted_contribution);
+                        println!("   Corpus ratio: {:.3} -> ~{:.3} (estimated)", estimate.ratio(), estimate.estimated_ratio());
+                        if let Some((dict_id, path)) = &output {
+                            println!("✅ Frozen dictionary {} written to {}", dict_id, path);
+                        }
+                    }
+                    Ok(())
+                }.await,
+            };
+            
+            if let Err(e) = result {
+                if cli.json {
+                    println!("{}", serde_json::json!({"error": e.to_string()}));
+                } else {
+                    println!("❌ {}", e);
+                }
+                return Err(e);
+            }
+            return Ok(());
+        }
         Some(Commands::Symbol { symbol_cmd }) => {
             use crate::storage::PersistentStorage;
             
@@ -820,7 +1221,7 @@ async fn main() -> anyhow::Result<()> {
         }
         None => {
             // Normal server startup
-            info!("Starting Symvea server on {}", listen_addr);
+            info!("Starting Symvea server on {} (compression level: {})", listen_addr, config.compression_level);
             
             if !config.readonly_mounts.is_empty() {
                 info!("Readonly mounts: {:?}", config.readonly_mounts);
@@ -829,7 +1230,7 @@ async fn main() -> anyhow::Result<()> {
             let validator = StartupValidator::new(&data_dir)?;
             validator.validate_and_start()?;
             
-            server::run_on(&listen_addr, &data_dir).await
+            server::run_on(&listen_addr, &data_dir, config.compression_level, config.dedup_chunks, config.recompress.clone()).await
         }
     }
 }
@@ -3,6 +3,7 @@ use std::sync::Arc;
 use serde::Serialize;
 use tokio::net::TcpListener;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::storage::dictionary::Dictionary;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Metrics {
@@ -109,14 +110,7 @@ pub async fn start_metrics_server(
                         .unwrap_or(0);
                     
                     // Check if dictionary is frozen
-                    let dict_frozen = std::fs::read_dir(&data_dir)
-                        .map(|mut entries| {
-                            entries.any(|entry| {
-                                entry.map(|e| e.file_name().to_string_lossy().starts_with("dictionary_"))
-                                    .unwrap_or(false)
-                            })
-                        })
-                        .unwrap_or(false);
+                    let dict_frozen = std::path::Path::new(&Dictionary::active_path(&data_dir)).exists();
                     
                     let metrics_data = metrics.get_metrics(symbols_count, dict_frozen);
                     let json = serde_json::to_string_pretty(&metrics_data).unwrap_or_default();
@@ -20,6 +20,15 @@ pub const FRAME_CHUNK_START: u8 = 0x10;
 pub const FRAME_CHUNK_DATA: u8 = 0x11;
 pub const FRAME_CHUNK_END: u8 = 0x12;
 
+/// Frame header flag: the payload starts with a u16 length and the
+/// uploader's user id (Upload and ChunkStart only)
+pub const FRAME_FLAG_USER_ID: u8 = 0x01;
+
+/// Frame header flag: the payload (after any user id prefix) starts with
+/// one byte naming the upload's compression level (Upload and ChunkStart
+/// only). Without it the server's default level applies.
+pub const FRAME_FLAG_LEVEL: u8 = 0x02;
+
 /// Hard safety limits
 pub const MAX_FRAME_SIZE: usize = usize::MAX; // No limit
 pub const MAX_HEADER_SIZE: usize = usize::MAX; // No limit
@@ -1,4 +1,5 @@
-use crate::protocol::error::ProtocolError;
+use crate::protocol::{error::ProtocolError, FRAME_FLAG_LEVEL, FRAME_FLAG_USER_ID};
+use crate::engine::config::CompressionLevel;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use crate::utils::crc::crc32;
@@ -55,17 +56,20 @@ impl FrameHeader {
 /// Frame types
 #[derive(Debug)]
 pub enum Frame {
-    Upload { key: String, data: Vec<u8>, user_id: Option<String> },
+    Upload { key: String, data: Vec<u8>, user_id: Option<String>, level: Option<CompressionLevel> },
     Download { key: String },
+    /// Download of `len` bytes from `offset`, answered with one Data frame
+    DownloadRange { key: String, offset: u64, len: u64 },
     Verify { key: String },
     Ack { key: String, original_size: u64, compressed_size: u64 },
     Data { key: String, data: Vec<u8> },
     Verified { key: String, hash_match: bool },
     NotFound { key: String },
+    Error { key: String, message: String },
     FreezeDictionary,
     Close,
     // Chunked upload frames
-    ChunkStart { key: String, total_size: u64, chunk_count: u32, user_id: Option<String> },
+    ChunkStart { key: String, total_size: u64, chunk_count: u32, user_id: Option<String>, level: Option<CompressionLevel> },
     ChunkData { key: String, chunk_index: u32, data: Vec<u8> },
     ChunkEnd { key: String },
 }
@@ -99,6 +103,17 @@ pub async fn read_frame(stream: &mut TcpStream) -> anyhow::Result<Frame> {
         return Err(anyhow::anyhow!("Checksum mismatch"));
     }
     
+    let (user_id, payload) = if header.flags & FRAME_FLAG_USER_ID != 0 {
+        split_user_id(payload)?
+    } else {
+        (None, payload)
+    };
+    let (level, payload) = if header.flags & FRAME_FLAG_LEVEL != 0 {
+        split_level(payload)?
+    } else {
+        (None, payload)
+    };
+    
     // Parse frame based on type
 
     
@@ -122,12 +137,25 @@ pub async fn read_frame(stream: &mut TcpStream) -> anyhow::Result<Frame> {
             let data = payload[4+key_len..].to_vec();
             
 
-            Ok(Frame::Upload { key, data, user_id: None })
+            Ok(Frame::Upload { key, data, user_id, level })
         },
         2 => { // Download
             let key = String::from_utf8(payload)?;
             Ok(Frame::Download { key })
         },
+        10 => { // DownloadRange
+            if payload.len() < 4 {
+                return Err(anyhow::anyhow!("DownloadRange frame payload too short"));
+            }
+            let key_len = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+            if payload.len() != 20 + key_len {
+                return Err(anyhow::anyhow!("DownloadRange frame payload has the wrong length"));
+            }
+            let key = String::from_utf8(payload[4..4+key_len].to_vec())?;
+            let offset = u64::from_be_bytes(payload[4+key_len..12+key_len].try_into()?);
+            let len = u64::from_be_bytes(payload[12+key_len..20+key_len].try_into()?);
+            Ok(Frame::DownloadRange { key, offset, len })
+        },
         8 => { // Verify
             let key = String::from_utf8(payload)?;
             Ok(Frame::Verify { key })
@@ -140,6 +168,18 @@ pub async fn read_frame(stream: &mut TcpStream) -> anyhow::Result<Frame> {
 
             Ok(Frame::Close)
         },
+        0x7F => { // Error
+            if payload.len() < 4 {
+                return Err(anyhow::anyhow!("Error frame payload too short"));
+            }
+            let key_len = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+            if payload.len() < 4 + key_len {
+                return Err(anyhow::anyhow!("Error frame payload too short for key"));
+            }
+            let key = String::from_utf8(payload[4..4+key_len].to_vec())?;
+            let message = String::from_utf8_lossy(&payload[4+key_len..]).into_owned();
+            Ok(Frame::Error { key, message })
+        },
         0x10 => { // ChunkStart
 
             if payload.len() < 12 {
@@ -157,7 +197,7 @@ pub async fn read_frame(stream: &mut TcpStream) -> anyhow::Result<Frame> {
             let chunk_count = u32::from_be_bytes([
                 payload[12+key_len], payload[13+key_len], payload[14+key_len], payload[15+key_len]
             ]);
-            Ok(Frame::ChunkStart { key, total_size, chunk_count, user_id: None })
+            Ok(Frame::ChunkStart { key, total_size, chunk_count, user_id, level })
         },
         0x11 => { // ChunkData
 
@@ -187,8 +227,35 @@ pub async fn read_frame(stream: &mut TcpStream) -> anyhow::Result<Frame> {
     }
 }
 
+/// Splits the user id prefix off a payload sent with `FRAME_FLAG_USER_ID`
+fn split_user_id(payload: Vec<u8>) -> anyhow::Result<(Option<String>, Vec<u8>)> {
+    if payload.len() < 2 {
+        return Err(anyhow::anyhow!("User id prefix too short"));
+    }
+    let len = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+    if payload.len() < 2 + len {
+        return Err(anyhow::anyhow!("User id prefix too short for id"));
+    }
+    let user_id = String::from_utf8(payload[2..2+len].to_vec())?;
+    Ok((Some(user_id), payload[2+len..].to_vec()))
+}
+
+/// Splits the level byte off a payload sent with `FRAME_FLAG_LEVEL`
+fn split_level(payload: Vec<u8>) -> anyhow::Result<(Option<CompressionLevel>, Vec<u8>)> {
+    let Some(&tag) = payload.first() else {
+        return Err(anyhow::anyhow!("Compression level byte missing"));
+    };
+    let level = CompressionLevel::from_tag(tag)
+        .ok_or_else(|| anyhow::anyhow!("Unknown compression level: {}", tag))?;
+    Ok((Some(level), payload[1..].to_vec()))
+}
+
 pub async fn write_frame(stream: &mut TcpStream, frame: Frame) -> anyhow::Result<()> {
-    let (frame_type, payload) = match frame {
+    let (user_id, level) = match &frame {
+        Frame::Upload { user_id, level, .. } | Frame::ChunkStart { user_id, level, .. } => (user_id.clone(), *level),
+        _ => (None, None),
+    };
+    let (frame_type, mut payload) = match frame {
         Frame::Upload { key, data, .. } => {
             let mut payload = Vec::new();
             payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
@@ -201,6 +268,15 @@ pub async fn write_frame(stream: &mut TcpStream, frame: Frame) -> anyhow::Result
 
             (2u8, key.into_bytes())
         },
+        Frame::DownloadRange { key, offset, len } => {
+            let mut payload = Vec::new();
+            payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            payload.extend_from_slice(key.as_bytes());
+            payload.extend_from_slice(&offset.to_be_bytes());
+            payload.extend_from_slice(&len.to_be_bytes());
+
+            (10u8, payload)
+        },
         Frame::Verify { key } => {
 
             (8u8, key.into_bytes())
@@ -229,6 +305,14 @@ pub async fn write_frame(stream: &mut TcpStream, frame: Frame) -> anyhow::Result
 
             (7u8, payload)
         },
+        Frame::Error { key, message } => {
+            let mut payload = Vec::new();
+            payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            payload.extend_from_slice(key.as_bytes());
+            payload.extend_from_slice(message.as_bytes());
+
+            (0x7Fu8, payload)
+        },
         Frame::Verified { key, hash_match } => {
             let mut payload = Vec::new();
             payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
@@ -269,10 +353,24 @@ pub async fn write_frame(stream: &mut TcpStream, frame: Frame) -> anyhow::Result
         },
     };
     
+    let mut flags = 0;
+    if let Some(level) = level {
+        payload.insert(0, level.tag());
+        flags |= FRAME_FLAG_LEVEL;
+    }
+    if let Some(user_id) = user_id {
+        let mut prefixed = Vec::with_capacity(2 + user_id.len() + payload.len());
+        prefixed.extend_from_slice(&(user_id.len() as u16).to_be_bytes());
+        prefixed.extend_from_slice(user_id.as_bytes());
+        prefixed.extend(payload);
+        payload = prefixed;
+        flags |= FRAME_FLAG_USER_ID;
+    }
+    
     let checksum = crc32(&payload);
     let header = FrameHeader {
         frame_type,
-        flags: 0,
+        flags,
         header_len: FrameHeader::SIZE as u16,
         payload_len: payload.len() as u32,
         checksum,
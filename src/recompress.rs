@@ -0,0 +1,380 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::engine::{
+    codec::{choose_codec, encode_or_store, CHUNKED_CODEC},
+    config::EngineConfig,
+    container::CODEC_VERSION,
+    decompressor::decompress_object,
+    hash::sha256,
+};
+use crate::coordination::ObjectLocks;
+use crate::storage::{
+    ObjectMetadata, StorageEngine,
+    chunks::ChunkStore,
+    dictionary::Dictionary,
+    registry::DictionaryRegistry,
+    symbols::SymbolStore,
+};
+
+/// Background migration the server runs at startup, from `[recompress]`
+/// in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecompressJob {
+    pub to_dict: String,
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
+
+/// An object that was left as it was, and why
+pub struct RecompressFailure {
+    pub key: String,
+    pub error: anyhow::Error,
+}
+
+#[derive(Default)]
+pub struct RecompressReport {
+    pub examined: usize,
+    pub migrated: usize,
+    /// Objects already on the target dictionary
+    pub unchanged: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Migrated objects that came out no smaller than they were. They are
+    /// moved all the same, so the dictionary they left can be dropped.
+    pub grown: Vec<String>,
+    pub failures: Vec<RecompressFailure>,
+}
+
+/// Re-encodes stored objects against a frozen dictionary, so objects can
+/// drop the dictionary they were written with. Objects stored as chunks
+/// have their chunks re-encoded in place, which moves every object
+/// sharing them. Works on the stores the server serves from, so uploads
+/// and downloads carry on while it runs.
+pub struct Recompressor<S: StorageEngine> {
+    storage: Arc<S>,
+    chunk_store: Arc<ChunkStore>,
+    dictionaries: Arc<DictionaryRegistry>,
+    symbol_store: Arc<SymbolStore>,
+    object_locks: Arc<ObjectLocks>,
+}
+
+/// Re-encoded blob and metadata, waiting to replace the old ones, and the
+/// symbol usage to record once they have
+struct Replacement {
+    blob: Vec<u8>,
+    meta: ObjectMetadata,
+    usage: SymbolStore,
+}
+
+impl<S: StorageEngine + 'static> Recompressor<S> {
+    pub fn new(
+        storage: Arc<S>,
+        chunk_store: Arc<ChunkStore>,
+        dictionaries: Arc<DictionaryRegistry>,
+        symbol_store: Arc<SymbolStore>,
+        object_locks: Arc<ObjectLocks>,
+    ) -> Self {
+        Self {
+            storage,
+            chunk_store,
+            dictionaries,
+            symbol_store,
+            object_locks,
+        }
+    }
+
+    /// Moves every object whose key starts with `prefix` onto `to_dict`.
+    /// Objects that fail to decode, or do not round-trip to their
+    /// `original_hash`, are reported and left untouched.
+    pub async fn run(&self, to_dict: &str, prefix: Option<&str>) -> Result<RecompressReport> {
+        let target = self.dictionaries.get(to_dict)?
+            .ok_or_else(|| anyhow::anyhow!("Dictionary {} not found", to_dict))?;
+        if !target.frozen {
+            return Err(anyhow::anyhow!("Dictionary {} is not frozen", to_dict));
+        }
+
+        let mut report = RecompressReport::default();
+        for key in self.storage.list(prefix.unwrap_or("")).await? {
+            report.examined += 1;
+            match self.recompress_object(&key, &target).await {
+                Ok(Some((before, after))) => {
+                    report.migrated += 1;
+                    report.bytes_before += before;
+                    report.bytes_after += after;
+                    if after >= before {
+                        report.grown.push(key);
+                    }
+                }
+                Ok(None) => report.unchanged += 1,
+                Err(error) => {
+                    warn!("Not recompressing '{}': {}", key, error);
+                    report.failures.push(RecompressFailure { key, error });
+                }
+            }
+        }
+
+        info!("Recompressed {} of {} objects onto {}, {} grew, {} failed",
+              report.migrated, report.examined, to_dict, report.grown.len(), report.failures.len());
+        Ok(report)
+    }
+
+    /// Returns the old and new blob sizes, or `None` if the object already
+    /// uses `target`
+    async fn recompress_object(&self, key: &str, target: &Arc<Dictionary>) -> Result<Option<(u64, u64)>> {
+        let obj = self.storage.get(key).await?
+            .ok_or_else(|| anyhow::anyhow!("object was deleted while recompressing"))?;
+        if obj.metadata.codec == CHUNKED_CODEC {
+            return self.recompress_chunks(&obj.data, obj.metadata, target).await;
+        }
+
+        let dictionaries = Arc::clone(&self.dictionaries);
+        let symbol_store = Arc::clone(&self.symbol_store);
+        let target = Arc::clone(target);
+        let current = obj.metadata.clone();
+        let replacement = tokio::task::spawn_blocking(move || {
+            reencode(&dictionaries, &symbol_store, &obj.data, &obj.metadata, &target)
+        }).await??;
+        let Some(replacement) = replacement else {
+            return Ok(None);
+        };
+
+        // Uploads replace objects under the same lock, so the object
+        // checked is the one swapped
+        let _guard = self.object_locks.lock(key).await;
+        self.storage.replace(key, &replacement.blob, &replacement.meta, &current).await?;
+        Ok(Some(record_usage(&self.symbol_store, &current, replacement)))
+    }
+
+    /// Moves the chunks of a chunked object onto `target`, then records
+    /// `target` in the object's own metadata
+    async fn recompress_chunks(&self, blob: &[u8], meta: ObjectMetadata, target: &Arc<Dictionary>) -> Result<Option<(u64, u64)>> {
+        let chunk_store = Arc::clone(&self.chunk_store);
+        let dictionaries = Arc::clone(&self.dictionaries);
+        let symbol_store = Arc::clone(&self.symbol_store);
+        let chunk_target = Arc::clone(target);
+        let hashes: Vec<String> = meta.chunks.iter().map(|chunk| chunk.hash.clone()).collect();
+        let moved = tokio::task::spawn_blocking(move || {
+            let mut moved = None;
+            let mut seen = HashSet::new();
+            for hash in &hashes {
+                if !seen.insert(hash) {
+                    continue;
+                }
+                let chunk = chunk_store.get(hash)?
+                    .ok_or_else(|| anyhow::anyhow!("chunk {} is missing", hash))?;
+                let Some(replacement) = reencode(&dictionaries, &symbol_store, &chunk.data, &chunk.metadata, &chunk_target)? else {
+                    continue;
+                };
+                chunk_store.replace(hash, &replacement.blob, &replacement.meta, &chunk.metadata)?;
+                let (before, after) = record_usage(&symbol_store, &chunk.metadata, replacement);
+                let (total_before, total_after) = moved.get_or_insert((0, 0));
+                *total_before += before;
+                *total_after += after;
+            }
+            Ok::<_, anyhow::Error>(moved)
+        }).await??;
+
+        if meta.dict_id != target.id {
+            let _guard = self.object_locks.lock(&meta.key).await;
+            let mut new_meta = meta.clone();
+            new_meta.dict_id = target.id.clone();
+            self.storage.replace(&meta.key, blob, &new_meta, &meta).await?;
+        }
+        Ok(moved)
+    }
+}
+
+/// Re-encodes one blob, an object's or a chunk's, the way an upload of it
+/// would be, and checks that the result decodes to the original. `None` if
+/// it already uses `target`.
+fn reencode(
+    dictionaries: &DictionaryRegistry,
+    symbol_store: &SymbolStore,
+    blob: &[u8],
+    meta: &ObjectMetadata,
+    target: &Arc<Dictionary>,
+) -> Result<Option<Replacement>> {
+    if meta.dict_id == target.id {
+        return Ok(None);
+    }
+
+    let source = dictionaries.resolve(&meta.dict_id)?;
+    let original = decompress_object(blob, meta, &source)?;
+    if sha256(&original) != meta.original_hash {
+        return Err(anyhow::anyhow!("decoded content does not match original_hash"));
+    }
+
+    let config = EngineConfig {
+        entropy_coder: meta.entropy_coder,
+        ..EngineConfig::default().with_level(meta.level)
+    };
+    // The target is frozen, so there is nothing to learn; usage waits for
+    // the swap, which may not happen
+    let usage = symbol_store.deferred();
+    let (codec, (new_blob, symbols, explained_ratio, token_breakdown)) =
+        encode_or_store(choose_codec(&original), &original, target, &usage, &meta.key, &config, Vec::new());
+
+    let mut new_meta = meta.clone();
+    new_meta.dict_id = target.id.clone();
+    new_meta.compressed_size = new_blob.len() as u64;
+    new_meta.codec_version = CODEC_VERSION;
+    new_meta.codec = codec.id().to_string();
+    new_meta.symbols = symbols;
+    new_meta.explained_ratio = explained_ratio;
+    new_meta.token_breakdown = token_breakdown;
+
+    let decoded = decompress_object(&new_blob, &new_meta, target)?;
+    if sha256(&decoded) != meta.original_hash {
+        return Err(anyhow::anyhow!("re-encoded content does not round-trip"));
+    }
+    Ok(Some(Replacement { blob: new_blob, meta: new_meta, usage }))
+}
+
+/// Records the usage of a replacement that was swapped in, returning the
+/// old and new blob sizes
+fn record_usage(symbol_store: &SymbolStore, replaced: &ObjectMetadata, replacement: Replacement) -> (u64, u64) {
+    if let Err(e) = symbol_store.apply_usage(replacement.usage) {
+        warn!("Failed to record symbol usage of '{}': {}", replaced.key, e);
+    }
+    (replaced.compressed_size, replacement.meta.compressed_size)
+}
+
+/// Runs `job` in the background so it does not hold up the server
+pub fn spawn_background<S: StorageEngine + 'static>(recompressor: Recompressor<S>, job: RecompressJob) {
+    tokio::spawn(async move {
+        info!("Background recompress onto {} started", job.to_dict);
+        match recompressor.run(&job.to_dict, job.prefix.as_deref()).await {
+            Ok(report) => info!("Background recompress finished: {} migrated, {} unchanged, {} failed",
+                                report.migrated, report.unchanged, report.failures.len()),
+            Err(e) => warn!("Background recompress failed: {}", e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::engine::codec::{Codec, SymbolCodec};
+    use crate::storage::local::LocalStorage;
+
+    struct Fixture {
+        dir: PathBuf,
+        recompressor: Recompressor<LocalStorage>,
+        /// Frozen dictionary without symbols, which the object is written with
+        empty: Arc<Dictionary>,
+        /// Frozen dictionary holding the object's symbols
+        target: Arc<Dictionary>,
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    /// A data dir with one object, `key`, and a frozen dictionary with its
+    /// symbols to move it onto
+    async fn fixture(name: &str, key: &str, data: &[u8]) -> Fixture {
+        let dir = std::env::temp_dir().join(format!("symvea-recompress-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let data_dir = dir.to_string_lossy().into_owned();
+
+        let storage = Arc::new(LocalStorage::new(dir.clone()));
+        let dictionaries = Arc::new(DictionaryRegistry::new(data_dir.clone(), Dictionary::new("global")));
+        let symbol_store = Arc::new(SymbolStore::new(&data_dir));
+        let config = EngineConfig::default();
+
+        let empty = freeze(&data_dir, &dictionaries, Dictionary::new("global"));
+        let (blob, ..) = SymbolCodec.encode(data, &empty, &symbol_store, key, &config, Vec::new());
+        let hash = sha256(data);
+        let meta = ObjectMetadata::new(key.to_string(), hash, hash, empty.id.clone(), data.len() as u64, blob.len() as u64, None);
+        storage.put(key, &blob, &meta).await.unwrap();
+
+        let mut learned = Dictionary::new("global");
+        SymbolCodec.learn(data, &mut learned, &symbol_store, &config);
+        let target = freeze(&data_dir, &dictionaries, learned);
+        let recompressor = Recompressor::new(
+            storage,
+            Arc::new(ChunkStore::new(&data_dir)),
+            dictionaries,
+            symbol_store,
+            Arc::new(ObjectLocks::new()),
+        );
+        Fixture { dir, recompressor, empty, target }
+    }
+
+    fn freeze(data_dir: &str, dictionaries: &DictionaryRegistry, mut dict: Dictionary) -> Arc<Dictionary> {
+        let id = dict.freeze();
+        dict.save(&Dictionary::path(data_dir, &id)).unwrap();
+        dictionaries.get(&id).unwrap().unwrap()
+    }
+
+    fn usage_files(fixture: &Fixture) -> usize {
+        std::fs::read_dir(fixture.dir.join("symbol_usage")).unwrap().count()
+    }
+
+    fn clear_usage(fixture: &Fixture) {
+        std::fs::remove_dir_all(fixture.dir.join("symbol_usage")).unwrap();
+        std::fs::create_dir_all(fixture.dir.join("symbol_usage")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn moves_objects_onto_target() {
+        let data = b"recompress me, recompress me, recompress me please".repeat(50);
+        let fixture = fixture("moves", "obj", &data).await;
+        clear_usage(&fixture);
+        let report = fixture.recompressor.run(&fixture.target.id, None).await.unwrap();
+        assert_eq!((report.examined, report.migrated), (1, 1));
+        assert!(report.grown.is_empty());
+        assert!(usage_files(&fixture) > 0);
+
+        let obj = fixture.recompressor.storage.get("obj").await.unwrap().unwrap();
+        assert_eq!(obj.metadata.dict_id, fixture.target.id);
+        assert_eq!(decompress_object(&obj.data, &obj.metadata, &fixture.target).unwrap(), data);
+
+        let again = fixture.recompressor.run(&fixture.target.id, None).await.unwrap();
+        assert_eq!((again.migrated, again.unchanged), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn objects_that_grow_are_reported_and_never_pass_stored() {
+        let data = b"symbols make this small, symbols make this small".repeat(80);
+        let fixture = fixture("grown", "obj", &data).await;
+        fixture.recompressor.run(&fixture.target.id, None).await.unwrap();
+
+        // Without the object's symbols, re-encoding can only grow it
+        let report = fixture.recompressor.run(&fixture.empty.id, None).await.unwrap();
+        assert_eq!(report.migrated, 1);
+        assert_eq!(report.grown, vec!["obj".to_string()]);
+
+        let obj = fixture.recompressor.storage.get("obj").await.unwrap().unwrap();
+        assert!(obj.data.len() <= crate::engine::block::stored_size(&fixture.empty.id, data.len()));
+        assert_eq!(decompress_object(&obj.data, &obj.metadata, &fixture.empty).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn replaced_object_is_not_swapped_or_counted() {
+        let data = b"an object an upload replaces, an object an upload replaces".repeat(50);
+        let fixture = fixture("replaced", "obj", &data).await;
+        let recompressor = &fixture.recompressor;
+        let obj = recompressor.storage.get("obj").await.unwrap().unwrap();
+        clear_usage(&fixture);
+        let replacement = reencode(&recompressor.dictionaries, &recompressor.symbol_store, &obj.data, &obj.metadata, &fixture.target)
+            .unwrap()
+            .unwrap();
+
+        // An upload lands between re-encoding and the swap
+        let mut uploaded = obj.metadata.clone();
+        uploaded.stored_at += 1;
+        recompressor.storage.put("obj", &obj.data, &uploaded).await.unwrap();
+
+        assert!(recompressor.storage.replace("obj", &replacement.blob, &replacement.meta, &obj.metadata).await.is_err());
+        assert_eq!(recompressor.storage.get_metadata("obj").await.unwrap().unwrap().stored_at, uploaded.stored_at);
+        assert_eq!(usage_files(&fixture), 0);
+    }
+}
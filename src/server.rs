@@ -4,18 +4,28 @@ use crate::session::Session;
 use crate::storage::{
     local::LocalStorage,
     dictionary::Dictionary,
+    registry::DictionaryRegistry,
     symbols::SymbolStore,
+    chunks::ChunkStore,
 };
-use crate::coordination::CoordinationManager;
+use crate::coordination::{CoordinationManager, ObjectLocks};
+use crate::recompress::{self, Recompressor, RecompressJob};
+use crate::engine::config::{CompressionLevel, EngineConfig};
 use crate::metrics::{MetricsCollector, start_metrics_server};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::path::PathBuf;
 
 pub async fn run() -> anyhow::Result<()> {
-    run_on("0.0.0.0:24096", "./data").await
+    run_on("0.0.0.0:24096", "./data", CompressionLevel::Default, false, None).await
 }
 
-pub async fn run_on(addr: &str, data_dir: &str) -> anyhow::Result<()> {
+pub async fn run_on(
+    addr: &str,
+    data_dir: &str,
+    level: CompressionLevel,
+    dedup_chunks: bool,
+    recompress_job: Option<RecompressJob>,
+) -> anyhow::Result<()> {
     let listener = TcpListener::bind(addr).await?;
     info!("Server listening on {}", addr);
 
@@ -34,32 +44,34 @@ pub async fn run_on(addr: &str, data_dir: &str) -> anyhow::Result<()> {
         }
     });
     
-    // Try to load existing frozen dictionary with coordination
-    let global_dict = Arc::new(Mutex::new({
-        coordination.with_dictionary_lock(|| {
-            let dict_dir = format!("{}", data_dir);
-            let mut loaded_dict = None;
-            if let Ok(entries) = std::fs::read_dir(&dict_dir) {
-                for entry in entries.flatten() {
-                    let filename = entry.file_name();
-                    if let Some(name) = filename.to_str() {
-                        if name.starts_with("dictionary_") && name.ends_with(".json") {
-                            if let Ok(dict_json) = std::fs::read_to_string(entry.path()) {
-                                if let Ok(dict) = serde_json::from_str::<Dictionary>(&dict_json) {
-                                    info!("Loaded frozen dictionary: {}", name);
-                                    loaded_dict = Some(dict);
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            Ok(loaded_dict.unwrap_or_else(|| Dictionary::new("global")))
-        }).unwrap_or_else(|_| Dictionary::new("global"))
-    }));
+    // Load the active frozen dictionary with coordination
+    let global_dict = coordination.with_dictionary_lock(|| DictionaryRegistry::load_active(data_dir))?;
+    match &global_dict {
+        Some(dict) => info!("Loaded frozen dictionary: {}", dict.id),
+        None => info!("No frozen dictionary; learning from uploads"),
+    }
+    let global_dict = global_dict.unwrap_or_else(|| Dictionary::new("global"));
+    let dictionaries = Arc::new(DictionaryRegistry::new(data_dir, global_dict));
     
     let symbol_store = Arc::new(SymbolStore::new(data_dir));
+    let chunk_store = Arc::new(ChunkStore::new(data_dir));
+    let object_locks = Arc::new(ObjectLocks::new());
+    let engine_config = Arc::new(EngineConfig {
+        dedup_chunks,
+        ..EngineConfig::default().with_level(level)
+    });
+
+    // Migrates objects through the same stores and locks the sessions use
+    if let Some(job) = recompress_job {
+        let recompressor = Recompressor::new(
+            Arc::clone(&storage),
+            Arc::clone(&chunk_store),
+            Arc::clone(&dictionaries),
+            Arc::clone(&symbol_store),
+            Arc::clone(&object_locks),
+        );
+        recompress::spawn_background(recompressor, job);
+    }
 
     loop {
         match listener.accept().await {
@@ -68,8 +80,11 @@ pub async fn run_on(addr: &str, data_dir: &str) -> anyhow::Result<()> {
                 metrics.connection_opened();
                 
                 let storage_clone = Arc::clone(&storage);
-                let global_dict_clone = Arc::clone(&global_dict);
+                let dictionaries_clone = Arc::clone(&dictionaries);
                 let symbol_store_clone = Arc::clone(&symbol_store);
+                let chunk_store_clone = Arc::clone(&chunk_store);
+                let object_locks_clone = Arc::clone(&object_locks);
+                let engine_config_clone = Arc::clone(&engine_config);
                 let coordination_clone = Arc::clone(&coordination);
                 let metrics_clone = Arc::clone(&metrics);
                 let metrics_for_cleanup = Arc::clone(&metrics);
@@ -78,8 +93,11 @@ pub async fn run_on(addr: &str, data_dir: &str) -> anyhow::Result<()> {
                     let session = Session::new(
                         socket, 
                         storage_clone, 
-                        global_dict_clone, 
+                        dictionaries_clone, 
                         symbol_store_clone, 
+                        chunk_store_clone,
+                        object_locks_clone,
+                        engine_config_clone,
                         Some(coordination_clone),
                         Some(metrics_clone)
                     );
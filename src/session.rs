@@ -1,58 +1,231 @@
 use tokio::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::io::Write;
+use std::sync::Arc;
 use tracing::{info, error, warn};
 
 use crate::protocol::{
     handshake::{read_handshake, write_handshake},
-    frame::{Frame, read_frame, write_frame}, MAX_FILE_SIZE,
+    frame::{Frame, read_frame, write_frame}, CHUNK_SIZE, MAX_FILE_SIZE,
+};
+use crate::engine::{
+    learn_symbols, summarize, decompress_object, decompress_object_range, dictionary_id, LEGACY_MUTABLE_ID,
+    block::BlockEncoder, config::{CompressionLevel, EngineConfig}, error::DecompressError, estimate::incompressible_reason,
+    codec::{choose_codec, encode_or_store, CHUNKED_CODEC, SYMBOL_CODEC, STORED_CODEC}, prune::prune_dictionary,
+    stream::decompress_object_stream,
 };
-use crate::engine::{compress, decompress};
 use crate::storage::{
-    StorageEngine,
+    StorageEngine, StoredObject,
     dictionary::Dictionary,
-    metadata::ObjectMetadata,
+    metadata::{ObjectMetadata, SymbolInfo},
+    chunks::ChunkStore,
+    registry::DictionaryRegistry,
     symbols::SymbolStore,
 };
 use crate::engine::hash::sha256;
-use crate::coordination::CoordinationManager;
+use crate::dedup::{stores_as_chunks, DedupWriter};
+use crate::coordination::{CoordinationManager, ObjectLocks};
 use crate::metrics::MetricsCollector;
 
 pub struct Session<S: StorageEngine> {
     stream: TcpStream,
     storage: Arc<S>,
-    global_dict: Arc<Mutex<Dictionary>>,
+    dictionaries: Arc<DictionaryRegistry>,
     symbol_store: Arc<SymbolStore>,
-    user_dict: Dictionary,
+    chunk_store: Arc<ChunkStore>,
+    object_locks: Arc<ObjectLocks>,
+    engine_config: Arc<EngineConfig>,
     coordination: Option<Arc<CoordinationManager>>,
     metrics: Option<Arc<MetricsCollector>>,
     // Chunked upload state
     chunked_uploads: std::collections::HashMap<String, ChunkedUpload>,
 }
 
-#[derive(Debug)]
 struct ChunkedUpload {
-    key: String,
     total_size: u64,
     chunk_count: u32,
-    received_chunks: std::collections::HashMap<u32, Vec<u8>>,
+    // Chunks that arrived ahead of `next_chunk`
+    pending_chunks: std::collections::HashMap<u32, Vec<u8>>,
+    next_chunk: u32,
+    // Bytes of every chunk accepted so far
+    received: u64,
+    // Created from the first chunk, which also seeds a mutable dictionary
+    encoder: Option<BlockEncoder>,
+    compressed: Vec<u8>,
+    symbol_infos: Vec<SymbolInfo>,
+    codec: &'static str,
+    stored_reason: Option<String>,
     user_id: Option<String>,
+    // Engine settings at the level the upload asked for
+    config: EngineConfig,
+    // Set when the upload is stored as deduplicated chunks instead
+    dedup: Option<DedupWriter>,
+}
+
+impl ChunkedUpload {
+    fn new(total_size: u64, chunk_count: u32, user_id: Option<String>, config: EngineConfig, dedup: Option<DedupWriter>) -> Self {
+        Self {
+            total_size,
+            chunk_count,
+            pending_chunks: std::collections::HashMap::new(),
+            next_chunk: 0,
+            received: 0,
+            encoder: None,
+            compressed: Vec::new(),
+            symbol_infos: Vec::new(),
+            codec: SYMBOL_CODEC,
+            stored_reason: None,
+            user_id,
+            config,
+            dedup,
+        }
+    }
+
+    /// Queues a chunk for `compress_ready`. Chunks already received, past
+    /// the announced count, or that would take the upload past its
+    /// announced size are refused, so a client cannot make the server hold
+    /// more than it declared.
+    fn accept(&mut self, chunk_index: u32, data: Vec<u8>) -> anyhow::Result<()> {
+        if chunk_index >= self.chunk_count {
+            return Err(anyhow::anyhow!("Chunk {} is past the {} announced", chunk_index, self.chunk_count));
+        }
+        if chunk_index < self.next_chunk || self.pending_chunks.contains_key(&chunk_index) {
+            return Err(anyhow::anyhow!("Chunk {} was already received", chunk_index));
+        }
+        let received = self.received + data.len() as u64;
+        if received > self.total_size {
+            return Err(anyhow::anyhow!("Chunks exceed the announced {} bytes", self.total_size));
+        }
+        self.received = received;
+        self.pending_chunks.insert(chunk_index, data);
+        Ok(())
+    }
+
+    /// Encodes every chunk that is next in order
+    fn compress_ready(
+        &mut self,
+        dictionaries: &DictionaryRegistry,
+        symbol_store: &SymbolStore,
+        chunk_store: &ChunkStore,
+    ) -> anyhow::Result<()> {
+        let config = &self.config;
+        while let Some(chunk) = self.pending_chunks.remove(&self.next_chunk) {
+            if let Some(writer) = self.dedup.as_mut() {
+                writer.push(&chunk, dictionaries, symbol_store, chunk_store)?;
+                self.next_chunk += 1;
+                continue;
+            }
+            if self.encoder.is_none() {
+                // Same choice as `choose_codec`, judged from the first chunk
+                self.stored_reason = incompressible_reason(&chunk);
+                if self.stored_reason.is_some() {
+                    self.codec = STORED_CODEC;
+                }
+                // Later uploads may grow the dictionary before this one
+                // finishes; the generation it is encoded against stays put
+                let dict = if self.stored_reason.is_some() {
+                    dictionaries.current(self.user_id.as_deref(), config)?
+                } else {
+                    let (dict, learned) = dictionaries.learn(self.user_id.as_deref(), config, |dict| {
+                        learn_symbols(&chunk, dict, symbol_store, config)
+                    })?;
+                    self.symbol_infos = learned;
+                    dict
+                };
+                let mut encoder = BlockEncoder::new(&dict, config.entropy_coder);
+                if self.stored_reason.is_some() {
+                    encoder.store_raw();
+                } else if config.back_references {
+                    encoder.enable_back_references();
+                }
+                self.encoder = Some(encoder);
+            }
+            let encoder = self.encoder.as_mut().unwrap();
+            encoder.push(&chunk);
+            self.compressed.extend(encoder.take_output());
+            self.next_chunk += 1;
+        }
+        Ok(())
+    }
+
+    /// Encodes what is left and returns the object's metadata and blob
+    fn finish(
+        self,
+        key: String,
+        dictionaries: &DictionaryRegistry,
+        symbol_store: &SymbolStore,
+        chunk_store: &ChunkStore,
+    ) -> anyhow::Result<(ObjectMetadata, Vec<u8>)> {
+        if let Some(writer) = self.dedup {
+            let meta = writer.finish(dictionaries, symbol_store, chunk_store)?;
+            if meta.original_size != self.total_size {
+                chunk_store.release(&meta.key, meta.chunks.iter().map(|chunk| chunk.hash.as_str()))?;
+                return Err(anyhow::anyhow!("Size mismatch: expected {}, got {}", self.total_size, meta.original_size));
+            }
+            return Ok((meta, Vec::new()));
+        }
+        let config = &self.config;
+        let encoder = match self.encoder {
+            Some(encoder) => encoder,
+            None => {
+                let dict = dictionaries.current(self.user_id.as_deref(), config)?;
+                BlockEncoder::new(&dict, config.entropy_coder)
+            }
+        };
+        let (tail, summary) = encoder.finish();
+        let mut compressed_data = self.compressed;
+        compressed_data.extend(tail);
+        
+        if summary.original_size != self.total_size {
+            return Err(anyhow::anyhow!("Size mismatch: expected {}, got {}", self.total_size, summary.original_size));
+        }
+        
+        // Tokens are only meaningful in the generation they were encoded
+        // against; the live dictionary may have been pruned since
+        let dict = dictionaries.resolve(&summary.dict_id)?;
+        let (symbol_infos, explained_ratio, token_breakdown) =
+            summarize(&summary, &dict, symbol_store, &key, self.symbol_infos, self.stored_reason);
+        
+        let mut meta = ObjectMetadata::new(
+            key,
+            summary.content_hash,
+            summary.content_hash,
+            summary.dict_id,
+            summary.original_size,
+            compressed_data.len() as u64,
+            self.user_id,
+        );
+        
+        meta.symbols = symbol_infos;
+        meta.explained_ratio = explained_ratio;
+        meta.token_breakdown = token_breakdown;
+        meta.entropy_coder = config.entropy_coder;
+        meta.level = config.level;
+        meta.codec = self.codec.to_string();
+        Ok((meta, compressed_data))
+    }
 }
 
 impl<S: StorageEngine> Session<S> {
+    #[allow(clippy::too_many_arguments)] // One per store the server shares between sessions
     pub fn new(
         stream: TcpStream,
         storage: Arc<S>,
-        global_dict: Arc<Mutex<Dictionary>>,
+        dictionaries: Arc<DictionaryRegistry>,
         symbol_store: Arc<SymbolStore>,
+        chunk_store: Arc<ChunkStore>,
+        object_locks: Arc<ObjectLocks>,
+        engine_config: Arc<EngineConfig>,
         coordination: Option<Arc<CoordinationManager>>,
         metrics: Option<Arc<MetricsCollector>>,
     ) -> Self {
         Self {
             stream,
             storage,
-            global_dict,
+            dictionaries,
             symbol_store,
-            user_dict: Dictionary::new("session".to_string()),
+            chunk_store,
+            object_locks,
+            engine_config,
             coordination,
             metrics,
             chunked_uploads: std::collections::HashMap::new(),
@@ -60,6 +233,19 @@ impl<S: StorageEngine> Session<S> {
     }
 
     pub async fn run(mut self) -> anyhow::Result<()> {
+        let result = self.serve().await;
+        // Uploads the client never finished still hold chunk references
+        for (key, upload) in self.chunked_uploads.drain() {
+            if let Some(writer) = upload.dedup {
+                if let Err(e) = writer.abort(&self.chunk_store) {
+                    error!("Failed to release chunks of unfinished upload '{}': {}", key, e);
+                }
+            }
+        }
+        result
+    }
+
+    async fn serve(&mut self) -> anyhow::Result<()> {
 
         
         let _client = read_handshake(&mut self.stream).await?;
@@ -78,9 +264,9 @@ impl<S: StorageEngine> Session<S> {
             };
 
             match frame {
-                Frame::Upload { key, data, user_id } => {
+                Frame::Upload { key, data, user_id, level } => {
                     info!("Processing upload: key='{}', size={} bytes", key, data.len());
-                    if let Err(e) = self.handle_upload(key.clone(), data, user_id).await {
+                    if let Err(e) = self.handle_upload(key.clone(), data, user_id, level).await {
                         error!("Upload failed for key '{}': {}", key, e);
                         return Err(e);
                     }
@@ -94,6 +280,14 @@ impl<S: StorageEngine> Session<S> {
                     }
                 }
 
+                Frame::DownloadRange { key, offset, len } => {
+                    info!("Processing range download: key='{}', offset={}, len={}", key, offset, len);
+                    if let Err(e) = self.handle_download_range(key.clone(), offset, len).await {
+                        error!("Range download failed for key '{}': {}", key, e);
+                        return Err(e);
+                    }
+                }
+
                 Frame::Verify { key } => {
                     info!("Processing verify: key='{}", key);
                     if let Err(e) = self.handle_verify(key.clone()).await {
@@ -106,33 +300,11 @@ impl<S: StorageEngine> Session<S> {
                     info!("Freezing global dictionary");
                     if let Some(coord) = &self.coordination {
                         coord.with_dictionary_lock(|| {
-                            let mut global_dict = self.global_dict.lock().unwrap();
-                            if !global_dict.frozen {
-                                let dict_id = global_dict.freeze();
-                                info!("Dictionary frozen with ID: {}", dict_id);
-                                
-                                // Save frozen dictionary to disk
-                                let dict_path = format!("{}/dictionary_{}.json", "./data", dict_id);
-                                if let Ok(dict_json) = serde_json::to_string_pretty(&*global_dict) {
-                                    let _ = std::fs::write(&dict_path, dict_json);
-                                    info!("Dictionary saved to: {}", dict_path);
-                                }
-                            }
+                            self.freeze_global_dictionary();
                             Ok(())
                         }).unwrap_or_else(|e| error!("Dictionary freeze coordination failed: {}", e));
                     } else {
-                        let mut global_dict = self.global_dict.lock().unwrap();
-                        if !global_dict.frozen {
-                            let dict_id = global_dict.freeze();
-                            info!("Dictionary frozen with ID: {}", dict_id);
-                            
-                            // Save frozen dictionary to disk
-                            let dict_path = format!("{}/dictionary_{}.json", "./data", dict_id);
-                            if let Ok(dict_json) = serde_json::to_string_pretty(&*global_dict) {
-                                let _ = std::fs::write(&dict_path, dict_json);
-                                info!("Dictionary saved to: {}", dict_path);
-                            }
-                        }
+                        self.freeze_global_dictionary();
                     }
                 }
 
@@ -141,28 +313,39 @@ impl<S: StorageEngine> Session<S> {
                     break;
                 }
                 
-                Frame::ChunkStart { key, total_size, chunk_count, user_id } => {
+                Frame::ChunkStart { key, total_size, chunk_count, user_id, level } => {
                     info!("Starting chunked upload: key='{}', total_size={}, chunks={}", key, total_size, chunk_count);
                     if total_size > MAX_FILE_SIZE as u64 {
                         error!("File too large: {} bytes (max: {})", total_size, MAX_FILE_SIZE);
                         return Err(anyhow::anyhow!("File too large"));
                     }
-                    self.chunked_uploads.insert(key.clone(), ChunkedUpload {
-                        key: key.clone(),
-                        total_size,
-                        chunk_count,
-                        received_chunks: std::collections::HashMap::new(),
-                        user_id,
-                    });
+                    let config = self.level_config(level);
+                    let dedup = stores_as_chunks(total_size, &config)
+                        .then(|| DedupWriter::new(key.clone(), user_id.clone(), config.clone()));
+                    let replaced = self.chunked_uploads.insert(
+                        key.clone(),
+                        ChunkedUpload::new(total_size, chunk_count, user_id, config, dedup),
+                    );
+                    // A restarted upload gives up what the first attempt referenced
+                    if let Some(writer) = replaced.and_then(|upload| upload.dedup) {
+                        writer.abort(&self.chunk_store)?;
+                    }
                 }
                 
                 Frame::ChunkData { key, chunk_index, data } => {
                     if let Some(upload) = self.chunked_uploads.get_mut(&key) {
-                        upload.received_chunks.insert(chunk_index, data);
+                        if let Err(e) = upload.accept(chunk_index, data) {
+                            error!("Chunked upload rejected for key '{}': {}", key, e);
+                            return Err(e);
+                        }
+                        if let Err(e) = self.compress_ready_chunks(&key).await {
+                            error!("Chunked upload compression failed for key '{}': {}", key, e);
+                            return Err(e);
+                        }
                         
                         // Check if all chunks received
-                        if upload.received_chunks.len() == upload.chunk_count as usize {
-                            info!("All chunks received for key '{}', assembling file", key);
+                        if self.chunked_uploads[&key].next_chunk == self.chunked_uploads[&key].chunk_count {
+                            info!("All chunks received for key '{}', finishing upload", key);
                             if let Err(e) = self.handle_chunked_complete(key.clone()).await {
                                 error!("Chunked upload assembly failed for key '{}': {}", key, e);
                                 return Err(e);
@@ -178,7 +361,7 @@ impl<S: StorageEngine> Session<S> {
                     // ChunkEnd is optional - file is complete when all chunks received
                 }
                 
-                Frame::Ack { .. } | Frame::Data { .. } | Frame::NotFound { .. } | Frame::Verified { .. } => {
+                Frame::Ack { .. } | Frame::Data { .. } | Frame::NotFound { .. } | Frame::Verified { .. } | Frame::Error { .. } => {
                     warn!("Received response frame in server context, ignoring");
                 }
             }
@@ -193,43 +376,124 @@ impl<S: StorageEngine> Session<S> {
         key: String,
         data: Vec<u8>,
         user_id: Option<String>,
+        level: Option<CompressionLevel>,
     ) -> anyhow::Result<()> {
         let original_size = data.len() as u64;
         let content_hash = sha256(&data);
         let original_hash = content_hash;
 
-        let (compressed_data, dict_id, symbol_infos, explained_ratio, token_breakdown) = {
-            let mut global_dict = self.global_dict.lock().unwrap();
-            let (compressed, symbols, ratio, breakdown) = compress(&data, &mut global_dict, &self.symbol_store, &key);
-            let dict_id = if global_dict.frozen {
-                global_dict.id.clone()
-            } else {
-                "mutable".to_string()
-            };
-            (compressed, dict_id, symbols, ratio, breakdown)
-        };
-
-        let compressed_size = compressed_data.len() as u64;
-        info!("Upload: {} -> {} bytes ({:.1}%), explained: {:.1}%", 
-              original_size, compressed_size, 
-              (compressed_size as f64 / original_size as f64) * 100.0,
-              explained_ratio * 100.0);
+        let codec = choose_codec(&data);
+        let dictionaries = Arc::clone(&self.dictionaries);
+        let symbol_store = Arc::clone(&self.symbol_store);
+        let config = Arc::new(self.level_config(level));
+        if stores_as_chunks(original_size, &config) {
+            return self.store_as_chunks(key, data, user_id, config).await;
+        }
+        let (entropy_coder, level) = (config.entropy_coder, config.level);
+        let dict_user_id = user_id.clone();
+        let object_key = key.clone();
+        // Encoding is CPU bound, so it runs on a blocking thread, against
+        // the generation published once the dictionary has learned from
+        // the upload
+        let (codec, compressed_data, dict_id, symbol_infos, explained_ratio, token_breakdown) =
+            tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+                let (generation, learned) = dictionaries.learn(dict_user_id.as_deref(), &config, |dict| {
+                    codec.learn(&data, dict, &symbol_store, &config)
+                })?;
+                let dict_id = dictionary_id(&generation);
+                let (codec, (compressed, symbols, ratio, breakdown)) =
+                    encode_or_store(codec, &data, &generation, &symbol_store, &object_key, &config, learned);
+                Ok((codec, compressed, dict_id, symbols, ratio, breakdown))
+            }).await??;
 
         let mut meta = ObjectMetadata::new(
-            key.clone(),
+            key,
             content_hash,
             original_hash,
             dict_id,
             original_size,
-            compressed_size,
+            compressed_data.len() as u64,
             user_id,
         );
         
         meta.symbols = symbol_infos;
         meta.explained_ratio = explained_ratio;
         meta.token_breakdown = token_breakdown;
+        meta.entropy_coder = entropy_coder;
+        meta.level = level;
+        meta.codec = codec.id().to_string();
+
+        self.store_object(meta, compressed_data).await
+    }
 
-        self.storage.put(&key, &compressed_data, &meta).await?;
+    /// Stores a whole upload as deduplicated chunks
+    async fn store_as_chunks(
+        &mut self,
+        key: String,
+        data: Vec<u8>,
+        user_id: Option<String>,
+        config: Arc<EngineConfig>,
+    ) -> anyhow::Result<()> {
+        let dictionaries = Arc::clone(&self.dictionaries);
+        let symbol_store = Arc::clone(&self.symbol_store);
+        let chunk_store = Arc::clone(&self.chunk_store);
+        let meta = tokio::task::spawn_blocking(move || {
+            let mut writer = DedupWriter::new(key, user_id, EngineConfig::clone(&config));
+            if let Err(e) = writer.push(&data, &dictionaries, &symbol_store, &chunk_store) {
+                writer.abort(&chunk_store)?;
+                return Err(e);
+            }
+            writer.finish(&dictionaries, &symbol_store, &chunk_store)
+        }).await??;
+
+        self.store_object(meta, Vec::new()).await
+    }
+
+    /// Engine settings for an upload, at the level it asked for or the
+    /// server's default
+    fn level_config(&self, level: Option<CompressionLevel>) -> EngineConfig {
+        match level {
+            Some(level) => self.engine_config.with_level(level),
+            None => EngineConfig::clone(&self.engine_config),
+        }
+    }
+
+    async fn store_object(
+        &mut self,
+        meta: ObjectMetadata,
+        compressed_data: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let original_size = meta.original_size;
+        let compressed_size = meta.compressed_size;
+        info!("Upload: {} -> {} bytes ({:.1}%), explained: {:.1}%", 
+              original_size, compressed_size, 
+              (compressed_size as f64 / original_size as f64) * 100.0,
+              meta.explained_ratio * 100.0);
+        if meta.token_breakdown.deduplicated_bytes > 0 {
+            info!("Upload: {} bytes were already stored in shared chunks", meta.token_breakdown.deduplicated_bytes);
+        }
+
+        // Held until the previous version is read and replaced, so a
+        // background recompress cannot swap in a stale copy in between
+        let object_locks = Arc::clone(&self.object_locks);
+        let _guard = object_locks.lock(&meta.key).await;
+        let stored = match self.storage.get_metadata(&meta.key).await {
+            Ok(previous) => self.storage.put(&meta.key, &compressed_data, &meta).await.map(|()| previous),
+            Err(e) => Err(e),
+        };
+        let previous = match stored {
+            Ok(previous) => previous,
+            Err(e) => {
+                // Nothing keeps the chunks this version referenced
+                self.chunk_store.release(&meta.key, meta.chunks.iter().map(|chunk| chunk.hash.as_str()))?;
+                return Err(e);
+            }
+        };
+        // Chunks the replaced version referenced are released once the
+        // new one is in place
+        if let Some(previous) = previous.filter(|previous| !previous.chunks.is_empty()) {
+            self.chunk_store.release(&previous.key, previous.chunks.iter().map(|chunk| chunk.hash.as_str()))?;
+        }
         
         // Record metrics
         if let Some(metrics) = &self.metrics {
@@ -240,7 +504,7 @@ impl<S: StorageEngine> Session<S> {
         write_frame(
             &mut self.stream,
             Frame::Ack {
-                key: key.clone(),
+                key: meta.key,
                 original_size,
                 compressed_size,
             },
@@ -250,6 +514,100 @@ impl<S: StorageEngine> Session<S> {
         Ok(())
     }
 
+    /// Decodes an object with the exact dictionary generation it names, on
+    /// a blocking thread so large objects do not stall other sessions
+    async fn decode_object(&self, obj: StoredObject) -> Result<Vec<u8>, DecompressError> {
+        let dictionaries = Arc::clone(&self.dictionaries);
+        let chunk_store = Arc::clone(&self.chunk_store);
+        tokio::task::spawn_blocking(move || {
+            if obj.metadata.codec == CHUNKED_CODEC {
+                return chunk_store.assemble(&obj.metadata, &dictionaries);
+            }
+            let dict = object_dictionary(&obj.metadata, &dictionaries)?;
+            decompress_object(&obj.data, &obj.metadata, &dict)
+        })
+        .await
+        .map_err(|e| DecompressError::Io(std::io::Error::other(e)))?
+    }
+    
+    /// Sends an object too large for one frame as chunk frames, decoding on
+    /// a blocking thread at most a chunk ahead of the connection. The hash
+    /// is only checked once every block was decoded, so a corrupt object is
+    /// reported by an Error frame in place of ChunkEnd.
+    async fn stream_download(&mut self, key: String, obj: StoredObject) -> anyhow::Result<()> {
+        let total_size = obj.metadata.original_size;
+        let chunk_count = total_size.div_ceil(CHUNK_SIZE as u64) as u32;
+        write_frame(
+            &mut self.stream,
+            Frame::ChunkStart { key: key.clone(), total_size, chunk_count, user_id: None, level: None },
+        )
+        .await?;
+        
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let dictionaries = Arc::clone(&self.dictionaries);
+        let chunk_store = Arc::clone(&self.chunk_store);
+        let decode = tokio::task::spawn_blocking(move || {
+            let mut sender = ChunkSender { chunk: Vec::new(), tx };
+            let written = if obj.metadata.codec == CHUNKED_CODEC {
+                chunk_store.assemble_to(&obj.metadata, &dictionaries, &mut sender)?
+            } else {
+                let dict = object_dictionary(&obj.metadata, &dictionaries)?;
+                decompress_object_stream(&obj.data, &obj.metadata, &dict, &mut sender)?
+            };
+            sender.flush()?;
+            Ok::<_, DecompressError>(written)
+        });
+        
+        let mut chunk_index = 0;
+        while let Some(data) = rx.recv().await {
+            write_frame(&mut self.stream, Frame::ChunkData { key: key.clone(), chunk_index, data }).await?;
+            chunk_index += 1;
+        }
+        
+        let decoded = decode.await.map_err(|e| DecompressError::Io(std::io::Error::other(e)))?;
+        match decoded {
+            Ok(written) => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_download(written);
+                }
+                write_frame(&mut self.stream, Frame::ChunkEnd { key: key.clone() }).await?;
+                info!("Download streamed for key: {} ({} bytes in {} chunks)", key, written, chunk_index);
+            }
+            Err(e) => {
+                error!("Cannot decompress key '{}': {}", key, e);
+                write_frame(&mut self.stream, Frame::Error { key, message: e.to_string() }).await?;
+            }
+        }
+        Ok(())
+    }
+    
+    fn freeze_global_dictionary(&self) {
+        if self.dictionaries.global().frozen {
+            return;
+        }
+        let frozen = self.dictionaries.update_global(|global_dict| {
+            // Another session may have frozen it first
+            if global_dict.frozen {
+                return None;
+            }
+            // Frozen symbols are tokenized against by every later upload, so
+            // dead ones are dropped first
+            let (pruned, report) = prune_dictionary(global_dict, &self.symbol_store, self.engine_config.prune_min_contribution);
+            info!("Pruned {} of {} symbols before freezing", report.evicted.len(), report.symbols_before);
+            *global_dict = pruned;
+            Some(global_dict.freeze())
+        });
+        
+        match frozen {
+            Ok((_, Some(dict_id))) => match self.dictionaries.activate(&dict_id) {
+                Ok(()) => info!("Dictionary frozen and saved with ID: {}", dict_id),
+                Err(e) => error!("Failed to record frozen dictionary {} as active: {}", dict_id, e),
+            },
+            Ok((_, None)) => {}
+            Err(e) => error!("Failed to save frozen dictionary: {}", e),
+        }
+    }
+    
     pub async fn handle_download(&mut self, key: String) -> anyhow::Result<()> {
         let Some(obj) = self.storage.get(&key).await? else {
             warn!("Key not found: {}", key);
@@ -257,10 +615,19 @@ impl<S: StorageEngine> Session<S> {
             return Ok(());
         };
         
-        // Use the global dictionary for decompression
-        let data = {
-            let global_dict = self.global_dict.lock().unwrap();
-            decompress(&obj.data, &global_dict)
+        if obj.metadata.original_size > CHUNK_SIZE as u64 {
+            return self.stream_download(key, obj).await;
+        }
+        
+        let decoded = self.decode_object(obj).await;
+        
+        let data = match decoded {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Cannot decompress key '{}': {}", key, e);
+                write_frame(&mut self.stream, Frame::Error { key, message: e.to_string() }).await?;
+                return Ok(());
+            }
         };
         
         info!("Decompressed to {} bytes", data.len());
@@ -283,6 +650,48 @@ impl<S: StorageEngine> Session<S> {
         Ok(())
     }
     
+    /// Answers with `len` bytes of an object from `offset`, decoding only
+    /// the blocks that cover them. Ranges are limited to one frame.
+    pub async fn handle_download_range(&mut self, key: String, offset: u64, len: u64) -> anyhow::Result<()> {
+        if len > CHUNK_SIZE as u64 {
+            let message = format!("range of {} bytes is larger than {} bytes", len, CHUNK_SIZE);
+            write_frame(&mut self.stream, Frame::Error { key, message }).await?;
+            return Ok(());
+        }
+        let Some(obj) = self.storage.get(&key).await? else {
+            warn!("Key not found: {}", key);
+            write_frame(&mut self.stream, Frame::NotFound { key }).await?;
+            return Ok(());
+        };
+        
+        let dictionaries = Arc::clone(&self.dictionaries);
+        let chunk_store = Arc::clone(&self.chunk_store);
+        let decoded = tokio::task::spawn_blocking(move || {
+            if obj.metadata.codec == CHUNKED_CODEC {
+                return chunk_store.assemble_range(&obj.metadata, &dictionaries, offset, len);
+            }
+            let dict = object_dictionary(&obj.metadata, &dictionaries)?;
+            decompress_object_range(&obj.data, &obj.metadata, &dict, offset, len)
+        })
+        .await
+        .map_err(|e| DecompressError::Io(std::io::Error::other(e)))?;
+        
+        let data = match decoded {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Cannot decompress range of key '{}': {}", key, e);
+                write_frame(&mut self.stream, Frame::Error { key, message: e.to_string() }).await?;
+                return Ok(());
+            }
+        };
+        
+        if let Some(metrics) = &self.metrics {
+            metrics.record_download(data.len() as u64);
+        }
+        write_frame(&mut self.stream, Frame::Data { key, data }).await?;
+        Ok(())
+    }
+    
     pub async fn handle_verify(&mut self, key: String) -> anyhow::Result<()> {
         let Some(obj) = self.storage.get(&key).await? else {
             warn!("Key not found for verification: {}", key);
@@ -290,30 +699,40 @@ impl<S: StorageEngine> Session<S> {
             return Ok(());
         };
         
-        // Decompress the data
-        let data = {
-            let global_dict = self.global_dict.lock().unwrap();
-            decompress(&obj.data, &global_dict)
+        let original_hash = obj.metadata.original_hash;
+        let decoded = self.decode_object(obj).await;
+        
+        let data = match decoded {
+            Ok(data) => data,
+            Err(e) => {
+                error!("CORRUPTION DETECTED for key '{}': {}", key, e);
+                write_frame(&mut self.stream, Frame::Error { key, message: e.to_string() }).await?;
+                return Ok(());
+            }
         };
         
         // Hash the reconstructed data
         let reconstructed_hash = sha256(&data);
         
         // Compare with stored original hash
-        let hash_match = reconstructed_hash == obj.metadata.original_hash;
-        
-        if !hash_match {
-            error!("CORRUPTION DETECTED for key '{}': hash mismatch", key);
-            panic!("FATAL: CORRUPTION DETECTED - refusing service");
+        if reconstructed_hash != original_hash {
+            let message = format!(
+                "hash mismatch: expected {}, reconstructed {}",
+                hex::encode(original_hash),
+                hex::encode(reconstructed_hash),
+            );
+            error!("CORRUPTION DETECTED for key '{}': {}", key, message);
+            write_frame(&mut self.stream, Frame::Error { key, message }).await?;
+            return Ok(());
         }
         
-        info!("Verification completed for key '{}': hash_match={}", key, hash_match);
+        info!("Verification completed for key '{}': hash_match=true", key);
         
         write_frame(
             &mut self.stream,
             Frame::Verified {
                 key: key.clone(),
-                hash_match,
+                hash_match: true,
             },
         )
         .await?;
@@ -321,27 +740,94 @@ impl<S: StorageEngine> Session<S> {
         Ok(())
     }
     
+    /// Compresses every chunk that is next in order, so chunks are encoded
+    /// as they arrive instead of being assembled first.
+    async fn compress_ready_chunks(&mut self, key: &str) -> anyhow::Result<()> {
+        let Some(mut upload) = self.chunked_uploads.remove(key) else {
+            return Ok(());
+        };
+        let dictionaries = Arc::clone(&self.dictionaries);
+        let symbol_store = Arc::clone(&self.symbol_store);
+        let chunk_store = Arc::clone(&self.chunk_store);
+        let (upload, result) = tokio::task::spawn_blocking(move || {
+            let result = upload.compress_ready(&dictionaries, &symbol_store, &chunk_store);
+            (upload, result)
+        }).await?;
+        self.chunked_uploads.insert(key.to_string(), upload);
+        result
+    }
+    
     async fn handle_chunked_complete(&mut self, key: String) -> anyhow::Result<()> {
         let upload = self.chunked_uploads.remove(&key)
             .ok_or_else(|| anyhow::anyhow!("Chunked upload not found"))?;
         
-        // Assemble chunks in order
-        let mut data = Vec::with_capacity(upload.total_size as usize);
-        for i in 0..upload.chunk_count {
-            if let Some(chunk) = upload.received_chunks.get(&i) {
-                data.extend_from_slice(chunk);
-            } else {
-                return Err(anyhow::anyhow!("Missing chunk {}", i));
-            }
-        }
-        
-        if data.len() != upload.total_size as usize {
-            return Err(anyhow::anyhow!("Size mismatch: expected {}, got {}", upload.total_size, data.len()));
-        }
+        let dictionaries = Arc::clone(&self.dictionaries);
+        let symbol_store = Arc::clone(&self.symbol_store);
+        let chunk_store = Arc::clone(&self.chunk_store);
+        let (meta, compressed_data) =
+            tokio::task::spawn_blocking(move || upload.finish(key, &dictionaries, &symbol_store, &chunk_store))
+                .await??;
         
-        info!("Assembled chunked upload: key='{}', size={} bytes", key, data.len());
+        info!("Compressed chunked upload: key='{}', size={} bytes", meta.key, meta.original_size);
         
-        // Process as normal upload
-        self.handle_upload(key, data, upload.user_id).await
+        self.store_object(meta, compressed_data).await
+    }
+}
+
+/// Dictionary generation an object was encoded against
+fn object_dictionary(meta: &ObjectMetadata, dictionaries: &DictionaryRegistry) -> Result<Arc<Dictionary>, DecompressError> {
+    if meta.dict_id == LEGACY_MUTABLE_ID {
+        // The generation was never recorded; the current global dictionary
+        // is the only candidate left
+        return Ok(dictionaries.global());
+    }
+    dictionaries.resolve(&meta.dict_id)
+}
+
+/// Cuts decoded bytes into `CHUNK_SIZE` pieces for the connection to send.
+/// The channel holds one piece, so a download never runs far ahead of it.
+struct ChunkSender {
+    chunk: Vec<u8>,
+    tx: tokio::sync::mpsc::Sender<Vec<u8>>,
+}
+
+impl Write for ChunkSender {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let take = (CHUNK_SIZE - self.chunk.len()).min(data.len());
+        self.chunk.extend_from_slice(&data[..take]);
+        if self.chunk.len() == CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(take)
+    }
+
+    /// Sends the piece collected so far, waiting while the channel is full
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        self.tx.blocking_send(std::mem::take(&mut self.chunk))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_held_to_what_the_upload_announced() {
+        let mut upload = ChunkedUpload::new(10, 3, None, EngineConfig::default(), None);
+        upload.accept(1, vec![0; 4]).unwrap();
+        assert!(upload.accept(1, vec![0; 4]).is_err());
+        assert!(upload.accept(3, vec![0; 1]).is_err());
+        assert!(upload.accept(0, vec![0; 7]).is_err());
+
+        upload.accept(0, vec![0; 4]).unwrap();
+        upload.next_chunk = 2;
+        upload.pending_chunks.clear();
+        assert!(upload.accept(0, vec![0; 1]).is_err());
+        upload.accept(2, vec![0; 2]).unwrap();
+        assert_eq!(upload.received, 10);
     }
 }
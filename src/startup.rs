@@ -2,7 +2,16 @@ use std::path::Path;
 use tracing::info;
 use anyhow::Result;
 
-use crate::storage::PersistentStorage;
+use crate::engine::{codec::CHUNKED_CODEC, decompressor::decompress_object};
+use crate::engine::error::DecompressError;
+use crate::storage::{ObjectMetadata, PersistentStorage};
+use crate::storage::{chunks::ChunkStore, dictionary::Dictionary, registry::DictionaryRegistry};
+
+/// A stored object that could not be decoded back to its original bytes
+pub struct ObjectFailure {
+    pub key: String,
+    pub error: DecompressError,
+}
 
 pub struct StartupValidator {
     storage: PersistentStorage,
@@ -49,4 +58,59 @@ impl StartupValidator {
         info!("Found {} symbol files", count);
         Ok(())
     }
-}
\ No newline at end of file
+    
+    /// Decodes every stored object against the dictionary generation it names
+    /// and returns the ones that fail, with the reason.
+    pub fn verify_objects(&self) -> Result<Vec<ObjectFailure>> {
+        let files_dir = self.storage.root_path.join("files");
+        let mut failures = Vec::new();
+        
+        if !files_dir.exists() {
+            info!("No files directory - nothing to decode");
+            return Ok(failures);
+        }
+        
+        let data_dir = self.storage.root_path.to_string_lossy().to_string();
+        let chunk_store = ChunkStore::new(data_dir.clone());
+        let dictionaries = DictionaryRegistry::new(data_dir, Dictionary::new("global"));
+        let mut checked = 0;
+        
+        for entry in std::fs::read_dir(&files_dir)? {
+            let path = entry?.path();
+            let Some(key) = path.file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".meta"))
+                .map(str::to_string) else {
+                continue;
+            };
+            
+            let meta: ObjectMetadata = serde_json::from_slice(&std::fs::read(&path)?)?;
+            let data = std::fs::read(files_dir.join(&key))?;
+            checked += 1;
+            
+            if meta.codec == CHUNKED_CODEC {
+                if let Err(error) = chunk_store.assemble(&meta, &dictionaries) {
+                    failures.push(ObjectFailure { key, error });
+                }
+                continue;
+            }
+            
+            // Objects written against the mutable dictionary before its
+            // generations were recorded cannot be checked offline
+            let dict = match dictionaries.resolve(&meta.dict_id) {
+                Ok(dict) => dict,
+                Err(error) => {
+                    failures.push(ObjectFailure { key, error });
+                    continue;
+                }
+            };
+            
+            if let Err(error) = decompress_object(&data, &meta, &dict) {
+                failures.push(ObjectFailure { key, error });
+            }
+        }
+        
+        info!("Decoded {} objects, {} failed", checked, failures.len());
+        Ok(failures)
+    }
+}
@@ -0,0 +1,345 @@
+//! Content-addressed store of compressed chunks shared between objects.
+//! Each chunk is kept once, with its own metadata, and counts the objects
+//! that reference it; it is deleted when the last reference goes.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use tracing::warn;
+
+use crate::engine::{
+    decompressor::{checked_range, decompress_object, decompress_object_range},
+    error::DecompressError,
+    hash::sha256,
+};
+use crate::storage::{metadata::ObjectMetadata, object::StoredObject, registry::DictionaryRegistry, swap};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkReferences {
+    pub chunk_hash: String,
+    pub total_references: u64,
+    pub objects: HashMap<String, u64>, // object key → references
+}
+
+pub struct ChunkStore {
+    data_dir: String,
+    // Held while a chunk's presence is checked and its references change,
+    // so a chunk is not deleted between being found and being referenced
+    lock: Mutex<()>,
+}
+
+impl ChunkStore {
+    /// Opens the store, completing replacements a crash cut short
+    pub fn new(data_dir: impl Into<String>) -> Self {
+        let data_dir = data_dir.into();
+        fs::create_dir_all(format!("{}/chunks", data_dir)).ok();
+        fs::create_dir_all(format!("{}/chunk_refs", data_dir)).ok();
+        if let Err(e) = swap::finish_interrupted_swaps(&Path::new(&data_dir).join("chunks")) {
+            warn!("Cannot complete interrupted chunk replacements in {}: {}", data_dir, e);
+        }
+        Self { data_dir, lock: Mutex::new(()) }
+    }
+
+    /// Blob and metadata files of a chunk
+    fn chunk_paths(&self, hash: &str) -> (PathBuf, PathBuf) {
+        let dir = Path::new(&self.data_dir).join("chunks");
+        (dir.join(hash), dir.join(format!("{}.meta", hash)))
+    }
+
+    fn references_path(&self, hash: &str) -> PathBuf {
+        Path::new(&self.data_dir).join("chunk_refs").join(hash)
+    }
+
+    /// Whether the chunk is stored. Its metadata is written last, so a
+    /// chunk whose blob is on disk without it was never completed.
+    pub fn contains(&self, hash: &str) -> bool {
+        self.chunk_paths(hash).1.exists()
+    }
+
+    /// Stores a chunk unless an upload stored it first. The chunk has no
+    /// references until `retain` is called for it.
+    pub fn insert(&self, hash: &str, blob: &[u8], meta: &ObjectMetadata) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        if self.contains(hash) {
+            return Ok(());
+        }
+        let (blob_path, meta_path) = self.chunk_paths(hash);
+        write_atomic(&blob_path, blob)?;
+        write_atomic(&meta_path, &serde_json::to_vec(meta)?)?;
+        Ok(())
+    }
+
+    /// Adds a reference from `object_key` to a stored chunk and returns the
+    /// chunk's metadata, or `None` if the chunk is not stored
+    pub fn retain(&self, hash: &str, object_key: &str) -> anyhow::Result<Option<ObjectMetadata>> {
+        let _guard = self.lock.lock().unwrap();
+        if !self.contains(hash) {
+            return Ok(None);
+        }
+        let meta: ObjectMetadata = serde_json::from_slice(&fs::read(self.chunk_paths(hash).1)?)?;
+        let mut references = self.references(hash)?;
+        *references.objects.entry(object_key.to_string()).or_insert(0) += 1;
+        references.total_references += 1;
+        write_atomic(&self.references_path(hash), &bincode::serialize(&references)?)?;
+        Ok(Some(meta))
+    }
+
+    /// Drops one reference from `object_key` per listed chunk. Chunks left
+    /// without references are deleted.
+    pub fn release<'a>(&self, object_key: &str, hashes: impl IntoIterator<Item = &'a str>) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        for hash in hashes {
+            let mut references = self.references(hash)?;
+            let Some(count) = references.objects.get_mut(object_key) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                references.objects.remove(object_key);
+            }
+            references.total_references -= 1;
+
+            if references.total_references == 0 {
+                let (blob_path, meta_path) = self.chunk_paths(hash);
+                // Metadata first, so a half-deleted chunk reads as absent
+                fs::remove_file(meta_path).ok();
+                fs::remove_file(blob_path).ok();
+                fs::remove_file(self.references_path(hash)).ok();
+            } else {
+                write_atomic(&self.references_path(hash), &bincode::serialize(&references)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn references(&self, hash: &str) -> anyhow::Result<ChunkReferences> {
+        let path = self.references_path(hash);
+        if !path.exists() {
+            return Ok(ChunkReferences {
+                chunk_hash: hash.to_string(),
+                total_references: 0,
+                objects: HashMap::new(),
+            });
+        }
+        Ok(bincode::deserialize(&fs::read(path)?)?)
+    }
+
+    /// A chunk's blob and metadata as stored, or `None` if it is not stored
+    pub fn get(&self, hash: &str) -> anyhow::Result<Option<StoredObject>> {
+        let (blob_path, meta_path) = self.chunk_paths(hash);
+        let Ok(meta_json) = fs::read(meta_path) else {
+            return Ok(None);
+        };
+        let metadata = serde_json::from_slice(&meta_json)?;
+        Ok(Some(StoredObject { data: fs::read(blob_path)?, metadata }))
+    }
+
+    /// Replaces a chunk's blob and metadata, as `StorageEngine::replace`
+    /// does for objects. A chunk released meanwhile is gone and stays gone.
+    pub fn replace(&self, hash: &str, blob: &[u8], meta: &ObjectMetadata, current: &ObjectMetadata) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        if !self.contains(hash) {
+            return Err(anyhow::anyhow!("chunk {} was released while it was being replaced", hash));
+        }
+        let (blob_path, meta_path) = self.chunk_paths(hash);
+        swap::replace_files(&blob_path, blob, &meta_path, meta, current)
+    }
+
+    /// Decodes one chunk and checks it against its hash
+    pub fn load(&self, hash: &str, dictionaries: &DictionaryRegistry) -> Result<Vec<u8>, DecompressError> {
+        let (blob, meta) = self.read(hash)?;
+        let dict = dictionaries.resolve(&meta.dict_id)?;
+        let data = decompress_object(&blob, &meta, &dict)?;
+        if hex::encode(sha256(&data)) != hash {
+            return Err(DecompressError::ChecksumMismatch);
+        }
+        Ok(data)
+    }
+
+    /// A chunk's blob and metadata, for decoding
+    fn read(&self, hash: &str) -> Result<(Vec<u8>, ObjectMetadata), DecompressError> {
+        let (blob_path, meta_path) = self.chunk_paths(hash);
+        let meta_json = fs::read(meta_path).map_err(|_| DecompressError::MissingChunk(hash.to_string()))?;
+        let meta: ObjectMetadata = serde_json::from_slice(&meta_json)
+            .map_err(|e| DecompressError::Io(std::io::Error::other(e)))?;
+        let blob = fs::read(blob_path).map_err(|_| DecompressError::MissingChunk(hash.to_string()))?;
+        Ok((blob, meta))
+    }
+
+    /// Reassembles an object written by `CHUNKED_CODEC` from its chunks
+    pub fn assemble(&self, meta: &ObjectMetadata, dictionaries: &DictionaryRegistry) -> Result<Vec<u8>, DecompressError> {
+        let mut out = Vec::with_capacity(meta.original_size as usize);
+        self.assemble_to(meta, dictionaries, &mut out)?;
+        Ok(out)
+    }
+
+    /// `assemble` into `writer`, one chunk in memory at a time. Returns the
+    /// number of bytes written.
+    pub fn assemble_to<W: Write>(
+        &self,
+        meta: &ObjectMetadata,
+        dictionaries: &DictionaryRegistry,
+        mut writer: W,
+    ) -> Result<u64, DecompressError> {
+        let mut written = 0;
+        for chunk in &meta.chunks {
+            let data = self.load(&chunk.hash, dictionaries)?;
+            writer.write_all(&data)?;
+            written += data.len() as u64;
+        }
+        if written != meta.original_size {
+            return Err(DecompressError::LengthMismatch {
+                expected: meta.original_size,
+                actual: written,
+            });
+        }
+        Ok(written)
+    }
+
+    /// `len` bytes of an object written by `CHUNKED_CODEC`, starting at
+    /// `offset`. Only the chunks covering the range are read, and only the
+    /// blocks of those that cover it decoded, so as with
+    /// `decompress_range` the chunk hashes are not checked.
+    pub fn assemble_range(
+        &self,
+        meta: &ObjectMetadata,
+        dictionaries: &DictionaryRegistry,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, DecompressError> {
+        let range = checked_range(offset, len, meta.original_size)?;
+        let mut out = Vec::new();
+        let mut chunk_start = 0;
+        for chunk in &meta.chunks {
+            if chunk_start >= range.end {
+                break;
+            }
+            let chunk_end = chunk_start + chunk.size;
+            if chunk_end > range.start {
+                let from = range.start.max(chunk_start) - chunk_start;
+                let to = range.end.min(chunk_end) - chunk_start;
+                let (blob, chunk_meta) = self.read(&chunk.hash)?;
+                let dict = dictionaries.resolve(&chunk_meta.dict_id)?;
+                out.extend(decompress_object_range(&blob, &chunk_meta, &dict, from, to - from)?);
+            }
+            chunk_start = chunk_end;
+        }
+        if out.len() as u64 != len {
+            return Err(DecompressError::LengthMismatch {
+                expected: len,
+                actual: out.len() as u64,
+            });
+        }
+        Ok(out)
+    }
+}
+
+/// Write then rename, so a crash never leaves a partial file under the
+/// final name
+fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> (ChunkStore, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("symvea-chunks-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        (ChunkStore::new(dir.to_string_lossy().to_string()), dir)
+    }
+
+    fn meta(hash: &str) -> ObjectMetadata {
+        ObjectMetadata::new(hash.to_string(), [0; 32], [0; 32], "dict".to_string(), 3, 3, None)
+    }
+
+    #[test]
+    fn chunk_lives_while_referenced() {
+        let (store, dir) = store("refs");
+        assert!(store.retain("c1", "a").unwrap().is_none());
+
+        store.insert("c1", b"abc", &meta("c1")).unwrap();
+        assert!(store.contains("c1"));
+        assert_eq!(store.retain("c1", "a").unwrap().unwrap().key, "c1");
+        store.retain("c1", "a").unwrap();
+        store.retain("c1", "b").unwrap();
+        let references = store.references("c1").unwrap();
+        assert_eq!(references.total_references, 3);
+        assert_eq!(references.objects["a"], 2);
+
+        // Unknown objects hold nothing to release
+        store.release("c", ["c1"]).unwrap();
+        store.release("a", ["c1", "c1"]).unwrap();
+        assert!(store.contains("c1"));
+        assert!(!store.references("c1").unwrap().objects.contains_key("a"));
+
+        store.release("b", ["c1"]).unwrap();
+        assert!(!store.contains("c1"));
+        assert!(!store.chunk_paths("c1").0.exists());
+        assert_eq!(store.references("c1").unwrap().total_references, 0);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn insert_keeps_the_first_copy_and_no_temp_files() {
+        let (store, dir) = store("insert");
+        store.insert("c1", b"abc", &meta("c1")).unwrap();
+        store.insert("c1", b"xyz", &meta("c1")).unwrap();
+        assert_eq!(fs::read(store.chunk_paths("c1").0).unwrap(), b"abc");
+        store.retain("c1", "a").unwrap();
+
+        for sub in ["chunks", "chunk_refs"] {
+            for entry in fs::read_dir(dir.join(sub)).unwrap() {
+                let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+                assert!(!name.ends_with(".tmp"), "{} left behind", name);
+            }
+        }
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn ranges_map_onto_the_chunk_list() {
+        use crate::engine::{codec::{Codec, StoredCodec, CHUNKED_CODEC}, config::EngineConfig};
+        use crate::storage::{dictionary::Dictionary, metadata::ChunkRef, symbols::SymbolStore};
+
+        let (store, dir) = store("ranges");
+        let data_dir = dir.to_string_lossy().to_string();
+        let symbol_store = SymbolStore::new(data_dir.clone());
+        let mut dict = Dictionary::new("global");
+        let dict_id = dict.freeze();
+        dict.save(&Dictionary::path(&data_dir, &dict_id)).unwrap();
+        let dictionaries = DictionaryRegistry::new(data_dir, Dictionary::new("global"));
+
+        let parts: [&[u8]; 3] = [b"first chunk|", b"second|", b"third chunk"];
+        let mut object = ObjectMetadata::new("obj".to_string(), [0; 32], [0; 32], dict_id.clone(), 0, 0, None);
+        object.codec = CHUNKED_CODEC.to_string();
+        for part in parts {
+            let hash = hex::encode(sha256(part));
+            let (blob, ..) = StoredCodec.encode(part, &dict, &symbol_store, &hash, &EngineConfig::default(), Vec::new());
+            let mut chunk = ObjectMetadata::new(hash.clone(), [0; 32], [0; 32], dict_id.clone(), part.len() as u64, blob.len() as u64, None);
+            chunk.codec = StoredCodec.id().to_string();
+            store.insert(&hash, &blob, &chunk).unwrap();
+            object.chunks.push(ChunkRef { hash, size: part.len() as u64 });
+            object.original_size += part.len() as u64;
+        }
+
+        let whole = parts.concat();
+        for (offset, len) in [(0, 5), (3, 20), (12, 7), (19, 11), (0, whole.len() as u64), (30, 0)] {
+            let range = store.assemble_range(&object, &dictionaries, offset, len).unwrap();
+            assert_eq!(range, &whole[offset as usize..(offset + len) as usize]);
+        }
+        assert!(matches!(
+            store.assemble_range(&object, &dictionaries, 25, 6),
+            Err(DecompressError::OutOfRange { .. })
+        ));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
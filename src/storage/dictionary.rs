@@ -1,16 +1,43 @@
 use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
 use serde::{Serialize, Deserialize};
 use crate::engine::hash::sha256;
+use crate::engine::tokenizer::SymbolMatcher;
+use crate::utils::limits::USER_TOKEN_BASE;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Dictionary {
     pub id: String,
+    // JSON map keys must be strings, so symbols are written as token pairs
+    #[serde(with = "token_pairs")]
     pub encode: HashMap<Vec<u8>, u32>,
+    // Written in token order, so equal dictionaries serialize (and hash)
+    // to equal bytes
+    #[serde(with = "sorted_tokens")]
     pub decode: HashMap<u32, Vec<u8>>,
     pub frozen: bool,
     pub created_at: u64,
     pub frozen_at: Option<u64>,
     pub version: String,
+    /// Owner of a user dictionary, whose symbols use the reserved token range
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Frozen global dictionary a user dictionary is layered on
+    #[serde(default)]
+    pub base: Option<String>,
+    /// Set only in a generation file that holds just the symbols appended
+    /// since this earlier generation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    // Compiled tokenizer trie, rebuilt lazily after the symbol set changes
+    #[serde(skip)]
+    matcher: OnceLock<Arc<SymbolMatcher>>,
+    // Content hash of the symbol set, recomputed after it changes
+    #[serde(skip)]
+    generation: OnceLock<String>,
+    // First unallocated token, found once and then advanced by inserts
+    #[serde(skip)]
+    next_free: OnceLock<u32>,
 }
 
 impl Dictionary {
@@ -26,9 +53,135 @@ impl Dictionary {
                 .as_secs(),
             frozen_at: None,
             version: "symvea-engine@0.1.0".to_string(),
+            user: None,
+            base: None,
+            parent: None,
+            matcher: OnceLock::new(),
+            generation: OnceLock::new(),
+            next_free: OnceLock::new(),
         }
     }
     
+    /// An empty dictionary for one user's symbols
+    pub fn new_user(user_id: &str) -> Self {
+        let mut dict = Self::new(format!("user-{}", user_id));
+        dict.user = Some(user_id.to_string());
+        dict
+    }
+    
+    /// Stacks the user symbols of `layer` on `base`, or uses them alone
+    pub fn layered(layer: &Dictionary, base: Option<&Dictionary>) -> Self {
+        let mut dict = Self::new(layer.id.clone());
+        if let Some(base) = base {
+            dict.encode = base.encode.clone();
+            dict.decode = base.decode.clone();
+            dict.base = Some(base.id.clone());
+        }
+        dict.user = layer.user.clone();
+        dict.created_at = layer.created_at;
+        for (&token, bytes) in &layer.decode {
+            if token >= USER_TOKEN_BASE {
+                dict.insert_symbol(bytes.clone(), token);
+            }
+        }
+        dict
+    }
+    
+    /// The symbols a user dictionary adds to its base, which is all that
+    /// gets persisted for it
+    pub fn layer(&self) -> Self {
+        let mut layer = Self::new(self.id.clone());
+        layer.user = self.user.clone();
+        layer.base = self.base.clone();
+        layer.created_at = self.created_at;
+        for (&token, bytes) in &self.decode {
+            if token >= USER_TOKEN_BASE {
+                layer.insert_symbol(bytes.clone(), token);
+            }
+        }
+        layer
+    }
+    
+    pub fn insert_symbol(&mut self, bytes: Vec<u8>, token: u32) {
+        self.encode.insert(bytes.clone(), token);
+        self.decode.insert(token, bytes);
+        self.matcher = OnceLock::new();
+        self.generation = OnceLock::new();
+        let range = self.token_range();
+        if let Some(next) = self.next_free.get_mut() {
+            if range.contains(&token) && token >= *next {
+                *next = token + 1;
+            }
+        }
+    }
+    
+    /// Returns the token for `bytes`, allocating the next unused one if the
+    /// symbol is new, or `None` once the dictionary's token range is used
+    /// up. Tokens are never reassigned, so content encoded against an
+    /// earlier generation decodes with every later one.
+    pub fn append_symbol(&mut self, bytes: Vec<u8>) -> Option<u32> {
+        if let Some(&token) = self.encode.get(&bytes) {
+            return Some(token);
+        }
+        let token = self.next_token()?;
+        self.insert_symbol(bytes, token);
+        Some(token)
+    }
+    
+    /// First token above every symbol allocated so far in this
+    /// dictionary's range, or `None` if none are left
+    pub fn next_token(&self) -> Option<u32> {
+        let range = self.token_range();
+        let next = *self.next_free.get_or_init(|| {
+            self.decode.keys()
+                .copied()
+                .filter(|token| range.contains(token))
+                .max()
+                .map_or(range.start, |token| token + 1)
+        });
+        range.contains(&next).then_some(next)
+    }
+    
+    /// Tokens this dictionary allocates: the reserved range for user
+    /// dictionaries. Global symbols stop short of it so user tokens never
+    /// collide with a base, and u32::MAX stays free for back-references.
+    fn token_range(&self) -> std::ops::Range<u32> {
+        if self.user.is_some() {
+            USER_TOKEN_BASE..u32::MAX
+        } else {
+            256..USER_TOKEN_BASE
+        }
+    }
+    
+    /// Identifies the exact symbol set, independent of when or in what
+    /// order the symbols were added.
+    pub fn generation_id(&self) -> String {
+        self.generation.get_or_init(|| {
+            let mut symbols: Vec<(&u32, &Vec<u8>)> = self.decode.iter().collect();
+            symbols.sort_unstable();
+            let serialized = bincode::serialize(&symbols).unwrap();
+            hex::encode(&sha256(&serialized)[..16])
+        }).clone()
+    }
+    
+    /// Symbols in token order
+    pub fn symbols(&self) -> Vec<(u32, &[u8])> {
+        let mut symbols: Vec<(u32, &[u8])> = self.decode.iter()
+            .map(|(&token, bytes)| (token, bytes.as_slice()))
+            .collect();
+        symbols.sort_unstable();
+        symbols
+    }
+    
+    /// Tokenizer trie for the current symbol set, compiled on first use.
+    pub fn matcher(&self) -> Arc<SymbolMatcher> {
+        Arc::clone(self.matcher.get_or_init(|| {
+            Arc::new(SymbolMatcher::new(
+                self.encode.iter().map(|(bytes, &token)| (bytes.as_slice(), token)),
+            ))
+        }))
+    }
+    
     pub fn freeze(&mut self) -> String {
         if self.frozen {
             return self.compute_hash();
@@ -47,17 +200,101 @@ impl Dictionary {
         dict_id
     }
     
+    pub fn path(data_dir: &str, dict_id: &str) -> String {
+        format!("{}/dictionary_{}.json", data_dir, dict_id)
+    }
+    
+    /// Where a generation of the mutable dictionary is kept. These live
+    /// apart from frozen dictionaries, which the server loads at startup.
+    pub fn generation_path(data_dir: &str, dict_id: &str) -> String {
+        format!("{}/dictionaries/{}.json", data_dir, dict_id)
+    }
+    
+    /// Where the id of the frozen dictionary the server starts with is
+    /// recorded. Pruned and trained dictionaries sit next to it, frozen
+    /// too, until one is activated.
+    pub fn active_path(data_dir: &str) -> String {
+        format!("{}/active_dictionary", data_dir)
+    }
+    
+    /// Where the latest symbols of a user dictionary are kept
+    pub fn user_path(data_dir: &str, user_id: &str) -> String {
+        format!("{}/users/{}.json", data_dir, user_id)
+    }
+    
+    /// Writes the dictionary as JSON, the form the server loads at startup.
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+    
+    /// Loads a frozen dictionary saved by `FreezeDictionary`, if present.
+    pub fn load_frozen(data_dir: &str, dict_id: &str) -> anyhow::Result<Option<Self>> {
+        let path = Self::path(data_dir, dict_id);
+        if !std::path::Path::new(&path).exists() {
+            return Ok(None);
+        }
+        let dict_json = std::fs::read_to_string(path)?;
+        Ok(Some(serde_json::from_str(&dict_json)?))
+    }
+    
     pub fn compute_hash(&self) -> String {
         let serialized = bincode::serialize(self).unwrap();
         let hash = sha256(&serialized);
         hex::encode(&hash[..16])
     }
-    
-    pub fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+
+}
+
+mod token_pairs {
+    use std::collections::HashMap;
+    use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+    pub fn serialize<S: Serializer>(encode: &HashMap<Vec<u8>, u32>, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut pairs: Vec<(u32, &Vec<u8>)> = encode.iter().map(|(bytes, &token)| (token, bytes)).collect();
+        pairs.sort_unstable();
+        pairs.serialize(serializer)
     }
-    
-    pub fn deserialize(data: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(bincode::deserialize(data)?)
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<Vec<u8>, u32>, D::Error> {
+        let pairs = Vec::<(u32, Vec<u8>)>::deserialize(deserializer)?;
+        Ok(pairs.into_iter().map(|(token, bytes)| (bytes, token)).collect())
+    }
+}
+
+mod sorted_tokens {
+    use std::collections::{BTreeMap, HashMap};
+    use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+    pub fn serialize<S: Serializer>(decode: &HashMap<u32, Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        decode.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<u32, Vec<u8>>, D::Error> {
+        HashMap::deserialize(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_allocated_in_order_until_the_range_is_used_up() {
+        let mut dict = Dictionary::new("global");
+        assert_eq!(dict.append_symbol(b"one".to_vec()), Some(256));
+        dict.insert_symbol(b"two".to_vec(), 300);
+        assert_eq!(dict.append_symbol(b"three".to_vec()), Some(301));
+        assert_eq!(dict.append_symbol(b"one".to_vec()), Some(256));
+
+        dict.insert_symbol(b"last".to_vec(), USER_TOKEN_BASE - 1);
+        assert_eq!(dict.next_token(), None);
+        assert_eq!(dict.append_symbol(b"four".to_vec()), None);
+        assert!(!dict.encode.contains_key(b"four".as_slice()));
+
+        let mut user = Dictionary::new_user("alice");
+        assert_eq!(user.append_symbol(b"one".to_vec()), Some(USER_TOKEN_BASE));
+        user.insert_symbol(b"last".to_vec(), u32::MAX - 1);
+        assert_eq!(user.append_symbol(b"two".to_vec()), None);
     }
 }
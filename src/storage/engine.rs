@@ -15,6 +15,30 @@ pub trait StorageEngine: Send + Sync {
         key: &str,
     ) -> anyhow::Result<Option<StoredObject>>;
 
+    /// The object's metadata without its blob
+    async fn get_metadata(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Option<ObjectMetadata>>;
+
+    /// Keys of the stored objects that start with `prefix`, in order
+    async fn list(
+        &self,
+        prefix: &str,
+    ) -> anyhow::Result<Vec<String>>;
+
+    /// Replaces an object's blob and metadata together, so a crash leaves
+    /// one version or the other. Fails without writing if the object is no
+    /// longer the version `current` describes; callers hold the object's
+    /// lock so it cannot change between the check and the swap.
+    async fn replace(
+        &self,
+        key: &str,
+        data: &[u8],
+        meta: &ObjectMetadata,
+        current: &ObjectMetadata,
+    ) -> anyhow::Result<()>;
+
     async fn delete(
         &self,
         key: &str,
@@ -1,13 +1,18 @@
 use tokio::fs;
 use std::path::PathBuf;
-use crate::storage::{StorageEngine, StoredObject, ObjectMetadata};
+use tracing::warn;
+use crate::storage::{StorageEngine, StoredObject, ObjectMetadata, swap};
 
 pub struct LocalStorage {
     root: PathBuf,
 }
 
 impl LocalStorage {
+    /// Opens the store, completing replacements a crash cut short
     pub fn new(root: PathBuf) -> Self {
+        if let Err(e) = swap::finish_interrupted_swaps(&root.join("files")) {
+            warn!("Cannot complete interrupted replacements in {:?}: {}", root, e);
+        }
         Self { root }
     }
 
@@ -55,6 +60,53 @@ impl StorageEngine for LocalStorage {
         }))
     }
 
+    async fn get_metadata(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Option<ObjectMetadata>> {
+        let meta_json = match fs::read(self.meta_path(key)).await {
+            Ok(m) => m,
+            Err(_) => return Ok(None),
+        };
+        Ok(Some(serde_json::from_slice(&meta_json)?))
+    }
+
+    async fn list(
+        &self,
+        prefix: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut entries = match fs::read_dir(self.root.join("files")).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(keys),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(key) = name.strip_suffix(".meta") {
+                if key.starts_with(prefix) {
+                    keys.push(key.to_string());
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn replace(
+        &self,
+        key: &str,
+        data: &[u8],
+        meta: &ObjectMetadata,
+        current: &ObjectMetadata,
+    ) -> anyhow::Result<()> {
+        let (data_path, meta_path) = (self.data_path(key), self.meta_path(key));
+        let (data, meta, current) = (data.to_vec(), meta.clone(), current.clone());
+        tokio::task::spawn_blocking(move || {
+            swap::replace_files(&data_path, &data, &meta_path, &meta, &current)
+        }).await?
+    }
+
     async fn delete(&self, key: &str) -> anyhow::Result<()> {
         let _ = fs::remove_file(self.data_path(key)).await;
         let _ = fs::remove_file(self.meta_path(key)).await;
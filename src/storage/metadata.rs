@@ -1,4 +1,6 @@
 use serde::{Serialize, Deserialize};
+use crate::engine::{config::CompressionLevel, entropy::EntropyCoderKind};
+use crate::engine::codec::SYMBOL_CODEC;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TokenKind {
@@ -23,6 +25,26 @@ pub struct TokenBreakdown {
     pub symbol_bytes: u64,
     pub literal_bytes: u64,
     pub literal_reason: String,
+    /// Bytes kept uncoded because coding would have grown them
+    #[serde(default)]
+    pub stored_bytes: u64,
+    #[serde(default)]
+    pub stored_reason: Option<String>,
+    /// Bytes coded as back-references to earlier content
+    #[serde(default)]
+    pub matched_bytes: u64,
+    /// Bytes in chunks that were already stored, by earlier uploads or
+    /// earlier in the same object
+    #[serde(default)]
+    pub deduplicated_bytes: u64,
+}
+
+/// One content-defined chunk of an object written by `CHUNKED_CODEC`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRef {
+    /// Hex SHA-256 of the chunk's original bytes, its key in the chunk store
+    pub hash: String,
+    pub size: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,12 +59,32 @@ pub struct ObjectMetadata {
     pub stored_at: u64,
     pub user_id: Option<String>,
     pub codec_version: u16,
+    /// Coder preferred for the blocks; objects from before it was
+    /// selectable all use Huffman
+    #[serde(default)]
+    pub entropy_coder: EntropyCoderKind,
+    /// Compression level the object was written at; objects from before
+    /// levels were selectable were all written at the default
+    #[serde(default)]
+    pub level: CompressionLevel,
+    /// Id of the `Codec` that wrote the object; decoding dispatches on it
+    #[serde(default = "default_codec")]
+    pub codec: String,
+    /// Chunks of an object written by `CHUNKED_CODEC`, in order; its own
+    /// blob is empty
+    #[serde(default)]
+    pub chunks: Vec<ChunkRef>,
     // Phase 2: symbolic indexing
     pub symbols: Vec<SymbolInfo>,
     pub explained_ratio: f64,
     pub token_breakdown: TokenBreakdown,
 }
 
+/// Objects from before codecs were recorded all went through the symbol pipeline
+fn default_codec() -> String {
+    SYMBOL_CODEC.to_string()
+}
+
 impl ObjectMetadata {
     pub fn new(
         key: String,
@@ -66,13 +108,21 @@ impl ObjectMetadata {
                 .unwrap()
                 .as_secs(),
             user_id,
-            codec_version: 1,
+            codec_version: crate::engine::container::CODEC_VERSION,
+            entropy_coder: EntropyCoderKind::Huffman,
+            level: CompressionLevel::Default,
+            codec: default_codec(),
+            chunks: Vec::new(),
             symbols: Vec::new(),
             explained_ratio: 0.0,
             token_breakdown: TokenBreakdown {
                 symbol_bytes: 0,
                 literal_bytes: 0,
                 literal_reason: "Below promotion threshold".to_string(),
+                stored_bytes: 0,
+                stored_reason: None,
+                matched_bytes: 0,
+                deduplicated_bytes: 0,
             },
         }
     }
@@ -1,6 +1,7 @@
 pub mod engine;
 pub mod object;
 pub mod dictionary;
+pub mod registry;
 pub mod metadata;
 pub mod local;
 pub mod s3;
@@ -9,6 +10,8 @@ pub mod persistent;
 pub mod versioned;
 pub mod explanation;
 pub mod layered;
+pub mod chunks;
+pub mod swap;
 
 pub use engine::*;
 pub use object::*;
@@ -0,0 +1,714 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use arc_swap::ArcSwap;
+use serde::Serialize;
+
+use crate::engine::{config::EngineConfig, decompressor::dictionary_id, error::DecompressError};
+use crate::storage::dictionary::Dictionary;
+use crate::utils::limits::USER_TOKEN_BASE;
+
+/// Generation files in a row that hold only appended symbols before one
+/// is written in full, which bounds the files read to load a generation
+const MAX_GENERATION_CHAIN: usize = 32;
+
+/// Every dictionary an object may name, keyed by dict id. The global
+/// dictionary keeps growing until it is frozen; each symbol set it passes
+/// through that content is encoded against is published as an immutable
+/// generation, so objects always decode with the exact dictionary they
+/// were written with. User dictionaries are versioned the same way.
+pub struct DictionaryRegistry {
+    data_dir: String,
+    global: DictionaryCell,
+    /// Current dictionary of each user seen since startup, layered on the
+    /// global dictionary when that is allowed
+    users: Mutex<HashMap<String, Arc<DictionaryCell>>>,
+    /// Every dictionary published or loaded since startup. Only the ones
+    /// still in use stay in memory; the rest are read back from disk.
+    generations: RwLock<HashMap<String, Generation>>,
+}
+
+struct Generation {
+    dict: Weak<Dictionary>,
+    /// Generation files behind this one's that hold only appended
+    /// symbols, 0 for a file holding every symbol
+    chain: usize,
+}
+
+/// The current generation of a dictionary. Readers load the snapshot
+/// without locking; writers take turns producing the next generation and
+/// swap it in, so encoders and decoders never wait on a change.
+struct DictionaryCell {
+    current: ArcSwap<Dictionary>,
+    writer: Mutex<()>,
+}
+
+impl DictionaryCell {
+    fn new(dict: Dictionary) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(dict),
+            writer: Mutex::new(()),
+        }
+    }
+
+    fn load(&self) -> Arc<Dictionary> {
+        self.current.load_full()
+    }
+}
+
+/// A persisted dictionary as listed by `DictionaryRegistry::list`
+#[derive(Debug, Clone, Serialize)]
+pub struct DictionaryEntry {
+    pub id: String,
+    pub frozen: bool,
+    /// Symbols held in the file; for a user dictionary, only the user's
+    /// own, and for a generation that extends another, only those it added
+    pub symbols: usize,
+    pub created_at: u64,
+    pub frozen_at: Option<u64>,
+    pub user: Option<String>,
+    pub base: Option<String>,
+}
+
+impl DictionaryRegistry {
+    pub fn new(data_dir: impl Into<String>, global: Dictionary) -> Self {
+        Self {
+            data_dir: data_dir.into(),
+            global: DictionaryCell::new(global),
+            users: Mutex::new(HashMap::new()),
+            generations: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Snapshot of the dictionary new uploads are compressed against
+    pub fn global(&self) -> Arc<Dictionary> {
+        self.global.load()
+    }
+
+    /// Applies `change` to a copy of the global dictionary and makes the
+    /// result, once published, the new global generation
+    pub fn update_global<R>(&self, change: impl FnOnce(&mut Dictionary) -> R) -> anyhow::Result<(Arc<Dictionary>, R)> {
+        self.update(&self.global, change)
+    }
+
+    /// Published snapshot of the dictionary an upload is compressed against
+    pub fn current(&self, user_id: Option<&str>, config: &EngineConfig) -> anyhow::Result<Arc<Dictionary>> {
+        let dict = self.upload_cell(user_id, config)?.load();
+        self.publish(&dict)?;
+        Ok(dict)
+    }
+
+    /// Lets `learn` add symbols from an upload to its dictionary, then
+    /// returns the published generation to encode against. Frozen
+    /// dictionaries are returned as they are, without calling `learn`.
+    pub fn learn<R: Default>(
+        &self,
+        user_id: Option<&str>,
+        config: &EngineConfig,
+        learn: impl FnOnce(&mut Dictionary) -> R,
+    ) -> anyhow::Result<(Arc<Dictionary>, R)> {
+        let cell = self.upload_cell(user_id, config)?;
+        let dict = cell.load();
+        if dict.frozen {
+            self.publish(&dict)?;
+            return Ok((dict, R::default()));
+        }
+        self.update(&cell, learn)
+    }
+
+    /// Dictionary an upload is compressed against: its user's own when
+    /// `config` allows one, otherwise the global dictionary. User symbols
+    /// extend the global dictionary only once it is frozen, since until
+    /// then uploads keep teaching the global one.
+    fn upload_cell(&self, user_id: Option<&str>, config: &EngineConfig) -> anyhow::Result<CellRef<'_>> {
+        let Some(user_id) = user_id.filter(|_| config.allow_user_dict) else {
+            return Ok(CellRef::Global(&self.global));
+        };
+        if !valid_user_id(user_id) {
+            return Err(anyhow::anyhow!("Invalid user id '{}'", user_id));
+        }
+
+        let global = self.global();
+        let base = if config.allow_global_dict {
+            if !global.frozen {
+                return Ok(CellRef::Global(&self.global));
+            }
+            Some(&global)
+        } else {
+            None
+        };
+        let base_id = base.map(|base| base.id.clone());
+
+        let mut users = self.users.lock().unwrap();
+        let layer = match users.get(user_id) {
+            Some(cell) => {
+                let dict = cell.load();
+                if dict.base == base_id {
+                    return Ok(CellRef::User(Arc::clone(cell)));
+                }
+                // The global dictionary changed underneath; keep the user's
+                // symbols, whose tokens cannot clash with the new base
+                dict.layer()
+            }
+            None => self.load_user(user_id)?.unwrap_or_else(|| Dictionary::new_user(user_id)),
+        };
+
+        let cell = Arc::new(DictionaryCell::new(Dictionary::layered(&layer, base.map(|base| &**base))));
+        users.insert(user_id.to_string(), Arc::clone(&cell));
+        Ok(CellRef::User(cell))
+    }
+
+    /// Copy-on-write change to `cell`: the next generation is built and
+    /// published aside and swapped in whole, with its tokenizer compiled
+    fn update<R>(&self, cell: &DictionaryCell, change: impl FnOnce(&mut Dictionary) -> R) -> anyhow::Result<(Arc<Dictionary>, R)> {
+        let _writer = cell.writer.lock().unwrap();
+        let parent = cell.load();
+        let mut dict = Dictionary::clone(&parent);
+        let result = change(&mut dict);
+        dict.matcher();
+
+        let dict = Arc::new(dict);
+        self.publish_generation(&dict, Some(&parent))?;
+        cell.current.store(Arc::clone(&dict));
+        Ok((dict, result))
+    }
+
+    /// Persists the current state of `dict` under its dict id unless that
+    /// generation is already known. Must succeed before anything encoded
+    /// against `dict` is stored. A new generation of a user dictionary also
+    /// becomes that user's saved dictionary.
+    pub fn publish(&self, dict: &Arc<Dictionary>) -> anyhow::Result<String> {
+        self.publish_generation(dict, None)
+    }
+
+    /// `publish` for a generation grown from `parent`, which is written as
+    /// just the symbols appended since when `parent` is on disk
+    fn publish_generation(&self, dict: &Arc<Dictionary>, parent: Option<&Dictionary>) -> anyhow::Result<String> {
+        let dict_id = dictionary_id(dict);
+        if self.generations.read().unwrap().contains_key(&dict_id) {
+            return Ok(dict_id);
+        }
+
+        // A file written before startup may end a chain of unknown length,
+        // so generations grown from it are written in full
+        let mut chain = MAX_GENERATION_CHAIN;
+        if dict.frozen {
+            let path = Dictionary::path(&self.data_dir, &dict_id);
+            if !Path::new(&path).exists() {
+                write_atomic(dict, &path)?;
+            }
+        } else {
+            // Only the user's own symbols are written; the base is frozen
+            // and kept on its own
+            let content = if dict.user.is_some() { dict.layer() } else { Dictionary::clone(dict) };
+            let path = Dictionary::generation_path(&self.data_dir, &dict_id);
+            if !Path::new(&path).exists() {
+                let (file, file_chain) = match parent.and_then(|parent| self.appended(&content, parent)) {
+                    Some((appended, parent_chain)) => (appended, parent_chain + 1),
+                    None => (content.clone(), 0),
+                };
+                write_atomic(&file, &path)?;
+                chain = file_chain;
+            }
+            if let Some(user_id) = &dict.user {
+                write_atomic(&content, &Dictionary::user_path(&self.data_dir, user_id))?;
+            }
+        }
+
+        self.remember(&dict_id, dict, chain);
+        Ok(dict_id)
+    }
+
+    /// The symbols `content` adds to the published generation `parent`,
+    /// with the length of the chain `parent` ends. `None` when `content`
+    /// is to be written in full: `parent` is unknown, ends a chain that is
+    /// long enough, or holds symbols `content` does not.
+    fn appended(&self, content: &Dictionary, parent: &Dictionary) -> Option<(Dictionary, usize)> {
+        if parent.frozen {
+            return None;
+        }
+        let parent_id = dictionary_id(parent);
+        let parent_chain = self.generations.read().unwrap().get(&parent_id)?.chain;
+        if parent_chain + 1 >= MAX_GENERATION_CHAIN {
+            return None;
+        }
+
+        let mut appended = Dictionary::new(content.id.clone());
+        appended.user = content.user.clone();
+        appended.base = content.base.clone();
+        appended.created_at = content.created_at;
+        appended.parent = Some(parent_id);
+        for (token, bytes) in parent.symbols() {
+            if content.user.is_some() && token < USER_TOKEN_BASE {
+                continue;
+            }
+            if content.decode.get(&token).map(Vec::as_slice) != Some(bytes) {
+                return None;
+            }
+        }
+        for (&token, bytes) in &content.decode {
+            if !parent.decode.contains_key(&token) {
+                appended.insert_symbol(bytes.clone(), token);
+            }
+        }
+        Some((appended, parent_chain))
+    }
+
+    fn remember(&self, dict_id: &str, dict: &Arc<Dictionary>, chain: usize) {
+        let mut generations = self.generations.write().unwrap();
+        let generation = generations.entry(dict_id.to_string()).or_insert(Generation { dict: Weak::new(), chain });
+        generation.dict = Arc::downgrade(dict);
+    }
+
+    /// Looks up a dictionary by id, loading frozen dictionaries and
+    /// generations from disk when they are not in use already.
+    pub fn get(&self, dict_id: &str) -> anyhow::Result<Option<Arc<Dictionary>>> {
+        if let Some(dict) = self.generations.read().unwrap().get(dict_id).and_then(|g| g.dict.upgrade()) {
+            return Ok(Some(dict));
+        }
+
+        let (mut dict, chain) = match Dictionary::load_frozen(&self.data_dir, dict_id)? {
+            Some(dict) => (dict, 0),
+            None => match self.read_generation(dict_id)? {
+                Some(loaded) => loaded,
+                None => return Ok(None),
+            },
+        };
+
+        if dict.user.is_some() {
+            let base = match &dict.base {
+                Some(base_id) => Some(self.get(base_id)?.ok_or_else(|| {
+                    anyhow::anyhow!("dictionary {} is layered on missing dictionary {}", dict_id, base_id)
+                })?),
+                None => None,
+            };
+            dict = Dictionary::layered(&dict, base.as_deref());
+        }
+
+        // A file under the wrong name would decode objects with the wrong symbols
+        if dictionary_id(&dict) != dict_id {
+            return Err(anyhow::anyhow!(
+                "dictionary file for {} holds {}", dict_id, dictionary_id(&dict)
+            ));
+        }
+
+        let dict = Arc::new(dict);
+        self.remember(dict_id, &dict, chain);
+        Ok(Some(dict))
+    }
+
+    /// Reads a generation file and the files of the generations it
+    /// extends, back to one written in full. Returns every symbol of the
+    /// generation (only the user's own for a user dictionary) and the
+    /// length of the chain.
+    fn read_generation(&self, dict_id: &str) -> anyhow::Result<Option<(Dictionary, usize)>> {
+        let path = Dictionary::generation_path(&self.data_dir, dict_id);
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+        let mut dict: Dictionary = serde_json::from_slice(&std::fs::read(&path)?)?;
+
+        let mut appended = Vec::new();
+        while let Some(parent_id) = dict.parent.take() {
+            if appended.len() >= MAX_GENERATION_CHAIN {
+                return Err(anyhow::anyhow!("generation {} extends too many others", dict_id));
+            }
+            let parent_path = Dictionary::generation_path(&self.data_dir, &parent_id);
+            let parent: Dictionary = serde_json::from_slice(&std::fs::read(&parent_path).map_err(|e| {
+                anyhow::anyhow!("generation {} extends missing generation {}: {}", dict_id, parent_id, e)
+            })?)?;
+            appended.push(std::mem::replace(&mut dict, parent));
+        }
+
+        let chain = appended.len();
+        while let Some(layer) = appended.pop() {
+            for (token, bytes) in layer.symbols() {
+                dict.insert_symbol(bytes.to_vec(), token);
+            }
+            dict.id = layer.id;
+            dict.user = layer.user;
+            dict.base = layer.base;
+            dict.created_at = layer.created_at;
+        }
+        Ok(Some((dict, chain)))
+    }
+
+    /// `get` for decoding: a dictionary that cannot be found or loaded
+    /// fails the object rather than the session
+    pub fn resolve(&self, dict_id: &str) -> Result<Arc<Dictionary>, DecompressError> {
+        match self.get(dict_id) {
+            Ok(Some(dict)) => Ok(dict),
+            Ok(None) => Err(DecompressError::DictionaryMismatch {
+                expected: dict_id.to_string(),
+                found: "none".to_string(),
+            }),
+            Err(e) => Err(DecompressError::Io(std::io::Error::other(e))),
+        }
+    }
+
+    /// The frozen global dictionary recorded as active, which new uploads
+    /// are compressed against. A data directory from before the record
+    /// existed holds only the dictionary the server froze, which is
+    /// adopted; with several frozen dictionaries and no record, which one
+    /// is meant is left to `dict activate`.
+    pub fn load_active(data_dir: &str) -> anyhow::Result<Option<Dictionary>> {
+        let active_path = Dictionary::active_path(data_dir);
+        if Path::new(&active_path).exists() {
+            let dict_id = std::fs::read_to_string(&active_path)?.trim().to_string();
+            let dict = Dictionary::load_frozen(data_dir, &dict_id)?
+                .ok_or_else(|| anyhow::anyhow!("active dictionary {} not found", dict_id))?;
+            return Ok(Some(dict));
+        }
+
+        if !Path::new(data_dir).exists() {
+            return Ok(None);
+        }
+        let mut frozen = Vec::new();
+        for entry in std::fs::read_dir(data_dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(id) = name.strip_prefix("dictionary_").and_then(|n| n.strip_suffix(".json")) {
+                if let Some(dict) = Dictionary::load_frozen(data_dir, id)? {
+                    if dict.frozen && dict.user.is_none() {
+                        frozen.push(dict);
+                    }
+                }
+            }
+        }
+        match frozen.len() {
+            0 => Ok(None),
+            1 => {
+                let dict = frozen.remove(0);
+                write_active(data_dir, &dictionary_id(&dict))?;
+                Ok(Some(dict))
+            }
+            n => Err(anyhow::anyhow!(
+                "{} frozen dictionaries and none recorded as active; choose one with `dict activate`", n
+            )),
+        }
+    }
+
+    /// Records a frozen global dictionary as the one the server starts with
+    pub fn activate(&self, dict_id: &str) -> anyhow::Result<()> {
+        let dict = self.get(dict_id)?.ok_or_else(|| anyhow::anyhow!("Dictionary {} not found", dict_id))?;
+        if !dict.frozen || dict.user.is_some() {
+            return Err(anyhow::anyhow!("{} is not a frozen global dictionary", dict_id));
+        }
+        write_active(&self.data_dir, dict_id)
+    }
+
+    /// Every frozen dictionary and generation on disk, frozen ones first
+    pub fn list(&self) -> anyhow::Result<Vec<DictionaryEntry>> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&self.data_dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(id) = name.strip_prefix("dictionary_").and_then(|n| n.strip_suffix(".json")) {
+                entries.push(self.entry(id, &Dictionary::path(&self.data_dir, id))?);
+            }
+        }
+
+        let generations_dir = Path::new(&self.data_dir).join("dictionaries");
+        if generations_dir.exists() {
+            for entry in std::fs::read_dir(&generations_dir)? {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                if let Some(id) = name.strip_suffix(".json") {
+                    entries.push(self.entry(id, &Dictionary::generation_path(&self.data_dir, id))?);
+                }
+            }
+        }
+
+        entries.sort_by(|a, b| b.frozen.cmp(&a.frozen).then(a.created_at.cmp(&b.created_at)).then(a.id.cmp(&b.id)));
+        Ok(entries)
+    }
+
+    /// `get` that also accepts an unambiguous prefix of a listed id
+    pub fn find(&self, id_or_prefix: &str) -> anyhow::Result<Arc<Dictionary>> {
+        if let Some(dict) = self.get(id_or_prefix)? {
+            return Ok(dict);
+        }
+        let matching: Vec<String> = self.list()?
+            .into_iter()
+            .map(|entry| entry.id)
+            .filter(|id| id.starts_with(id_or_prefix))
+            .collect();
+        match matching.as_slice() {
+            [id] => self.get(id)?.ok_or_else(|| anyhow::anyhow!("Dictionary {} not found", id)),
+            [] => Err(anyhow::anyhow!("Dictionary {} not found", id_or_prefix)),
+            _ => Err(anyhow::anyhow!("'{}' matches {} dictionaries", id_or_prefix, matching.len())),
+        }
+    }
+
+    fn entry(&self, dict_id: &str, path: &str) -> anyhow::Result<DictionaryEntry> {
+        let dict: Dictionary = serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        Ok(DictionaryEntry {
+            id: dict_id.to_string(),
+            frozen: dict.frozen,
+            symbols: dict.decode.len(),
+            created_at: dict.created_at,
+            frozen_at: dict.frozen_at,
+            user: dict.user,
+            base: dict.base,
+        })
+    }
+
+    /// The saved symbols of a user dictionary, without its base
+    fn load_user(&self, user_id: &str) -> anyhow::Result<Option<Dictionary>> {
+        let path = Dictionary::user_path(&self.data_dir, user_id);
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&std::fs::read(&path)?)?))
+    }
+}
+
+/// The global cell or a user's, which may be replaced while in use
+enum CellRef<'a> {
+    Global(&'a DictionaryCell),
+    User(Arc<DictionaryCell>),
+}
+
+impl std::ops::Deref for CellRef<'_> {
+    type Target = DictionaryCell;
+
+    fn deref(&self) -> &DictionaryCell {
+        match self {
+            CellRef::Global(cell) => cell,
+            CellRef::User(cell) => cell,
+        }
+    }
+}
+
+/// User ids become file names, so only a conservative set is accepted
+fn valid_user_id(user_id: &str) -> bool {
+    !user_id.is_empty()
+        && user_id.len() <= 128
+        && !user_id.starts_with('.')
+        && user_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn write_active(data_dir: &str, dict_id: &str) -> anyhow::Result<()> {
+    let path = Dictionary::active_path(data_dir);
+    let tmp_path = format!("{}.tmp", path);
+    std::fs::write(&tmp_path, dict_id)?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Write then rename, so a reader never sees a partial dictionary
+fn write_atomic(dict: &Dictionary, path: &str) -> anyhow::Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp_path = format!("{}.tmp", path);
+    dict.save(&tmp_path)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("symvea-registry-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn frozen(data_dir: &str, symbol: &[u8]) -> String {
+        let mut dict = Dictionary::new("global");
+        dict.insert_symbol(symbol.to_vec(), 256);
+        let dict_id = dict.freeze();
+        dict.save(&Dictionary::path(data_dir, &dict_id)).unwrap();
+        dict_id
+    }
+
+    fn grow(registry: &DictionaryRegistry, symbol: &str) -> Arc<Dictionary> {
+        registry.update_global(|dict| {
+            dict.append_symbol(symbol.as_bytes().to_vec());
+        }).unwrap().0
+    }
+
+    fn generation_file(dir: &str, dict: &Dictionary) -> Dictionary {
+        let path = Dictionary::generation_path(dir, &dictionary_id(dict));
+        serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn generations_persist_appended_symbols() {
+        let dir = data_dir("appended");
+        let registry = DictionaryRegistry::new(dir.clone(), Dictionary::new("global"));
+        let first = grow(&registry, "first symbol");
+        let second = grow(&registry, "second symbol");
+
+        assert_eq!(generation_file(&dir, &first).parent, None);
+        let file = generation_file(&dir, &second);
+        assert_eq!(file.parent, Some(dictionary_id(&first)));
+        assert_eq!(file.decode.len(), 1);
+
+        // A restarted server reads the chain back
+        let reloaded = DictionaryRegistry::new(dir.clone(), Dictionary::new("global"));
+        let loaded = reloaded.get(&dictionary_id(&second)).unwrap().unwrap();
+        assert_eq!(loaded.symbols(), second.symbols());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn generation_chains_are_bounded() {
+        let dir = data_dir("chain");
+        let registry = DictionaryRegistry::new(dir.clone(), Dictionary::new("global"));
+        let generations: Vec<_> = (0..2 * MAX_GENERATION_CHAIN)
+            .map(|i| grow(&registry, &format!("symbol number {}", i)))
+            .collect();
+
+        let full: Vec<usize> = generations.iter().enumerate()
+            .filter(|(_, dict)| generation_file(&dir, dict).parent.is_none())
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(full, [0, MAX_GENERATION_CHAIN]);
+
+        let reloaded = DictionaryRegistry::new(dir.clone(), Dictionary::new("global"));
+        for dict in [&generations[MAX_GENERATION_CHAIN - 1], generations.last().unwrap()] {
+            let loaded = reloaded.get(&dictionary_id(dict)).unwrap().unwrap();
+            assert_eq!(loaded.symbols(), dict.symbols());
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn unused_generations_leave_memory() {
+        let dir = data_dir("evict");
+        let registry = DictionaryRegistry::new(dir.clone(), Dictionary::new("global"));
+        let old_id = dictionary_id(&grow(&registry, "old symbol"));
+        grow(&registry, "new symbol");
+        let in_memory = |id: &str| registry.generations.read().unwrap()[id].dict.strong_count() > 0;
+        assert!(!in_memory(&old_id));
+
+        let old = registry.get(&old_id).unwrap().unwrap();
+        assert_eq!(dictionary_id(&old), old_id);
+        assert!(in_memory(&old_id));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn user_generations_reload_on_their_base() {
+        let dir = data_dir("user");
+        let mut global = Dictionary::new("global");
+        global.insert_symbol(b"global symbol".to_vec(), 256);
+        let base_id = global.freeze();
+        global.save(&Dictionary::path(&dir, &base_id)).unwrap();
+        let registry = DictionaryRegistry::new(dir.clone(), global);
+        let config = EngineConfig { allow_user_dict: true, allow_global_dict: true, ..EngineConfig::default() };
+
+        let learn = |symbol: &str| registry.learn(Some("alice"), &config, |dict| {
+            dict.append_symbol(symbol.as_bytes().to_vec());
+        }).unwrap().0;
+        let first = learn("user symbol one");
+        let second = learn("user symbol two");
+        let file = generation_file(&dir, &second);
+        assert_eq!(file.parent, Some(dictionary_id(&first)));
+        assert_eq!(file.base, Some(base_id.clone()));
+        assert_eq!(file.decode.len(), 1);
+
+        let reloaded = DictionaryRegistry::new(dir.clone(), Dictionary::new("global"));
+        let loaded = reloaded.get(&dictionary_id(&second)).unwrap().unwrap();
+        assert_eq!(loaded.symbols(), second.symbols());
+        assert_eq!(loaded.encode[b"global symbol".as_slice()], 256);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn snapshots_do_not_see_later_changes() {
+        let dir = data_dir("snapshot");
+        let registry = DictionaryRegistry::new(dir.clone(), Dictionary::new("global"));
+        let before = registry.global();
+        let after = grow(&registry, "later symbol");
+
+        assert!(before.decode.is_empty());
+        assert_eq!(after.decode.len(), 1);
+        assert!(Arc::ptr_eq(&registry.global(), &after));
+        // The snapshot an object was encoded against stays resolvable
+        assert!(Arc::ptr_eq(&registry.get(&dictionary_id(&after)).unwrap().unwrap(), &after));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn concurrent_writers_all_land() {
+        let dir = data_dir("concurrent");
+        let registry = DictionaryRegistry::new(dir.clone(), Dictionary::new("global"));
+        std::thread::scope(|scope| {
+            for writer in 0..4 {
+                let registry = &registry;
+                scope.spawn(move || {
+                    for i in 0..10 {
+                        grow(registry, &format!("writer {} symbol {}", writer, i));
+                    }
+                });
+            }
+            scope.spawn(|| {
+                // Readers never see the dictionary shrink or a half-made change
+                let mut seen = 0;
+                for _ in 0..50 {
+                    let dict = registry.global();
+                    assert!(dict.decode.len() >= seen);
+                    assert_eq!(dict.encode.len(), dict.decode.len());
+                    seen = dict.decode.len();
+                }
+            });
+        });
+
+        let global = registry.global();
+        assert_eq!(global.decode.len(), 40);
+        // Tokens are handed out once each
+        let tokens: std::collections::HashSet<u32> = global.decode.keys().copied().collect();
+        assert_eq!(tokens, (256..296).collect());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn active_dictionary_is_recorded() {
+        let dir = data_dir("active");
+        assert!(DictionaryRegistry::load_active(&dir).unwrap().is_none());
+
+        let first = frozen(&dir, b"first symbol");
+        let registry = DictionaryRegistry::new(dir.clone(), Dictionary::new("global"));
+        registry.activate(&first).unwrap();
+        // A pruned or trained dictionary written alongside is not picked up
+        let second = frozen(&dir, b"second symbol");
+        let active = DictionaryRegistry::load_active(&dir).unwrap().unwrap();
+        assert_eq!(dictionary_id(&active), first);
+
+        registry.activate(&second).unwrap();
+        let active = DictionaryRegistry::load_active(&dir).unwrap().unwrap();
+        assert_eq!(dictionary_id(&active), second);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn lone_frozen_dictionary_is_adopted() {
+        let dir = data_dir("adopt");
+        let dict_id = frozen(&dir, b"only symbol");
+        let active = DictionaryRegistry::load_active(&dir).unwrap().unwrap();
+        assert_eq!(dictionary_id(&active), dict_id);
+        assert_eq!(std::fs::read_to_string(Dictionary::active_path(&dir)).unwrap(), dict_id);
+
+        // Without a record, several frozen dictionaries are ambiguous
+        std::fs::remove_file(Dictionary::active_path(&dir)).unwrap();
+        frozen(&dir, b"another symbol");
+        assert!(DictionaryRegistry::load_active(&dir).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn only_frozen_global_dictionaries_are_activated() {
+        let dir = data_dir("reject");
+        let registry = DictionaryRegistry::new(dir.clone(), Dictionary::new("global"));
+        let (mutable, ()) = registry.update_global(|dict| {
+            dict.insert_symbol(b"mutable symbol".to_vec(), 256);
+        }).unwrap();
+        assert!(registry.activate(&dictionary_id(&mutable)).is_err());
+        assert!(registry.activate("missing").is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
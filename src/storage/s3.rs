@@ -22,6 +22,30 @@ impl StorageEngine for S3Storage {
         unimplemented!("S3 backend not yet implemented");
     }
 
+    async fn get_metadata(
+        &self,
+        _key: &str,
+    ) -> anyhow::Result<Option<ObjectMetadata>> {
+        unimplemented!("S3 backend not yet implemented");
+    }
+
+    async fn list(
+        &self,
+        _prefix: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        unimplemented!("S3 backend not yet implemented");
+    }
+
+    async fn replace(
+        &self,
+        _key: &str,
+        _data: &[u8],
+        _meta: &ObjectMetadata,
+        _current: &ObjectMetadata,
+    ) -> anyhow::Result<()> {
+        unimplemented!("S3 backend not yet implemented");
+    }
+
     async fn delete(
         &self,
         _key: &str,
@@ -0,0 +1,146 @@
+//! Replacing a blob and its metadata file together. Both replacements are
+//! fully written before either is renamed into place, and the metadata is
+//! renamed last, so a leftover metadata swap file means the swap was
+//! committed and only has to be rolled forward.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::storage::metadata::ObjectMetadata;
+
+/// Suffix of a replacement file written next to the one it replaces
+const SWAP_SUFFIX: &str = ".swap";
+
+/// Swaps in `blob` and `meta` unless the metadata on disk no longer
+/// describes `current`. Callers hold whatever lock writers of the object
+/// take, so the version checked is the one replaced.
+pub fn replace_files(
+    data_path: &Path,
+    blob: &[u8],
+    meta_path: &Path,
+    meta: &ObjectMetadata,
+    current: &ObjectMetadata,
+) -> anyhow::Result<()> {
+    let on_disk: ObjectMetadata = serde_json::from_slice(&fs::read(meta_path)?)?;
+    if on_disk.object_hash != current.object_hash || on_disk.stored_at != current.stored_at {
+        return Err(anyhow::anyhow!("object changed while it was being replaced"));
+    }
+
+    let data_swap = swap_path(data_path);
+    let meta_swap = swap_path(meta_path);
+    write_synced(&data_swap, blob)?;
+    write_synced(&meta_swap, &serde_json::to_vec(meta)?)?;
+    fs::rename(&data_swap, data_path)?;
+    fs::rename(&meta_swap, meta_path)?;
+    Ok(())
+}
+
+/// Completes swaps in `dir` cut short by a crash, and drops blob
+/// replacements whose metadata never made it to disk
+pub fn finish_interrupted_swaps(dir: &Path) -> anyhow::Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    let mut pending = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(SWAP_SUFFIX) {
+            pending.push(path);
+        }
+    }
+
+    for swap in &pending {
+        let target = swapped_path(swap);
+        if target.to_string_lossy().ends_with(".meta") {
+            continue;
+        }
+        let meta_swap = swap_path(&PathBuf::from(format!("{}.meta", target.to_string_lossy())));
+        if meta_swap.exists() {
+            info!("Completing interrupted replacement of {:?}", target);
+            fs::rename(swap, &target)?;
+        } else {
+            fs::remove_file(swap)?;
+        }
+    }
+
+    for swap in &pending {
+        let target = swapped_path(swap);
+        if target.to_string_lossy().ends_with(".meta") {
+            fs::rename(swap, target)?;
+        }
+    }
+    Ok(())
+}
+
+fn swap_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}{}", path.to_string_lossy(), SWAP_SUFFIX))
+}
+
+/// The file a swap file replaces
+fn swapped_path(swap: &Path) -> PathBuf {
+    let name = swap.to_string_lossy();
+    PathBuf::from(&name[..name.len() - SWAP_SUFFIX.len()])
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("symvea-swap-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn meta(stored_at: u64) -> ObjectMetadata {
+        let mut meta = ObjectMetadata::new("obj".to_string(), [1; 32], [2; 32], "dict".to_string(), 3, 3, None);
+        meta.stored_at = stored_at;
+        meta
+    }
+
+    #[test]
+    fn replaces_only_the_version_read() {
+        let dir = dir("replace");
+        let (data_path, meta_path) = (dir.join("obj"), dir.join("obj.meta"));
+        fs::write(&data_path, b"old").unwrap();
+        fs::write(&meta_path, serde_json::to_vec(&meta(1)).unwrap()).unwrap();
+
+        assert!(replace_files(&data_path, b"new", &meta_path, &meta(3), &meta(2)).is_err());
+        assert_eq!(fs::read(&data_path).unwrap(), b"old");
+
+        replace_files(&data_path, b"new", &meta_path, &meta(3), &meta(1)).unwrap();
+        assert_eq!(fs::read(&data_path).unwrap(), b"new");
+        let on_disk: ObjectMetadata = serde_json::from_slice(&fs::read(&meta_path).unwrap()).unwrap();
+        assert_eq!(on_disk.stored_at, 3);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn interrupted_swaps_roll_forward_once_metadata_is_written() {
+        let dir = dir("interrupted");
+        for name in ["committed", "abandoned"] {
+            fs::write(dir.join(name), b"old").unwrap();
+            fs::write(dir.join(format!("{}.meta", name)), b"old meta").unwrap();
+            fs::write(swap_path(&dir.join(name)), b"new").unwrap();
+        }
+        fs::write(swap_path(&dir.join("committed.meta")), b"new meta").unwrap();
+
+        finish_interrupted_swaps(&dir).unwrap();
+        assert_eq!(fs::read(dir.join("committed")).unwrap(), b"new");
+        assert_eq!(fs::read(dir.join("committed.meta")).unwrap(), b"new meta");
+        assert_eq!(fs::read(dir.join("abandoned")).unwrap(), b"old");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 4);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
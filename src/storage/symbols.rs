@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::sync::Mutex;
 use serde::{Serialize, Deserialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +24,19 @@ pub struct SymbolUsage {
 
 pub struct SymbolStore {
     data_dir: String,
+    // Symbol and usage files are read, changed and written back; chunks
+    // of one upload are coded in parallel and may share symbols
+    update_lock: Mutex<()>,
+    // Usage held back by a `deferred` store
+    deferred_usage: Option<Mutex<Vec<DeferredUsage>>>,
+}
+
+/// One `add_usage` call a deferred store held back
+struct DeferredUsage {
+    symbol_hash: String,
+    object_key: String,
+    symbol_bytes: u64,
+    occurrence_count: u64,
 }
 
 impl SymbolStore {
@@ -34,7 +48,7 @@ impl SymbolStore {
         fs::create_dir_all(&symbols_dir).ok();
         fs::create_dir_all(&usage_dir).ok();
         
-        let store = Self { data_dir };
+        let store = Self { data_dir, update_lock: Mutex::new(()), deferred_usage: None };
         
         // Phase 3: Verify all symbols on startup
         if let Err(e) = store.verify_all_symbols() {
@@ -46,6 +60,7 @@ impl SymbolStore {
     
     pub fn store_symbol(&self, hash: &str, bytes: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
         let symbol_path = format!("{}/symbols/{}", self.data_dir, hash);
+        let _guard = self.update_lock.lock().unwrap();
         
         if !Path::new(&symbol_path).exists() {
             let content_hash = crate::engine::hash::sha256(bytes);
@@ -79,8 +94,39 @@ impl SymbolStore {
         Ok(bincode::deserialize(&data)?)
     }
     
+    /// A store over the same symbols that holds usage counts back, for an
+    /// encode whose blob may never be written. `apply_usage` records them.
+    pub fn deferred(&self) -> SymbolStore {
+        Self {
+            data_dir: self.data_dir.clone(),
+            update_lock: Mutex::new(()),
+            deferred_usage: Some(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Records the usage `deferred` held back
+    pub fn apply_usage(&self, deferred: SymbolStore) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(usage) = deferred.deferred_usage else {
+            return Ok(());
+        };
+        for usage in usage.into_inner().unwrap() {
+            self.add_usage(&usage.symbol_hash, &usage.object_key, usage.symbol_bytes, usage.occurrence_count)?;
+        }
+        Ok(())
+    }
+
     pub fn add_usage(&self, symbol_hash: &str, object_key: &str, symbol_bytes: u64, occurrence_count: u64) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(deferred) = &self.deferred_usage {
+            deferred.lock().unwrap().push(DeferredUsage {
+                symbol_hash: symbol_hash.to_string(),
+                object_key: object_key.to_string(),
+                symbol_bytes,
+                occurrence_count,
+            });
+            return Ok(());
+        }
         let usage_path = format!("{}/symbol_usage/{}", self.data_dir, symbol_hash);
+        let _guard = self.update_lock.lock().unwrap();
         
         let mut usage = if Path::new(&usage_path).exists() {
             let data = fs::read(&usage_path)?;
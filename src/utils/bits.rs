@@ -0,0 +1,143 @@
+/// MSB-first bit packer backed by a 64-bit accumulator
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    pub fn with_capacity(bytes: usize) -> Self {
+        Self {
+            out: Vec::with_capacity(bytes),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Append the low `len` bits of `code`, most significant first (`len <= 32`)
+    #[inline]
+    pub fn write(&mut self, code: u32, len: u8) {
+        let len = len as u32;
+        self.acc |= (code as u64) << (64 - self.bits - len);
+        self.bits += len;
+        while self.bits >= 8 {
+            self.out.push((self.acc >> 56) as u8);
+            self.acc <<= 8;
+            self.bits -= 8;
+        }
+    }
+
+    /// Flush the partial last byte; returns the bytes and how many bits of
+    /// the last byte are used (0 when it is full)
+    pub fn finish(mut self) -> (Vec<u8>, u8) {
+        let tail = self.bits as u8;
+        if self.bits > 0 {
+            self.out.push((self.acc >> 56) as u8);
+        }
+        (self.out, tail)
+    }
+}
+
+/// MSB-first bit reader that reads zeros past the end of its input
+#[derive(Debug)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    next_byte: usize,
+    acc: u64,
+    bits: u32,
+    consumed: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            next_byte: 0,
+            acc: 0,
+            bits: 0,
+            consumed: 0,
+        }
+    }
+
+    #[inline]
+    fn refill(&mut self) {
+        while self.bits <= 56 {
+            let byte = self.data.get(self.next_byte).copied().unwrap_or(0);
+            self.acc |= (byte as u64) << (56 - self.bits);
+            self.next_byte += 1;
+            self.bits += 8;
+        }
+    }
+
+    /// Next `n` bits without consuming them (`n <= 32`)
+    #[inline]
+    pub fn peek(&mut self, n: u8) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        self.refill();
+        (self.acc >> (64 - n as u32)) as u32
+    }
+
+    #[inline]
+    pub fn consume(&mut self, n: u8) {
+        self.acc <<= n as u32;
+        self.bits -= n as u32;
+        self.consumed += n as usize;
+    }
+
+    /// Total bits consumed so far
+    pub fn position(&self) -> usize {
+        self.consumed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips_mixed_widths() {
+        let codes: Vec<(u32, u8)> = (0..500u32)
+            .map(|i| {
+                let len = (i % 32 + 1) as u8;
+                (i.wrapping_mul(0x9E37_79B9) & (u32::MAX >> (32 - len)), len)
+            })
+            .collect();
+
+        let mut writer = BitWriter::default();
+        for &(code, len) in &codes {
+            writer.write(code, len);
+        }
+        let total_bits: usize = codes.iter().map(|&(_, len)| len as usize).sum();
+        let (bytes, tail) = writer.finish();
+        assert_eq!(bytes.len(), total_bits.div_ceil(8));
+        assert_eq!(tail as usize, total_bits % 8);
+
+        let mut reader = BitReader::new(&bytes);
+        for &(code, len) in &codes {
+            assert_eq!(reader.peek(len), code);
+            reader.consume(len);
+        }
+        assert_eq!(reader.position(), total_bits);
+    }
+
+    #[test]
+    fn packs_most_significant_bit_first() {
+        let mut writer = BitWriter::default();
+        writer.write(0b1, 1);
+        writer.write(0b011, 3);
+        writer.write(0b11111, 5);
+        assert_eq!(writer.finish(), (vec![0b1011_1111, 0b1000_0000], 1));
+    }
+
+    #[test]
+    fn reads_zeros_past_the_end() {
+        let mut reader = BitReader::new(&[0xFF]);
+        assert_eq!(reader.peek(12), 0xFF0);
+        reader.consume(8);
+        assert_eq!(reader.peek(32), 0);
+        assert_eq!(reader.peek(0), 0);
+    }
+}
@@ -1,6 +1,10 @@
 /// Maximum dictionary entries per scope
 pub const MAX_DICT_ENTRIES: usize = 10_000_000;
 
+/// First token reserved for user dictionaries. Global symbols are numbered
+/// below it, so a user layer never collides with the dictionary under it.
+pub const USER_TOKEN_BASE: u32 = 1 << 24;
+
 /// Maximum symbol length
 pub const MAX_SYMBOL_SIZE: usize = 256;
 
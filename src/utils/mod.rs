@@ -2,4 +2,6 @@ pub mod crc;
 pub mod io;
 pub mod buffer;
 pub mod varint;
+pub mod bits;
 pub mod limits;
+pub mod parallel;
@@ -0,0 +1,39 @@
+use rayon::prelude::*;
+
+/// Threads worth running CPU-bound work on
+pub fn workers() -> usize {
+    rayon::current_num_threads()
+}
+
+/// Applies `f` to every item on the shared worker pool, returning results
+/// in item order. Concurrent callers queue on the same threads instead of
+/// each starting their own. A single item runs on the calling thread.
+pub fn parallel_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync + Send) -> Vec<R> {
+    if items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    items.par_iter().map(f).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_keep_item_order() {
+        let items: Vec<u64> = (0..1000).collect();
+        assert_eq!(parallel_map(&items, |&i| i * i), items.iter().map(|&i| i * i).collect::<Vec<_>>());
+        assert_eq!(parallel_map(&[7], |&i| i + 1), [8]);
+        assert!(parallel_map(&[] as &[u64], |&i| i).is_empty());
+    }
+
+    #[test]
+    fn nested_calls_share_the_pool() {
+        let outer: Vec<usize> = (0..8).collect();
+        let sums = parallel_map(&outer, |&n| {
+            let inner: Vec<usize> = (0..100).map(|i| i * n).collect();
+            parallel_map(&inner, |&i| i).into_iter().sum::<usize>()
+        });
+        assert_eq!(sums, outer.iter().map(|&n| 4950 * n).collect::<Vec<_>>());
+    }
+}